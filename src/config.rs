@@ -4,7 +4,7 @@ use std::{
     time::Duration,
 };
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use sha2::{Digest, Sha224};
 
 use crate::{types::TrojanError, utils::resolve};
@@ -59,6 +59,41 @@ pub struct Opts {
     pub tcp_idle_duration: Duration,
 }
 
+/// What to do with a route event when the route thread falls behind
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoutePolicy {
+    /// Wait briefly for the route thread, stalling DNS answers
+    Block,
+    /// Keep the new event, dropping the oldest one not handed to the route thread
+    DropOldest,
+    /// Drop the new event
+    DropNewest,
+}
+
+/// How to handle a message carrying more than one query
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MultiQueryMode {
+    /// Drop the message
+    Reject,
+    /// Forward the whole message to the upstream chosen by the first query, not cached
+    First,
+    /// Resolve each query on its own and merge the answers into one response
+    Split,
+}
+
+/// Stage of the resolution pipeline, the first stage listing a name decides how it's resolved
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResolutionStage {
+    /// Answer sinkholed domains with the block page address
+    Sinkhole,
+    /// Answer local names with their address
+    Hosts,
+    /// Resolve the force direct list through poisoned DNS
+    ForceDirect,
+    /// Resolve blocked domains through trusted DNS
+    Block,
+}
+
 #[derive(Parser)]
 pub enum Mode {
     #[clap(version, name = "proxy", about = "run in proxy mode")]
@@ -68,7 +103,7 @@ pub enum Mode {
     #[clap(version, name = "wintun", about = "run in windows tun mode")]
     Wintun(WintunArgs),
     #[clap(version, name = "dns", about = "run in dns mode")]
-    Dns(DnsArgs),
+    Dns(Box<DnsArgs>),
 }
 
 #[derive(Parser, Debug)]
@@ -136,6 +171,16 @@ pub struct WintunArgs {
     /// DNS server address used for query trojan server ip
     #[clap(long)]
     pub dns_server_addr: Option<String>,
+
+    /// File the DNS server dumps its fake addresses to with --tunnel-by-domain, connections to
+    /// them connect by hostname
+    #[clap(long)]
+    pub fake_ip_file: Option<String>,
+
+    /// Range the DNS server hands fake addresses out from, connections to other addresses
+    /// skip the fake ip file
+    #[clap(long, default_value = "198.18.0.0/15")]
+    pub fake_ip_range: String,
 }
 
 #[derive(Parser)]
@@ -153,28 +198,66 @@ pub struct ProxyArgs {
     pub pool_size: usize,
 }
 
-#[derive(Parser)]
+#[derive(Parser, Clone)]
 pub struct DnsArgs {
     /// Tunnel name used for transparent proxy
     #[clap(short = 'n', long)]
     pub tun_name: String,
 
-    /// Domain list which should be resolved through safe DNS
+    /// Domain list file or directory of list files which should be resolved through safe DNS,
+    /// can be repeated, empty for no list file. Gzipped lists are decompressed on load.
+    /// Promoted domains are saved to the first file, unless it's gzipped
     #[clap(long, default_value = "ipset/domain.txt")]
-    pub blocked_domain_list: String,
+    pub blocked_domain_list: Vec<String>,
+
+    /// Max domains loaded from blocked domain lists, loading a larger list fails
+    #[clap(long, default_value = "1000000")]
+    pub blocked_domain_max: usize,
+
+    /// Domain resolved through safe DNS along with the blocked domain list, can be repeated
+    #[clap(long)]
+    pub blocked_domains_inline: Vec<String>,
 
-    /// Listen address for DNS server, like 127.0.0.1:53
+    /// Domain resolved through safe DNS during a local time window only, format like
+    /// work.example.com=09:00-18:00, windows may wrap midnight, can be repeated
+    #[clap(long)]
+    pub blocked_domain_schedule: Vec<String>,
+
+    /// Listen addresses for DNS server, like 127.0.0.1:53, the first one is set as name server
     #[clap(long, default_value = "127.0.0.1:53")]
-    pub dns_listen_address: String,
+    pub dns_listen_address: Vec<String>,
+
+    /// Named pipe like \\.\pipe\trojan-dns or unix socket path local clients can query through
+    #[clap(long)]
+    pub dns_ipc_path: Option<String>,
 
-    /// Trusted DNS server
+    /// Trusted DNS server, an address or a URL like tcp://8.8.8.8, tls://8.8.8.8#dns.google or
+    /// https://8.8.8.8/dns-query#dns.google, the name after # is verified against certificates
     #[clap(long, default_value = "8.8.8.8")]
     pub trusted_dns: String,
 
-    /// Poisoned DNS server
+    /// Poisoned DNS server, an address or a URL like the trusted DNS server
     #[clap(long, default_value = "114.114.114.114")]
     pub poisoned_dns: String,
 
+    /// Resolver like dnsmasq every query is passed through to unchanged, answers are not
+    /// cached and only looked into for routing blocked domains, overriding both upstreams
+    #[clap(long)]
+    pub downstream_dns: Option<String>,
+
+    /// Refuse to start if trusted and poisoned DNS are the same server
+    #[clap(long)]
+    pub strict_upstreams: bool,
+
+    /// Upstream for a query type in format like TXT=9.9.9.9, taking precedence over block lists,
+    /// the upstream is an address or a URL like the trusted DNS server, can be repeated
+    #[clap(long)]
+    pub query_type_upstream: Vec<String>,
+
+    /// Source ports each upstream rotates queries through with random ids, 1 for a single port
+    #[clap(long, default_value = "1")]
+    pub upstream_ports: usize,
+
     /// DNS cache timeout
     #[clap(long, default_value = "600")]
     pub dns_cache_time: u64,
@@ -182,6 +265,227 @@ pub struct DnsArgs {
     /// Flag for adding route table for resolved IPs
     #[clap(long)]
     pub add_route: bool,
+
+    /// Add routes for trusted answers, off when the TUN packet path proxies blocked domains
+    /// by itself, poisoned answers are never routed
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub route_trusted_answers: bool,
+
+    /// Answer blocked domains with fake addresses instead of resolving them, so the tunnel
+    /// connects by hostname and the trojan server resolves it
+    #[clap(long)]
+    pub tunnel_by_domain: bool,
+
+    /// Range fake addresses are handed out from
+    #[clap(long, default_value = "198.18.0.0/15")]
+    pub fake_ip_range: String,
+
+    /// File fake addresses are dumped to as CSV for the tunnel, like the route dump
+    #[clap(long)]
+    pub fake_ip_file: Option<String>,
+
+    /// Route the addresses of cached answers again on every hit, so routes flushed by a tunnel
+    /// restart come back before the answers expire
+    #[clap(long)]
+    pub reroute_on_cache_hit: bool,
+
+    /// Only add routes for IPv4 addresses, IPv6 answers are still returned to clients
+    #[clap(long)]
+    pub route_prefer_ipv4: bool,
+
+    /// Add routes for private and reserved addresses too, which usually black-holes LAN traffic
+    #[clap(long)]
+    pub route_private: bool,
+
+    /// Split policy for a client address, format like 192.168.1.10=direct, policy can be direct, tunnel or a domain list file
+    #[clap(long)]
+    pub client_policy: Vec<String>,
+
+    /// Local service answered to SRV queries, format like _http._tcp.corp.local=web.corp.local:8080
+    #[clap(long)]
+    pub srv_map: Vec<String>,
+
+    /// Cache hits needed for an entry to be refreshed before it expires, 0 for disable
+    #[clap(long, default_value = "0")]
+    pub proactive_refresh: u32,
+
+    /// Flag for resolving AAAA records along with A queries
+    #[clap(long)]
+    pub prefetch_aaaa: bool,
+
+    /// Client network allowed to query like 192.168.1.0/24, others are refused, loopback is
+    /// always allowed, can be repeated, every client is allowed if not given
+    #[clap(long)]
+    pub allowed_clients: Vec<String>,
+
+    /// Queries per second a client address may send, queries above the rate are dropped,
+    /// 0 for unlimited
+    #[clap(long, default_value = "0")]
+    pub per_client_qps: u32,
+
+    /// Max length of a queried name, longer ones are rejected before touching cache and lists
+    #[clap(long, default_value = "253")]
+    pub max_name_length: usize,
+
+    /// Flag for answering AAAA queries with no record, so applications fall back to IPv4
+    #[clap(long)]
+    pub suppress_aaaa: bool,
+
+    /// Flag for answering A queries with no record, so applications fall back to IPv6
+    #[clap(long)]
+    pub suppress_a: bool,
+
+    /// Flag for synthesizing AAAA answers from A records for names without IPv6 addresses,
+    /// so IPv6-only clients reach them through NAT64
+    #[clap(long)]
+    pub dns64: bool,
+
+    /// NAT64 prefix synthesized AAAA answers embed IPv4 addresses in, only /96 is supported
+    #[clap(long, default_value = "64:ff9b::/96")]
+    pub nat64_prefix: String,
+
+    /// Name answered locally with the first listen address like gateway.trojan, or with the
+    /// given address like gateway.trojan=10.8.0.1, can be repeated
+    #[clap(long)]
+    pub local_names: Vec<String>,
+
+    /// Order names are matched against the lists in, stages left out are skipped and names in
+    /// none of them are resolved through poisoned DNS
+    #[clap(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "sinkhole,hosts,force-direct,block"
+    )]
+    pub resolution_pipeline: Vec<ResolutionStage>,
+
+    /// What to do with a message carrying more than one query, which few servers support
+    #[clap(long, value_enum, default_value = "reject")]
+    pub multi_query_mode: MultiQueryMode,
+
+    /// Answer PTR query for the listen address locally instead of forwarding it
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub intercept_loopback_ptr: bool,
+
+    /// Answer PTR queries for addresses in the cache with the domain resolved to them
+    #[clap(long)]
+    pub ptr_from_cache: bool,
+
+    /// Warn when a routed address is resolved from this many domains, 0 for disable
+    #[clap(long, default_value = "8")]
+    pub route_shared_warn: usize,
+
+    /// What to do with a resolved address when the route thread falls behind
+    #[clap(long, value_enum, default_value = "drop-newest")]
+    pub route_channel_policy: RoutePolicy,
+
+    /// CSV file the routed address to domain mapping is saved to, along with CNAME targets
+    #[clap(long)]
+    pub route_dump_file: Option<String>,
+
+    /// Milliseconds addresses resolved for a domain are collected before routing them
+    /// together, batching CDN answers spread over several queries, 0 for disable
+    #[clap(long, default_value = "0")]
+    pub route_debounce_ms: u64,
+
+    /// Block size trusted queries are padded to, hiding name lengths in the tunnel, 0 for disable
+    #[clap(long, default_value = "0")]
+    pub dns_padding: usize,
+
+    /// Client subnet like 1.2.3.0/24 sent to trusted DNS, so CDNs answer with nearby edges
+    #[clap(long)]
+    pub ecs_subnet: Option<String>,
+
+    /// Domain whose HTTPS/SVCB answers are stripped of ECH config, can be repeated
+    #[clap(long)]
+    pub strip_ech_domains: Vec<String>,
+
+    /// Seconds a client waits for an upstream answer, later answers are not sent to it
+    #[clap(long, default_value = "10")]
+    pub pending_client_timeout: u64,
+
+    /// Times an unanswered upstream query is sent again before it times out, 0 for disable
+    #[clap(long, default_value = "0")]
+    pub dns_retries: u32,
+
+    /// Milliseconds between retransmissions of an unanswered upstream query
+    #[clap(long, default_value = "1000")]
+    pub dns_retry_interval_ms: u64,
+
+    /// Flag for answering through the system resolver when upstream DNS doesn't answer in time
+    #[clap(long)]
+    pub system_fallback: bool,
+
+    /// Domain list always resolved through poisoned DNS, even if it's in the blocked domain list
+    #[clap(long)]
+    pub force_direct_list: Option<String>,
+
+    /// Domain list never cached, like dynamic DNS names whose addresses change constantly
+    #[clap(long)]
+    pub no_cache_list: Option<String>,
+
+    /// Connectivity probe name always resolved through poisoned DNS, added to the built-in ones
+    #[clap(long)]
+    pub captive_portal_domains: Vec<String>,
+
+    /// Domain list answered with the block page address instead of being resolved
+    #[clap(long)]
+    pub sinkhole_domain_list: Option<String>,
+
+    /// Block page address sinkholed A queries resolve to
+    #[clap(long, default_value = "0.0.0.0")]
+    pub sinkhole_ip: Ipv4Addr,
+
+    /// Block page address sinkholed AAAA queries resolve to
+    #[clap(long, default_value = "::")]
+    pub sinkhole_ipv6: Ipv6Addr,
+
+    /// TTL of answers synthesized locally, like local names, sinkholed domains, local services
+    /// and NODATA, also their negative caching time
+    #[clap(long, default_value = "300")]
+    pub local_answer_ttl: u32,
+
+    /// Answer to version.bind like CHAOS queries, empty for refusing them
+    #[clap(long, default_value = "")]
+    pub dns_version_string: String,
+
+    /// Flag for rotating A/AAAA records of cached answers on every hit, like round-robin rrset-order
+    #[clap(long)]
+    pub rrset_rotate: bool,
+
+    /// Flag for dropping additional and non-SOA authority records from answers before caching
+    #[clap(long)]
+    pub minimal_responses: bool,
+
+    /// Flag for logging malformed or mismatched DNS messages as hex
+    #[clap(long)]
+    pub dns_debug_hex: bool,
+
+    /// Flag for answering malformed queries with FORMERR, instead of dropping them
+    #[clap(long)]
+    pub reply_formerr: bool,
+
+    /// Fraction of queries also sent to the other upstream, logging how their addresses
+    /// differ, clients are answered as usual
+    #[clap(long, default_value = "0")]
+    pub compare_sample_rate: f64,
+
+    /// Max seconds an answer is cached, so long-lived records are re-resolved, 0 for no limit
+    #[clap(long, default_value = "0")]
+    pub dns_max_ttl: u32,
+
+    /// Min seconds an answer is cached, so short-lived records aren't re-resolved all the time,
+    /// 0 for no limit, the max TTL wins when it's lower
+    #[clap(long, default_value = "0")]
+    pub dns_min_ttl: u32,
+
+    /// Interface index routes for resolved addresses are added on, default to the tun adapter
+    #[clap(long)]
+    pub route_if_index: Option<u32>,
+
+    /// Metric of routes for resolved addresses
+    #[clap(long, default_value = "99")]
+    pub route_metric: u32,
 }
 
 #[derive(Parser)]
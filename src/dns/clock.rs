@@ -0,0 +1,25 @@
+use std::time::Instant;
+
+use chrono::Timelike;
+
+/// Source of the current time, so tests can move time forward without sleeping
+pub trait Clock {
+    fn now(&self) -> Instant;
+
+    /// Minute of the local day, for time windows of scheduled domains.
+    fn local_minute(&self) -> u32;
+}
+
+/// Clock reading the system time
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn local_minute(&self) -> u32 {
+        let time = chrono::Local::now().time();
+        time.hour() * 60 + time.minute()
+    }
+}
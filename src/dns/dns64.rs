@@ -0,0 +1,103 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use trust_dns_proto::{
+    op::{Message, Query, ResponseCode},
+    rr::{RData, Record, RecordType},
+};
+
+use crate::types::{Result, TrojanError};
+
+/// Parse a NAT64 prefix like 64:ff9b::/96, only /96 prefixes embedding the IPv4 address in
+/// the last 32 bits are supported.
+pub fn parse_prefix(prefix: &str) -> Result<Ipv6Addr> {
+    let invalid = || TrojanError::InvalidConfig(format!("invalid nat64 prefix:{}", prefix));
+    let (addr, length) = prefix.split_once('/').ok_or_else(invalid)?;
+    let addr: Ipv6Addr = addr.parse().map_err(|_| invalid())?;
+    if length != "96" || addr.segments()[6..] != [0, 0] {
+        return Err(invalid());
+    }
+    Ok(addr)
+}
+
+/// IPv6 address reaching `ip` through the NAT64 gateway of `prefix`, see RFC 6052.
+pub fn synthesize(prefix: Ipv6Addr, ip: Ipv4Addr) -> Ipv6Addr {
+    let mut octets = prefix.octets();
+    octets[12..].copy_from_slice(&ip.octets());
+    octets.into()
+}
+
+/// Whether the AAAA `response` has no address, so it has to be synthesized from A records.
+pub fn needs_synthesis(response: &Message) -> bool {
+    response.response_code() == ResponseCode::NoError
+        && response.queries()[0].query_type() == RecordType::AAAA
+        && !response
+            .answers()
+            .iter()
+            .any(|record| record.record_type() == RecordType::AAAA)
+}
+
+/// Answer the AAAA `question` with addresses synthesized from the A `response`, other
+/// records like CNAME are kept.
+pub fn synthesize_response(response: &Message, question: &Query, prefix: Ipv6Addr) -> Message {
+    let mut message = response.clone();
+    message.take_queries();
+    message.add_query(question.clone());
+    let answers = message
+        .take_answers()
+        .into_iter()
+        .map(|record| match record.data() {
+            Some(RData::A(ip)) => Record::from_rdata(
+                record.name().clone(),
+                record.ttl(),
+                RData::AAAA(synthesize(prefix, *ip)),
+            ),
+            _ => record,
+        })
+        .collect();
+    message.insert_answers(answers);
+    message
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_proto::{
+        op::{Message, Query},
+        rr::{Name, RData, Record, RecordType},
+    };
+
+    use crate::dns::dns64::{parse_prefix, synthesize_response};
+
+    #[test]
+    fn test_synthesize_response() {
+        let prefix = parse_prefix("64:ff9b::/96").unwrap();
+        let name = Name::from_str("example.com.").unwrap();
+        let target = Name::from_str("cdn.example.com.").unwrap();
+        let mut response = Message::new();
+        response.add_query(Query::query(name.clone(), RecordType::A));
+        response.add_answer(Record::from_rdata(
+            name.clone(),
+            60,
+            RData::CNAME(target.clone()),
+        ));
+        response.add_answer(Record::from_rdata(
+            target,
+            300,
+            RData::A("1.2.3.4".parse().unwrap()),
+        ));
+        let question = Query::query(name, RecordType::AAAA);
+        let message = synthesize_response(&response, &question, prefix);
+        assert_eq!(message.queries(), &[question]);
+        assert_eq!(message.answers()[0].record_type(), RecordType::CNAME);
+        assert_eq!(message.answers()[1].ttl(), 300);
+        assert_eq!(
+            message.answers()[1].data(),
+            Some(&RData::AAAA("64:ff9b::102:304".parse().unwrap()))
+        );
+
+        assert!(parse_prefix("64:ff9b::/64").is_err());
+        assert!(parse_prefix("64:ff9b::1/96").is_err());
+        assert!(parse_prefix("64:ff9b::").is_err());
+    }
+}
@@ -1,4 +1,15 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Cursor, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    dns::gzip,
+    types::{Result, TrojanError},
+    utils::expand_path,
+};
 
 pub struct DomainMap {
     map: HashMap<String, Option<DomainMap>>,
@@ -11,6 +22,76 @@ impl DomainMap {
         }
     }
 
+    pub fn with_file(file: &str) -> Result<Self> {
+        Self::with_reader(open_list(&expand_path(file))?)
+    }
+
+    /// Merge list files and every file of listed directories, files failing to load are
+    /// logged and skipped. Empty paths are ignored. Loading fails once more than
+    /// `max_entries` domains are read, so a wrong file doesn't exhaust memory.
+    pub fn with_files(paths: &[String], max_entries: usize) -> Result<Self> {
+        let mut domain_map = Self::new();
+        let mut entries = 0;
+        for path in paths.iter().filter(|path| !path.is_empty()) {
+            let path = expand_path(path);
+            let files = if Path::new(&path).is_dir() {
+                match list_dir(&path) {
+                    Ok(files) => files,
+                    Err(err) => {
+                        log::error!("list domain directory {} failed:{:?}", path, err);
+                        continue;
+                    }
+                }
+            } else {
+                vec![path]
+            };
+            for file in files {
+                let result = open_list(&file)
+                    .and_then(|reader| domain_map.add_reader(reader, max_entries - entries));
+                match result {
+                    Ok(count) => entries += count,
+                    Err(TrojanError::InvalidConfig(_)) => {
+                        return Err(TrojanError::InvalidConfig(format!(
+                            "domain lists exceed {} entries at {}",
+                            max_entries, file
+                        )))
+                    }
+                    Err(err) => log::error!("load domain list {} failed:{:?}, skipped", file, err),
+                }
+            }
+        }
+        Ok(domain_map)
+    }
+
+    /// Parse one domain per line straight into the map, reusing a single line buffer.
+    pub fn with_reader(reader: impl BufRead) -> Result<Self> {
+        let mut domain_map = Self::new();
+        domain_map.add_reader(reader, usize::MAX)?;
+        Ok(domain_map)
+    }
+
+    /// Add domains of `reader`, returns how many are read or `InvalidConfig` past
+    /// `max_entries`.
+    fn add_reader(&mut self, mut reader: impl BufRead, max_entries: usize) -> Result<usize> {
+        let mut entries = 0;
+        let mut line = String::new();
+        while reader.read_line(&mut line)? != 0 {
+            let domain = line.trim();
+            if !domain.is_empty() && !domain.starts_with('#') {
+                if entries == max_entries {
+                    return Err(TrojanError::InvalidConfig(format!(
+                        "more than {} domains",
+                        max_entries
+                    )));
+                }
+                self.add_domain(domain);
+                entries += 1;
+            }
+            line.clear();
+        }
+        Ok(entries)
+    }
+
     pub fn add_domain(&mut self, domain: &str) {
         let mut iter = domain.split('.').rev();
         if domain.ends_with('.') {
@@ -54,17 +135,61 @@ impl DomainMap {
     }
 }
 
+/// Files of `dir` sorted by name, so merge order doesn't depend on the file system.
+fn list_dir(dir: &str) -> Result<Vec<String>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() {
+            files.push(path.to_string_lossy().into_owned());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Open the domain list `file`, gzipped lists are told by their magic bytes and decompressed
+/// whole.
+fn open_list(file: &str) -> Result<Box<dyn BufRead>> {
+    let mut reader = BufReader::new(File::open(file)?);
+    if !reader.fill_buf()?.starts_with(&gzip::MAGIC) {
+        return Ok(Box::new(reader));
+    }
+    let mut data = vec![];
+    reader.read_to_end(&mut data)?;
+    Ok(Box::new(Cursor::new(gzip::decompress(&data)?)))
+}
+
+/// Append `domain` as a new line of the domain list `file`.
+/// Gzipped lists are left alone, a plain line would corrupt them.
+pub fn append_domain(file: &str, domain: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(expand_path(file))?;
+    let mut magic = [0u8; 2];
+    if file.read_exact(&mut magic).is_ok() && magic == gzip::MAGIC {
+        return Err(TrojanError::InvalidConfig(
+            "can't append to a gzipped domain list".into(),
+        ));
+    }
+    writeln!(file, "{}", domain.trim_end_matches('.'))?;
+    Ok(())
+}
+
 mod tests {
     #![allow(unused_imports)]
     extern crate test;
 
     use std::{
-        fs::File,
-        io::{BufRead, BufReader},
+        env,
+        fs::{self, File},
+        io::{BufRead, BufReader, Cursor},
     };
     use test::Bencher;
 
-    use crate::dns::domain::DomainMap;
+    use crate::dns::domain::{append_domain, DomainMap};
 
     #[test]
     fn test_contains() {
@@ -79,6 +204,75 @@ mod tests {
         assert!(domain_map.contains("www.youtube.com."));
     }
 
+    #[test]
+    fn test_with_reader() {
+        let mut content = String::from("# comment\n\n");
+        for i in 0..100000 {
+            content += format!("domain{}.example.com\r\n", i).as_str();
+        }
+        let domain_map = DomainMap::with_reader(Cursor::new(content)).unwrap();
+        assert!(domain_map.contains("www.domain99999.example.com."));
+        assert!(!domain_map.contains("# comment"));
+    }
+
+    #[test]
+    fn test_append_domain() {
+        let file = env::temp_dir().join("trojan_test_append_domain.txt");
+        let file = file.to_str().unwrap();
+        fs::write(file, "example.com\n").unwrap();
+        assert!(!DomainMap::with_file(file).unwrap().contains("learned.org."));
+        append_domain(file, "learned.org.").unwrap();
+        let domain_map = DomainMap::with_file(file).unwrap();
+        fs::remove_file(file).unwrap();
+        assert!(domain_map.contains("example.com."));
+        assert!(domain_map.contains("www.learned.org."));
+    }
+
+    #[test]
+    fn test_with_files() {
+        let dir = env::temp_dir().join("trojan_test_domain_lists");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "example.com\nshared.org\n").unwrap();
+        fs::write(dir.join("b.txt"), "example.net\nshared.org\n").unwrap();
+        let file = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let files = [file("a.txt"), file("missing.txt"), file("b.txt")];
+        let domain_map = DomainMap::with_files(&files, 4).unwrap();
+        assert!(domain_map.contains("www.example.com."));
+        assert!(domain_map.contains("www.example.net."));
+        assert!(domain_map.contains("shared.org."));
+        assert!(DomainMap::with_files(&files, 3).is_err());
+        let domain_map = DomainMap::with_files(&[dir.to_str().unwrap().to_string()], 4).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(domain_map.contains("www.example.com."));
+        assert!(domain_map.contains("www.example.net."));
+        assert!(!domain_map.contains("example.org."));
+    }
+
+    #[test]
+    fn test_gzip_list() {
+        // gzip of "blocked.org\n# comment\nads.example.net\n"
+        let data = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x4b, 0xca, 0xc9, 0x4f,
+            0xce, 0x4e, 0x4d, 0xd1, 0xcb, 0x2f, 0x4a, 0xe7, 0x52, 0x56, 0x48, 0xce, 0xcf, 0xcd,
+            0x4d, 0xcd, 0x2b, 0xe1, 0x4a, 0x4c, 0x29, 0xd6, 0x4b, 0xad, 0x48, 0xcc, 0x2d, 0xc8,
+            0x49, 0xd5, 0xcb, 0x4b, 0x2d, 0xe1, 0x02, 0x00, 0x91, 0x93, 0xfb, 0x74, 0x26, 0x00,
+            0x00, 0x00,
+        ];
+        let file = env::temp_dir().join("trojan_test_gzip_list.txt.gz");
+        fs::write(&file, data).unwrap();
+        let files = [file.to_str().unwrap().to_string()];
+        let domain_map = DomainMap::with_files(&files, 2).unwrap();
+        assert!(domain_map.contains("www.blocked.org."));
+        assert!(domain_map.contains("ads.example.net."));
+        assert!(!domain_map.contains("example.org."));
+        assert!(DomainMap::with_file(&files[0])
+            .unwrap()
+            .contains("blocked.org."));
+        assert!(append_domain(&files[0], "learned.org.").is_err());
+        fs::remove_file(&file).unwrap();
+    }
+
     #[bench]
     fn bench_contains(b: &mut Bencher) {
         let mut domain_map = DomainMap::new();
@@ -0,0 +1,209 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use trust_dns_proto::{
+    op::{Edns, Message},
+    rr::rdata::opt::{EdnsCode, EdnsOption, OPT},
+};
+
+use crate::{
+    proto::MAX_PACKET_SIZE,
+    types::{Result, TrojanError},
+};
+
+/// Private EDNS option asking to resolve upstream even if the answer is cached
+pub const CACHE_BYPASS: u16 = 65001;
+
+/// Raw data of the EDNS option `code` carried by `message`.
+pub fn option_data(message: &Message, code: EdnsCode) -> Option<Vec<u8>> {
+    match message.extensions().as_ref()?.option(code)? {
+        EdnsOption::Unknown(_, data) => Some(data.clone()),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    }
+}
+
+pub fn set_option(edns: &mut Edns, code: EdnsCode, data: Vec<u8>) {
+    edns.options_mut()
+        .insert(EdnsOption::Unknown(code.into(), data));
+}
+
+pub fn remove_option(edns: &mut Edns, code: EdnsCode) {
+    let options: HashMap<EdnsCode, EdnsOption> = edns
+        .options()
+        .as_ref()
+        .iter()
+        .filter(|(key, _)| **key != code)
+        .map(|(key, option)| (*key, option.clone()))
+        .collect();
+    *edns.options_mut() = OPT::new(options);
+}
+
+/// Largest UDP response the client of `query` accepts, 512 bytes without EDNS, see RFC 6891.
+pub fn payload_size(query: &Message) -> usize {
+    query
+        .extensions()
+        .as_ref()
+        .map_or(512, |edns| edns.max_payload().max(512) as usize)
+}
+
+pub fn bypass_cache(query: &Message) -> bool {
+    option_data(query, EdnsCode::from(CACHE_BYPASS)).is_some()
+}
+
+/// Encode a subnet like `1.2.3.0/24` as CLIENT-SUBNET option data, see RFC 7871.
+pub fn client_subnet(subnet: &str) -> Result<Vec<u8>> {
+    let invalid = || TrojanError::InvalidConfig(format!("invalid ecs subnet:{}", subnet));
+    let (ip, prefix) = subnet.split_once('/').ok_or_else(invalid)?;
+    let ip: IpAddr = ip.parse().map_err(|_| invalid())?;
+    let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+    let (family, mut address) = match ip {
+        IpAddr::V4(ip) if prefix <= 32 => (1u16, ip.octets().to_vec()),
+        IpAddr::V6(ip) if prefix <= 128 => (2u16, ip.octets().to_vec()),
+        _ => return Err(invalid()),
+    };
+    // only the leading prefix bits are sent, the rest must be zero
+    address.truncate((prefix as usize).div_ceil(8));
+    if let (Some(last), bits @ 1..=7) = (address.last_mut(), prefix % 8) {
+        *last &= !0u8 << (8 - bits);
+    }
+    let mut data = family.to_be_bytes().to_vec();
+    data.push(prefix);
+    data.push(0);
+    data.extend(address);
+    Ok(data)
+}
+
+/// Attach CLIENT-SUBNET `data` to `query`, adding an OPT record if the client sent none.
+pub fn set_client_subnet(query: &mut Message, data: Vec<u8>) {
+    let edns = query.extensions_mut().get_or_insert_with(|| {
+        let mut edns = Edns::new();
+        edns.set_max_payload(MAX_PACKET_SIZE as u16);
+        edns
+    });
+    set_option(edns, EdnsCode::Subnet, data);
+}
+
+/// Add a PADDING option to `query`, so its size is a multiple of `block_size`, see RFC 7830.
+pub fn set_padding(query: &mut Message, block_size: usize) {
+    let edns = query.extensions_mut().get_or_insert_with(|| {
+        let mut edns = Edns::new();
+        edns.set_max_payload(MAX_PACKET_SIZE as u16);
+        edns
+    });
+    // measure with an empty option, so its header is counted
+    set_option(edns, EdnsCode::Padding, vec![]);
+    let length = query.to_vec().map(|data| data.len()).unwrap_or_default();
+    let padding = (block_size - length % block_size) % block_size;
+    if let Some(edns) = query.extensions_mut() {
+        set_option(edns, EdnsCode::Padding, vec![0; padding]);
+    }
+}
+
+/// Replace the COOKIE option of a cached `response` with the one carried by `query`,
+/// so the cookie of the client who filled the cache never reaches another client.
+pub fn rewrite_cookie(response: &mut Message, query: &Message) {
+    let cookie = option_data(query, EdnsCode::Cookie);
+    if let Some(edns) = response.extensions_mut() {
+        match cookie {
+            Some(cookie) => set_option(edns, EdnsCode::Cookie, cookie),
+            None => remove_option(edns, EdnsCode::Cookie),
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[allow(unused_imports)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_proto::{
+        op::{Edns, Message, Query},
+        rr::{rdata::opt::EdnsCode, Name, RecordType},
+        serialize::binary::BinDecodable,
+    };
+
+    use crate::dns::edns::{
+        client_subnet, option_data, payload_size, rewrite_cookie, set_client_subnet, set_option,
+        set_padding,
+    };
+
+    fn message_with_cookie(cookie: &[u8]) -> Message {
+        let mut edns = Edns::new();
+        set_option(&mut edns, EdnsCode::Cookie, cookie.to_vec());
+        let mut message = Message::new();
+        message.set_edns(edns);
+        message
+    }
+
+    #[test]
+    fn test_rewrite_cookie() {
+        let cached = message_with_cookie(&[0; 16]);
+        for cookie in [[1u8; 8], [2u8; 8]] {
+            let query = message_with_cookie(&cookie);
+            let mut response = cached.clone();
+            rewrite_cookie(&mut response, &query);
+            assert_eq!(
+                option_data(&response, EdnsCode::Cookie),
+                Some(cookie.to_vec())
+            );
+        }
+
+        let mut response = cached;
+        rewrite_cookie(&mut response, &Message::new());
+        assert_eq!(option_data(&response, EdnsCode::Cookie), None);
+    }
+
+    #[test]
+    fn test_payload_size() {
+        let mut query = Message::new();
+        assert_eq!(payload_size(&query), 512);
+        let mut edns = Edns::new();
+        edns.set_max_payload(1232);
+        query.set_edns(edns.clone());
+        assert_eq!(payload_size(&query), 1232);
+        // sizes below the classic limit are treated as 512
+        edns.set_max_payload(256);
+        query.set_edns(edns);
+        assert_eq!(payload_size(&query), 512);
+    }
+
+    #[test]
+    fn test_client_subnet() {
+        assert_eq!(
+            client_subnet("1.2.3.4/24").unwrap(),
+            vec![0, 1, 24, 0, 1, 2, 3]
+        );
+        assert_eq!(
+            client_subnet("1.2.255.4/20").unwrap(),
+            vec![0, 1, 20, 0, 1, 2, 240]
+        );
+        assert_eq!(
+            client_subnet("2001:db8::/32").unwrap(),
+            vec![0, 2, 32, 0, 32, 1, 13, 184]
+        );
+        assert!(client_subnet("1.2.3.4").is_err());
+        assert!(client_subnet("1.2.3.4/33").is_err());
+
+        let mut query = Message::new();
+        query.add_query(Query::query(
+            Name::from_str("example.com.").unwrap(),
+            RecordType::A,
+        ));
+        let data = client_subnet("1.2.3.0/24").unwrap();
+        set_client_subnet(&mut query, data.clone());
+        let sent = Message::from_bytes(query.to_vec().unwrap().as_slice()).unwrap();
+        assert_eq!(option_data(&sent, EdnsCode::Subnet), Some(data));
+    }
+
+    #[test]
+    fn test_padding() {
+        for name in ["a.com.", "a-much-longer-name.example.com."] {
+            let mut query = Message::new();
+            query.add_query(Query::query(Name::from_str(name).unwrap(), RecordType::A));
+            set_padding(&mut query, 128);
+            let data = query.to_vec().unwrap();
+            assert_eq!(data.len(), 128);
+            assert!(Message::from_bytes(data.as_slice()).is_ok());
+        }
+    }
+}
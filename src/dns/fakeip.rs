@@ -0,0 +1,217 @@
+#[cfg(windows)]
+use std::time::SystemTime;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{BufWriter, Write},
+    net::Ipv4Addr,
+};
+
+use itertools::Itertools;
+
+use crate::types::{Result, TrojanError};
+
+/// Parse an IPv4 range like 198.18.0.0/15 into its network address and mask.
+fn parse_range(range: &str) -> Result<(u32, u32)> {
+    let invalid = || TrojanError::InvalidConfig(format!("invalid fake ip range:{}", range));
+    let (addr, prefix) = range.trim().split_once('/').ok_or_else(invalid)?;
+    let addr: Ipv4Addr = addr.parse().map_err(|_| invalid())?;
+    let prefix: u32 = prefix.parse().map_err(|_| invalid())?;
+    if !(8..=30).contains(&prefix) {
+        return Err(invalid());
+    }
+    let mask = !0u32 << (32 - prefix);
+    Ok((u32::from(addr) & mask, mask))
+}
+
+/// Fake addresses handed out to domains tunneled by name, the connector maps them back to
+/// the hostname so the trojan server resolves it
+pub struct FakeIpPool {
+    /// First address of the range as a number
+    base: u32,
+    size: u32,
+    /// Offset of the next address never handed out
+    next: u32,
+    /// Counts uses, so the stamps order addresses by their last use
+    stamp: u64,
+    /// Handed out addresses by the stamp of their last use, the least recently used one is
+    /// taken over once all are used
+    uses: BTreeMap<u64, Ipv4Addr>,
+    /// Domain of each handed out address, along with the stamp of its last use
+    domains: HashMap<Ipv4Addr, (String, u64)>,
+    addresses: HashMap<String, Ipv4Addr>,
+}
+
+impl FakeIpPool {
+    /// Parse an IPv4 range like 198.18.0.0/15, the network and broadcast addresses are skipped.
+    pub fn parse(range: &str) -> Result<Self> {
+        let (network, mask) = parse_range(range)?;
+        Ok(Self {
+            base: network + 1,
+            size: !mask - 1,
+            next: 0,
+            stamp: 0,
+            uses: BTreeMap::new(),
+            domains: HashMap::new(),
+            addresses: HashMap::new(),
+        })
+    }
+
+    /// Fake address of `domain`, a new one is handed out if it has none yet.
+    /// Returns the address and whether it's new.
+    pub fn allocate(&mut self, domain: &str) -> (Ipv4Addr, bool) {
+        let domain = domain.trim_end_matches('.').to_lowercase();
+        self.stamp += 1;
+        if let Some(ip) = self.addresses.get(&domain).copied() {
+            // a domain in use keeps its address, the idle ones are taken over first
+            if let Some((_, stamp)) = self.domains.get_mut(&ip) {
+                self.uses.remove(stamp);
+                *stamp = self.stamp;
+            }
+            self.uses.insert(self.stamp, ip);
+            return (ip, false);
+        }
+        let ip = if self.next < self.size {
+            self.next += 1;
+            Ipv4Addr::from(self.base + self.next - 1)
+        } else {
+            let (_, ip) = self.uses.pop_first().unwrap();
+            if let Some((old, _)) = self.domains.remove(&ip) {
+                log::warn!("fake ip {} taken over from {} by {}", ip, old, domain);
+                self.addresses.remove(&old);
+            }
+            ip
+        };
+        self.uses.insert(self.stamp, ip);
+        self.addresses.insert(domain.clone(), ip);
+        self.domains.insert(ip, (domain, self.stamp));
+        (ip, true)
+    }
+
+    /// Domain the fake address `ip` was handed out to.
+    pub fn domain(&self, ip: Ipv4Addr) -> Option<&str> {
+        self.domains.get(&ip).map(|(domain, _)| domain.as_str())
+    }
+
+    /// Replace `file` with `ip,domain` lines of every handed out address, readers never see
+    /// a partial dump.
+    pub fn dump_file(&self, file: &str) -> Result<()> {
+        let temp = format!("{}.tmp", file);
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        for ip in self.domains.keys().sorted() {
+            writeln!(writer, "{},{}", ip, self.domains[ip].0)?;
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(temp, file)?;
+        Ok(())
+    }
+}
+
+/// Fake addresses handed out by the DNS server, read from the file it dumps them to
+#[cfg(windows)]
+pub struct FakeIpMap {
+    file: Option<String>,
+    /// Network address and mask of the fake range, other addresses are never looked up
+    network: u32,
+    mask: u32,
+    /// Modification time of the file when it was read
+    modified: Option<SystemTime>,
+    domains: HashMap<Ipv4Addr, String>,
+}
+
+// only the wintun connector looks addresses up
+#[cfg(windows)]
+impl FakeIpMap {
+    /// Map of the fake addresses in `range` the DNS server dumps to `file`.
+    pub fn new(file: Option<String>, range: &str) -> Result<Self> {
+        let (network, mask) = parse_range(range)?;
+        Ok(Self {
+            file,
+            network,
+            mask,
+            modified: None,
+            domains: HashMap::new(),
+        })
+    }
+
+    /// Hostname the fake address `ip` stands for, the file is read again if it changed since.
+    pub fn domain(&mut self, ip: Ipv4Addr) -> Option<&str> {
+        if u32::from(ip) & self.mask != self.network {
+            return None;
+        }
+        if !self.domains.contains_key(&ip) {
+            self.reload();
+        }
+        self.domains.get(&ip).map(String::as_str)
+    }
+
+    fn reload(&mut self) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+        let modified = fs::metadata(file)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified.is_none() || modified == self.modified {
+            return;
+        }
+        match fs::read_to_string(file) {
+            Ok(content) => {
+                self.domains = content
+                    .lines()
+                    .filter_map(|line| {
+                        let (ip, domain) = line.split_once(',')?;
+                        Some((ip.parse().ok()?, domain.to_string()))
+                    })
+                    .collect();
+                self.modified = modified;
+            }
+            Err(err) => log::error!("read fake ip file {} failed:{}", file, err),
+        }
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use std::{env, fs, net::Ipv4Addr};
+
+    #[cfg(windows)]
+    use crate::dns::fakeip::FakeIpMap;
+    use crate::dns::fakeip::FakeIpPool;
+
+    #[test]
+    fn test_fake_ip_pool() {
+        let mut pool = FakeIpPool::parse("198.18.0.0/30").unwrap();
+        let (first, new) = pool.allocate("www.Example.com.");
+        assert!(new);
+        assert_eq!(first, Ipv4Addr::new(198, 18, 0, 1));
+        assert_eq!(pool.allocate("www.example.com"), (first, false));
+        let (second, _) = pool.allocate("other.example.com.");
+        assert_eq!(second, Ipv4Addr::new(198, 18, 0, 2));
+        // the range is used up, the least recently used address is taken over
+        assert_eq!(pool.allocate("www.example.com."), (first, false));
+        assert_eq!(pool.allocate("third.example.com."), (second, true));
+        assert_eq!(pool.domain(second), Some("third.example.com"));
+        assert_eq!(pool.domain(first), Some("www.example.com"));
+        assert_eq!(pool.allocate("fourth.example.com.").0, first);
+        assert!(FakeIpPool::parse("198.18.0.0").is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_fake_ip_map() {
+        let mut pool = FakeIpPool::parse("198.18.0.0/30").unwrap();
+        let (ip, _) = pool.allocate("www.example.com.");
+        let file = env::temp_dir().join("trojan_test_fake_ip.csv");
+        let file = file.to_str().unwrap();
+        pool.dump_file(file).unwrap();
+        let mut map = FakeIpMap::new(Some(file.to_string()), "198.18.0.0/30").unwrap();
+        assert_eq!(map.domain(ip), Some("www.example.com"));
+        assert_eq!(map.domain(Ipv4Addr::new(198, 18, 0, 2)), None);
+        // addresses outside the range are real ones
+        assert_eq!(map.domain(Ipv4Addr::new(1, 2, 3, 4)), None);
+        fs::remove_file(file).unwrap();
+    }
+}
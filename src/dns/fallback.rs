@@ -0,0 +1,145 @@
+use std::{
+    collections::HashSet,
+    io,
+    net::{IpAddr, ToSocketAddrs},
+    sync::mpsc::{channel, Receiver, Sender},
+    thread,
+};
+
+use trust_dns_proto::{
+    op::{Message, ResponseCode},
+    rr::{RData, Record, RecordType},
+};
+
+use crate::dns::local;
+
+/// TTL of answers synthesized from the system resolver
+const FALLBACK_TTL: u32 = 60;
+
+/// Name resolution of the operating system
+pub trait SystemResolver {
+    fn lookup(&self, name: &str) -> io::Result<Vec<IpAddr>>;
+}
+
+/// System resolver reached through getaddrinfo
+pub struct StdResolver;
+
+impl SystemResolver for StdResolver {
+    fn lookup(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+        Ok((name, 0).to_socket_addrs()?.map(|addr| addr.ip()).collect())
+    }
+}
+
+/// Answer A/AAAA `request` with addresses from `resolver`, other types are refused.
+pub fn fallback_response(request: &Message, resolver: &dyn SystemResolver) -> Message {
+    let query = &request.queries()[0];
+    if !matches!(query.query_type(), RecordType::A | RecordType::AAAA) {
+        return local::empty_response(request, ResponseCode::Refused);
+    }
+    let name = query.name().to_utf8();
+    let addresses = match resolver.lookup(name.trim_end_matches('.')) {
+        Ok(addresses) => addresses,
+        Err(err) => {
+            log::error!("system resolver lookup {} failed:{}", name, err);
+            return local::empty_response(request, ResponseCode::ServFail);
+        }
+    };
+    let answers: Vec<Record> = addresses
+        .into_iter()
+        .filter_map(|ip| match (ip, query.query_type()) {
+            (IpAddr::V4(ip), RecordType::A) => Some(RData::A(ip)),
+            (IpAddr::V6(ip), RecordType::AAAA) => Some(RData::AAAA(ip)),
+            _ => None,
+        })
+        .map(|data| Record::from_rdata(query.name().clone(), FALLBACK_TTL, data))
+        .collect();
+    let mut response = local::empty_response(request, ResponseCode::NoError);
+    response.add_answers(answers);
+    response
+}
+
+/// Runs system resolver lookups off the DNS loop, as they block and may even come back
+/// to this server when it's the system DNS.
+pub struct FallbackResolver {
+    sender: Sender<(String, Message)>,
+    receiver: Receiver<(String, Message)>,
+    pending: HashSet<String>,
+}
+
+impl FallbackResolver {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel();
+        Self {
+            sender,
+            receiver,
+            pending: HashSet::new(),
+        }
+    }
+
+    /// Resolve `request` cached as `key` on a new thread, unless it's being resolved already.
+    pub fn resolve(&mut self, key: String, request: Message) {
+        if !self.pending.insert(key.clone()) {
+            return;
+        }
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            let response = fallback_response(&request, &StdResolver);
+            let _ = sender.send((key, response));
+        });
+    }
+
+    /// Responses resolved since the last call, with their cache keys.
+    pub fn responses(&mut self) -> Vec<(String, Message)> {
+        let responses: Vec<(String, Message)> = self.receiver.try_iter().collect();
+        for (key, _) in &responses {
+            self.pending.remove(key);
+        }
+        responses
+    }
+}
+
+#[allow(dead_code)]
+#[allow(unused_imports)]
+mod tests {
+    use std::{io, net::IpAddr, str::FromStr};
+
+    use trust_dns_proto::{
+        op::{Message, Query, ResponseCode},
+        rr::{Name, RData, RecordType},
+    };
+
+    use crate::dns::fallback::{fallback_response, SystemResolver};
+
+    struct MockResolver(Vec<IpAddr>);
+
+    impl SystemResolver for MockResolver {
+        fn lookup(&self, name: &str) -> io::Result<Vec<IpAddr>> {
+            assert_eq!(name, "example.com");
+            Ok(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn test_fallback_response() {
+        let mut request = Message::new();
+        request.set_id(7);
+        request.add_query(Query::query(
+            Name::from_str("example.com.").unwrap(),
+            RecordType::A,
+        ));
+        let resolver = MockResolver(vec![
+            "1.2.3.4".parse().unwrap(),
+            "2001:db8::1".parse().unwrap(),
+        ]);
+        let response = fallback_response(&request, &resolver);
+        let response = Message::from_vec(&response.to_vec().unwrap()).unwrap();
+        assert_eq!(response.id(), 7);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.queries(), request.queries());
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::A("1.2.3.4".parse().unwrap()))
+        );
+    }
+}
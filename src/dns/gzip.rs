@@ -0,0 +1,100 @@
+use std::io::{self, ErrorKind};
+
+use miniz_oxide::inflate;
+
+/// First bytes of gzip data, see RFC 1952
+pub const MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// Compression method of deflate, the only one defined
+const DEFLATE: u8 = 8;
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+/// Length of the fixed header
+const HEADER_SIZE: usize = 10;
+/// Length of the CRC32 and size trailer
+const TRAILER_SIZE: usize = 8;
+
+/// Decompress a single member gzip file, checking its CRC32 and size trailer.
+pub fn decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid =
+        |reason: &str| io::Error::new(ErrorKind::InvalidData, format!("invalid gzip:{}", reason));
+    if data.len() < HEADER_SIZE + TRAILER_SIZE || data[..2] != MAGIC || data[2] != DEFLATE {
+        return Err(invalid("bad header"));
+    }
+    let flags = data[3];
+    let mut offset = HEADER_SIZE;
+    if flags & FEXTRA != 0 {
+        let length = data
+            .get(offset..offset + 2)
+            .ok_or_else(|| invalid("truncated extra field"))?;
+        offset += 2 + u16::from_le_bytes([length[0], length[1]]) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = data
+                .get(offset..)
+                .and_then(|rest| rest.iter().position(|byte| *byte == 0))
+                .ok_or_else(|| invalid("unterminated name"))?;
+            offset += end + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        offset += 2;
+    }
+    let (body, trailer) = data
+        .get(offset..)
+        .filter(|rest| rest.len() >= TRAILER_SIZE)
+        .map(|rest| rest.split_at(rest.len() - TRAILER_SIZE))
+        .ok_or_else(|| invalid("truncated"))?;
+    let output = inflate::decompress_to_vec(body)
+        .map_err(|status| invalid(&format!("inflate failed:{:?}", status)))?;
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if crc != crc32(&output) || size != output.len() as u32 {
+        return Err(invalid("checksum mismatch"));
+    }
+    Ok(output)
+}
+
+/// CRC32 of gzip trailers, computed bitwise as lists are decompressed once per load.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use miniz_oxide::deflate;
+
+    use crate::dns::gzip::{crc32, decompress, MAGIC};
+
+    #[test]
+    fn test_decompress() {
+        let content = b"blocked.org\nexample.net\n";
+        // FNAME set, with the original file name
+        let mut data = vec![MAGIC[0], MAGIC[1], 8, 0x08, 0, 0, 0, 0, 0, 3];
+        data.extend_from_slice(b"list.txt\0");
+        data.extend_from_slice(&deflate::compress_to_vec(content, 6));
+        data.extend_from_slice(&crc32(content).to_le_bytes());
+        data.extend_from_slice(&(content.len() as u32).to_le_bytes());
+        assert_eq!(decompress(&data).unwrap(), content);
+
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        let last = data.len() - 5;
+        data[last] ^= 1;
+        assert!(decompress(&data).is_err());
+        assert!(decompress(&data[..12]).is_err());
+        assert!(decompress(content).is_err());
+    }
+}
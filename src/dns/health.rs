@@ -0,0 +1,146 @@
+use std::{
+    fmt::{Display, Formatter},
+    time::{Duration, Instant},
+};
+
+/// Consecutive failed queries making an upstream reported as down
+const DOWN_FAILURES: u32 = 3;
+/// Max age of the last upstream answer to count as a recent success
+const SUCCESS_WINDOW: Duration = Duration::from_secs(60);
+
+/// Answer history of an upstream DNS server
+#[derive(Debug, Default)]
+pub struct UpstreamHealth {
+    failures: u32,
+    /// Consecutive errors sending or receiving on the upstream sockets
+    socket_errors: u32,
+    last_response: Option<Instant>,
+}
+
+impl UpstreamHealth {
+    pub fn on_response(&mut self, now: Instant) {
+        self.failures = 0;
+        self.socket_errors = 0;
+        self.last_response = Some(now);
+    }
+
+    /// Record a send or receive error of the sockets, returns the consecutive errors so far.
+    pub fn on_socket_error(&mut self) -> u32 {
+        self.socket_errors = self.socket_errors.saturating_add(1);
+        self.socket_errors
+    }
+
+    pub fn clear_socket_errors(&mut self) {
+        self.socket_errors = 0;
+    }
+
+    /// Record a query which could not be sent or timed out.
+    pub fn on_failure(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.failures < DOWN_FAILURES
+    }
+
+    /// Whether the last query failed, no answer came since.
+    pub fn is_failing(&self) -> bool {
+        self.failures != 0
+    }
+}
+
+/// Liveness of the DNS server, for watchdogs restarting the process on persistent failure
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthStatus {
+    /// Every listener is bound and the server is set up
+    pub listening: bool,
+    pub trusted_up: bool,
+    pub poisoned_up: bool,
+    /// The route thread is alive, false if it exited and routing stopped
+    pub routing: bool,
+    /// Any upstream answered in the last minute, false on an idle server too
+    pub recent_success: bool,
+}
+
+impl HealthStatus {
+    pub fn new(
+        listening: bool,
+        routing: bool,
+        trusted: &UpstreamHealth,
+        poisoned: &UpstreamHealth,
+        now: Instant,
+    ) -> Self {
+        let recent_success = [trusted, poisoned].iter().any(|health| {
+            health
+                .last_response
+                .is_some_and(|time| time + SUCCESS_WINDOW > now)
+        });
+        Self {
+            listening,
+            trusted_up: trusted.is_up(),
+            poisoned_up: poisoned.is_up(),
+            routing,
+            recent_success,
+        }
+    }
+
+    /// Whether DNS works, an idle server with no recent answer is still healthy.
+    pub fn is_healthy(&self) -> bool {
+        self.listening && self.trusted_up && self.poisoned_up && self.routing
+    }
+}
+
+impl Display for HealthStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_healthy() {
+            return write!(f, "ok");
+        }
+        let mut problems = vec![];
+        if !self.listening {
+            problems.push("listener not bound");
+        }
+        if !self.trusted_up {
+            problems.push("trusted dns down");
+        }
+        if !self.poisoned_up {
+            problems.push("poisoned dns down");
+        }
+        if !self.routing {
+            problems.push("route thread exited");
+        }
+        write!(f, "down:{}", problems.join(","))
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::dns::health::{HealthStatus, UpstreamHealth};
+
+    #[test]
+    fn test_upstream_health() {
+        let now = Instant::now();
+        let mut trusted = UpstreamHealth::default();
+        let poisoned = UpstreamHealth::default();
+        trusted.on_response(now);
+        let status = HealthStatus::new(true, true, &trusted, &poisoned, now);
+        assert!(status.is_healthy() && status.recent_success);
+        assert_eq!(status.to_string(), "ok");
+
+        for _ in 0..3 {
+            trusted.on_failure();
+        }
+        let status = HealthStatus::new(
+            true,
+            true,
+            &trusted,
+            &poisoned,
+            now + Duration::from_secs(61),
+        );
+        assert!(!status.is_healthy() && !status.recent_success);
+        assert_eq!(status.to_string(), "down:trusted dns down");
+        trusted.on_response(now);
+        assert!(trusted.is_up());
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    thread,
+    time::Duration,
+};
+
+use cfg_if::cfg_if;
+
+/// Time a relayed query waits for the DNS listener to answer
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Relay DNS messages framed with a 2 byte length like DNS over TCP from `stream` to the
+/// UDP listener `server`, so IPC clients go through the same dispatch as UDP ones.
+pub fn relay(mut stream: impl Read + Write, mut server: SocketAddr) -> io::Result<()> {
+    if server.ip().is_unspecified() {
+        server.set_ip(Ipv4Addr::LOCALHOST.into());
+    }
+    let socket = UdpSocket::bind(SocketAddr::new(server.ip(), 0))?;
+    socket.set_read_timeout(Some(RELAY_TIMEOUT))?;
+    socket.connect(server)?;
+    // answers from stream upstreams may exceed a UDP packet, take any datagram
+    let mut buffer = vec![0; u16::MAX as usize];
+    loop {
+        let mut length = [0u8; 2];
+        match stream.read_exact(&mut length) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+            result => result?,
+        }
+        let mut query = vec![0; u16::from_be_bytes(length) as usize];
+        stream.read_exact(&mut query)?;
+        if query.len() < 2 {
+            continue;
+        }
+        socket.send(&query)?;
+        // skip late answers to queries which timed out earlier
+        let length = loop {
+            match socket.recv(&mut buffer) {
+                Ok(length) if length >= 2 && buffer[..2] == query[..2] => break Some(length),
+                Ok(_) => continue,
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    break None
+                }
+                Err(err) => return Err(err),
+            }
+        };
+        match length {
+            Some(length) => {
+                stream.write_all(&(length as u16).to_be_bytes())?;
+                stream.write_all(&buffer[..length])?;
+                stream.flush()?;
+            }
+            None => log::warn!("ipc query to {} timeout", server),
+        }
+    }
+}
+
+fn spawn_relay(stream: impl Read + Write + Send + 'static, server: SocketAddr) {
+    thread::spawn(move || {
+        if let Err(err) = relay(stream, server) {
+            log::error!("ipc relay failed:{}", err);
+        }
+    });
+}
+
+cfg_if! {
+    if #[cfg(unix)] {
+        /// Accept IPC clients on the unix socket `path`, relaying their queries to `server`.
+        // only run_with serves IPC so far, which is windows only
+        #[allow(dead_code)]
+        pub fn serve(path: &str, server: SocketAddr) -> io::Result<()> {
+            use std::os::unix::net::UnixListener;
+
+            // a socket file left by a previous run makes bind fail
+            let _ = std::fs::remove_file(path);
+            let listener = UnixListener::bind(path)?;
+            log::warn!("dns ipc listening on {}", path);
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => spawn_relay(stream, server),
+                    Err(err) => log::error!("accept ipc client failed:{}", err),
+                }
+            }
+            Ok(())
+        }
+    } else if #[cfg(windows)] {
+        /// Accept IPC clients on the named pipe `path` like \\.\pipe\trojan-dns, relaying
+        /// their queries to `server`.
+        pub fn serve(path: &str, server: SocketAddr) -> io::Result<()> {
+            use std::{fs::File, os::windows::io::FromRawHandle, ptr::null_mut};
+
+            use widestring::U16CString;
+            use winapi::{
+                shared::winerror::ERROR_PIPE_CONNECTED,
+                um::{
+                    handleapi::INVALID_HANDLE_VALUE,
+                    namedpipeapi::{ConnectNamedPipe, CreateNamedPipeW},
+                    winbase::{
+                        PIPE_ACCESS_DUPLEX, PIPE_READMODE_BYTE, PIPE_TYPE_BYTE,
+                        PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+                    },
+                },
+            };
+
+            use crate::proto::MAX_PACKET_SIZE;
+
+            let name = U16CString::from_str(path)
+                .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+            log::warn!("dns ipc listening on {}", path);
+            loop {
+                let size = MAX_PACKET_SIZE as u32 + 2;
+                let handle = unsafe {
+                    CreateNamedPipeW(
+                        name.as_ptr(),
+                        PIPE_ACCESS_DUPLEX,
+                        PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT,
+                        PIPE_UNLIMITED_INSTANCES,
+                        size,
+                        size,
+                        0,
+                        null_mut(),
+                    )
+                };
+                if handle == INVALID_HANDLE_VALUE {
+                    return Err(io::Error::last_os_error());
+                }
+                // the pipe owns the handle from now on, it's closed when dropped
+                let pipe = unsafe { File::from_raw_handle(handle as _) };
+                if unsafe { ConnectNamedPipe(handle, null_mut()) } == 0 {
+                    let err = io::Error::last_os_error();
+                    if err.raw_os_error() != Some(ERROR_PIPE_CONNECTED as i32) {
+                        log::error!("accept ipc client failed:{}", err);
+                        continue;
+                    }
+                }
+                spawn_relay(pipe, server);
+            }
+        }
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::UdpSocket,
+        str::FromStr,
+        thread,
+    };
+
+    use trust_dns_proto::{
+        op::{Message, Query},
+        rr::{Name, RecordType},
+    };
+
+    use crate::dns::{ipc::relay, local};
+
+    #[cfg(unix)]
+    #[test]
+    fn test_relay() {
+        use std::os::unix::net::UnixStream;
+
+        // a listener answering like the DNS server would
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 512];
+            let (length, from) = server.recv_from(&mut buffer).unwrap();
+            let request = Message::from_vec(&buffer[..length]).unwrap();
+            let response = local::any_response(&request, 3600);
+            server.send_to(&response.to_vec().unwrap(), from).unwrap();
+        });
+        let (mut client, stream) = UnixStream::pair().unwrap();
+        thread::spawn(move || relay(stream, server_addr).unwrap());
+
+        let mut query = Message::new();
+        query.set_id(42);
+        query.add_query(Query::query(
+            Name::from_str("example.com.").unwrap(),
+            RecordType::ANY,
+        ));
+        let data = query.to_vec().unwrap();
+        client
+            .write_all(&(data.len() as u16).to_be_bytes())
+            .unwrap();
+        client.write_all(&data).unwrap();
+        let mut length = [0u8; 2];
+        client.read_exact(&mut length).unwrap();
+        let mut data = vec![0; u16::from_be_bytes(length) as usize];
+        client.read_exact(&mut data).unwrap();
+        let response = Message::from_vec(&data).unwrap();
+        assert_eq!(response.id(), 42);
+        assert_eq!(response.answers()[0].record_type(), RecordType::HINFO);
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// Tokens of one client, refilled at the configured rate up to one second of queries
+struct TokenBucket {
+    tokens: f64,
+    update_time: Instant,
+}
+
+/// Token bucket rate limiter of queries per client address
+pub struct RateLimiter {
+    /// Queries per second a client may send, 0 for unlimited
+    qps: u32,
+    buckets: HashMap<IpAddr, TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(qps: u32) -> Self {
+        Self {
+            qps,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Take a token for a query from `client`, returns false if it exceeds its rate.
+    pub fn allow(&mut self, client: IpAddr, now: Instant) -> bool {
+        if self.qps == 0 {
+            return true;
+        }
+        let qps = self.qps as f64;
+        let bucket = self.buckets.entry(client).or_insert(TokenBucket {
+            tokens: qps,
+            update_time: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.update_time);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * qps).min(qps);
+        bucket.update_time = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Forget clients whose buckets refilled, they'd start full anyway.
+    pub fn prune(&mut self, now: Instant) {
+        self.buckets.retain(|_, bucket| {
+            now.saturating_duration_since(bucket.update_time) < Duration::from_secs(1)
+        });
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::dns::limit::RateLimiter;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(2);
+        let client = "192.168.1.10".parse().unwrap();
+        let other = "192.168.1.11".parse().unwrap();
+        let now = Instant::now();
+        assert!(limiter.allow(client, now));
+        assert!(limiter.allow(client, now));
+        assert!(!limiter.allow(client, now));
+        assert!(limiter.allow(other, now));
+        assert!(limiter.allow(client, now + Duration::from_millis(500)));
+        assert!(!limiter.allow(client, now + Duration::from_millis(500)));
+
+        limiter.prune(now + Duration::from_secs(2));
+        assert!(limiter.buckets.is_empty());
+        assert!(RateLimiter::new(0).allow(client, now));
+    }
+}
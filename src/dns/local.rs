@@ -0,0 +1,328 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use trust_dns_proto::{
+    op::{Header, Message, MessageType, ResponseCode},
+    rr::{
+        rdata::{HINFO, SOA, TXT},
+        DNSClass, Name, RData, Record, RecordType,
+    },
+    serialize::binary::{BinDecodable, BinDecoder},
+};
+
+use crate::types::{Result, TrojanError};
+
+/// Domains resolved through multicast DNS on the local link, see RFC 6762
+const MDNS_DOMAINS: [&str; 6] = [
+    ".local.",
+    ".254.169.in-addr.arpa.",
+    ".8.e.f.ip6.arpa.",
+    ".9.e.f.ip6.arpa.",
+    ".a.e.f.ip6.arpa.",
+    ".b.e.f.ip6.arpa.",
+];
+/// CHAOS names asking for the server identity, see RFC 4892
+const CHAOS_NAMES: [&str; 4] = [
+    "version.bind.",
+    "version.server.",
+    "id.server.",
+    "hostname.bind.",
+];
+
+/// Build a response to `request` carrying its question but no record.
+pub fn empty_response(request: &Message, code: ResponseCode) -> Message {
+    let mut message = Message::new();
+    message.set_id(request.id());
+    message.set_message_type(MessageType::Response);
+    message.set_op_code(request.op_code());
+    message.set_recursion_desired(request.recursion_desired());
+    message.set_recursion_available(true);
+    message.set_response_code(code);
+    message.add_queries(request.queries().to_vec());
+    message
+}
+
+/// Build a FORMERR response to the malformed query `data` from its header, so the client fails
+/// fast instead of retrying. None if even the header is malformed or it's no query.
+pub fn formerr_response(data: &[u8]) -> Option<Message> {
+    let header = Header::read(&mut BinDecoder::new(data)).ok()?;
+    if header.message_type() != MessageType::Query {
+        return None;
+    }
+    let mut message = Message::new();
+    message.set_id(header.id());
+    message.set_message_type(MessageType::Response);
+    message.set_op_code(header.op_code());
+    message.set_recursion_desired(header.recursion_desired());
+    message.set_recursion_available(true);
+    message.set_response_code(ResponseCode::FormErr);
+    Some(message)
+}
+
+/// Build an authoritative response to `request` for data owned by this server.
+pub fn local_response(request: &Message, answers: Vec<Record>) -> Message {
+    let mut message = empty_response(request, ResponseCode::NoError);
+    message.set_authoritative(true);
+    message.set_recursion_available(false);
+    message.add_answers(answers);
+    message
+}
+
+/// Minimal answer for ANY queries, see RFC 8482. Synthesized answers carry `ttl`, negative
+/// ones use it as the caching time too.
+pub fn any_response(request: &Message, ttl: u32) -> Message {
+    let record = Record::from_rdata(
+        request.queries()[0].name().clone(),
+        ttl,
+        RData::HINFO(HINFO::new("RFC8482".into(), "".into())),
+    );
+    local_response(request, vec![record])
+}
+
+/// Answer a sinkholed `request` with the block page address, other types get no record.
+pub fn sinkhole_response(request: &Message, ipv4: Ipv4Addr, ipv6: Ipv6Addr, ttl: u32) -> Message {
+    let query = &request.queries()[0];
+    let data = match query.query_type() {
+        RecordType::A => RData::A(ipv4),
+        RecordType::AAAA => RData::AAAA(ipv6),
+        _ => return local_response(request, vec![]),
+    };
+    let record = Record::from_rdata(query.name().clone(), ttl, data);
+    local_response(request, vec![record])
+}
+
+/// Parse local names like gateway.trojan, resolving to `default`, or gateway.trojan=10.8.0.1.
+pub fn parse_local_names(names: &[String], default: IpAddr) -> Result<HashMap<String, IpAddr>> {
+    let mut result = HashMap::new();
+    for item in names {
+        let (name, ip) = match item.split_once('=') {
+            Some((name, ip)) => (
+                name,
+                ip.trim().parse().map_err(|_| {
+                    TrojanError::InvalidConfig(format!("invalid local name:{}", item))
+                })?,
+            ),
+            None => (item.as_str(), default),
+        };
+        let name = format!("{}.", name.trim().trim_end_matches('.').to_lowercase());
+        result.insert(name, ip);
+    }
+    Ok(result)
+}
+
+/// Answer `request` for a local name with `ip`, queries of the other family get no record.
+pub fn local_name_response(request: &Message, ip: IpAddr, ttl: u32) -> Message {
+    let query = &request.queries()[0];
+    let data = match (query.query_type(), ip) {
+        (RecordType::A, IpAddr::V4(ip)) => RData::A(ip),
+        (RecordType::AAAA, IpAddr::V6(ip)) => RData::AAAA(ip),
+        _ => return local_response(request, vec![]),
+    };
+    let record = Record::from_rdata(query.name().clone(), ttl, data);
+    local_response(request, vec![record])
+}
+
+/// Answer `request` with no record but a SOA in authority, so clients cache the NODATA.
+pub fn nodata_response(request: &Message, ttl: u32) -> Message {
+    let name = request.queries()[0].name().clone();
+    let soa = SOA::new(
+        Name::from_ascii("trojan.dns.").unwrap(),
+        Name::from_ascii("hostmaster.trojan.dns.").unwrap(),
+        1,
+        ttl as i32,
+        ttl as i32,
+        ttl as i32,
+        ttl,
+    );
+    let mut message = local_response(request, vec![]);
+    message.add_name_server(Record::from_rdata(name, ttl, RData::SOA(soa)));
+    message
+}
+
+/// Answer names reserved by RFC 6761 locally, returns None for names resolved upstream.
+/// localhost names resolve to loopback, invalid and test names don't exist, example names
+/// are not special to caching servers and still go upstream.
+pub fn special_use_response(request: &Message, ttl: u32) -> Option<Message> {
+    let query = &request.queries()[0];
+    let name = query.name().to_lowercase().to_utf8();
+    let tld = name
+        .trim_end_matches('.')
+        .rsplit('.')
+        .next()
+        .unwrap_or_default();
+    match tld {
+        "localhost" => {
+            let data = match query.query_type() {
+                RecordType::A => RData::A(Ipv4Addr::LOCALHOST),
+                RecordType::AAAA => RData::AAAA(Ipv6Addr::LOCALHOST),
+                _ => return Some(nodata_response(request, ttl)),
+            };
+            let record = Record::from_rdata(query.name().clone(), ttl, data);
+            Some(local_response(request, vec![record]))
+        }
+        "invalid" | "test" => {
+            let mut response = nodata_response(request, ttl);
+            response.set_response_code(ResponseCode::NXDomain);
+            Some(response)
+        }
+        _ => None,
+    }
+}
+
+/// Answer names which only make sense on the local link, so they never leak upstream: mDNS
+/// names of RFC 6762 get NXDOMAIN, single-label names get NODATA as they may be real TLDs.
+/// Single-label types TLDs really have are still resolved.
+pub fn link_local_response(request: &Message, ttl: u32) -> Option<Message> {
+    let query = &request.queries()[0];
+    let name = query.name().to_lowercase().to_utf8();
+    let mdns = MDNS_DOMAINS
+        .iter()
+        .any(|domain| name == domain[1..] || name.ends_with(domain));
+    if mdns {
+        let mut response = nodata_response(request, ttl);
+        response.set_response_code(ResponseCode::NXDomain);
+        return Some(response);
+    }
+    let single_label = query.name().num_labels() == 1
+        && !matches!(
+            query.query_type(),
+            RecordType::NS | RecordType::SOA | RecordType::DS | RecordType::DNSKEY
+        );
+    single_label.then(|| nodata_response(request, ttl))
+}
+
+/// Whether `request` asks for the server identity in CHAOS class
+pub fn is_chaos_identity(request: &Message) -> bool {
+    let query = &request.queries()[0];
+    let name = query.name().to_lowercase().to_utf8();
+    query.query_class() == DNSClass::CH && CHAOS_NAMES.contains(&name.as_str())
+}
+
+/// Answer a CHAOS identity query with `text`, refused if `text` is empty.
+pub fn chaos_response(request: &Message, text: &str) -> Message {
+    let query = &request.queries()[0];
+    if text.is_empty() || query.query_type() != RecordType::TXT {
+        return empty_response(request, ResponseCode::Refused);
+    }
+    let mut record = Record::from_rdata(
+        query.name().clone(),
+        0,
+        RData::TXT(TXT::new(vec![text.to_owned()])),
+    );
+    record.set_dns_class(DNSClass::CH);
+    local_response(request, vec![record])
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr},
+        str::FromStr,
+    };
+
+    use trust_dns_proto::{
+        op::{Message, Query, ResponseCode},
+        rr::{DNSClass, Name, RData, RecordType},
+    };
+
+    use crate::dns::local::{
+        any_response, chaos_response, is_chaos_identity, link_local_response, sinkhole_response,
+        special_use_response,
+    };
+
+    #[test]
+    fn test_any_response() {
+        let mut request = Message::new();
+        request.add_query(Query::query(
+            Name::from_str("example.com.").unwrap(),
+            RecordType::ANY,
+        ));
+        let response = any_response(&request, 3600);
+        assert!(response.authoritative());
+        assert_eq!(response.answers().len(), 1);
+        match response.answers()[0].data() {
+            Some(RData::HINFO(hinfo)) => assert_eq!(hinfo.cpu(), b"RFC8482"),
+            data => panic!("unexpected answer:{:?}", data),
+        }
+    }
+
+    #[test]
+    fn test_sinkhole_response() {
+        let block_ip = Ipv4Addr::new(192, 168, 1, 2);
+        let mut request = Message::new();
+        request.add_query(Query::query(
+            Name::from_str("ads.example.com.").unwrap(),
+            RecordType::A,
+        ));
+        let response = sinkhole_response(&request, block_ip, Ipv6Addr::UNSPECIFIED, 300);
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(response.answers()[0].data(), Some(&RData::A(block_ip)));
+    }
+
+    #[test]
+    fn test_chaos_response() {
+        let mut query = Query::query(Name::from_str("version.bind.").unwrap(), RecordType::TXT);
+        query.set_query_class(DNSClass::CH);
+        let mut request = Message::new();
+        request.add_query(query);
+        assert!(is_chaos_identity(&request));
+
+        let response = chaos_response(&request, "trojan-dns");
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(response.answers()[0].dns_class(), DNSClass::CH);
+        match response.answers()[0].data() {
+            Some(RData::TXT(txt)) => assert_eq!(&*txt.txt_data()[0], b"trojan-dns"),
+            data => panic!("unexpected answer:{:?}", data),
+        }
+        let response = chaos_response(&request, "");
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+    }
+
+    #[test]
+    fn test_special_use_response() {
+        let request = |name: &str, query_type| {
+            let mut request = Message::new();
+            request.add_query(Query::query(Name::from_str(name).unwrap(), query_type));
+            request
+        };
+        let response = special_use_response(&request("localhost.", RecordType::A), 300).unwrap();
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::A(Ipv4Addr::LOCALHOST))
+        );
+        let response =
+            special_use_response(&request("app.LOCALHOST.", RecordType::AAAA), 300).unwrap();
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::AAAA(Ipv6Addr::LOCALHOST))
+        );
+        let response = special_use_response(&request("foo.invalid.", RecordType::A), 300).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert!(special_use_response(&request("www.example.", RecordType::A), 300).is_none());
+        assert!(special_use_response(&request("localhost.com.", RecordType::A), 300).is_none());
+    }
+
+    #[test]
+    fn test_link_local_response() {
+        let request = |name: &str, query_type| {
+            let mut request = Message::new();
+            request.add_query(Query::query(Name::from_str(name).unwrap(), query_type));
+            request
+        };
+        for name in ["printer.local.", "local.", "4.3.254.169.in-addr.arpa."] {
+            let response = link_local_response(&request(name, RecordType::A), 300).unwrap();
+            assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        }
+        // single-label names may be real TLDs, which exist without having addresses
+        for name in ["printer.", "com."] {
+            let response = link_local_response(&request(name, RecordType::A), 300).unwrap();
+            assert_eq!(response.response_code(), ResponseCode::NoError);
+            assert!(response.answers().is_empty());
+        }
+        assert!(link_local_response(&request("com.", RecordType::DS), 300).is_none());
+        assert!(link_local_response(&request("example.com.", RecordType::A), 300).is_none());
+        assert!(link_local_response(&request("local.example.com.", RecordType::A), 300).is_none());
+    }
+}
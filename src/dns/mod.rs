@@ -1,27 +1,58 @@
-use std::{thread, time::Duration};
+#[cfg(windows)]
+use std::{
+    net::SocketAddr,
+    sync::mpsc::Receiver,
+    thread,
+    time::{Duration, Instant},
+};
 
+#[cfg(windows)]
 use mio::{Events, Poll};
+#[cfg(windows)]
 use winapi::{
     shared::minwindef::{BOOL, DWORD, FALSE, TRUE},
     um::{consoleapi::SetConsoleCtrlHandler, wincon},
 };
 
-use server::DnsServer;
+#[cfg(windows)]
+pub use fakeip::FakeIpMap;
+#[cfg(windows)]
+pub use observer::DnsObserver;
+#[cfg(windows)]
+use server::DnsServerBuilder;
 
-use crate::{dns::adapter::get_adapter_index, OPTIONS, types::Result};
+#[cfg(windows)]
 pub use crate::dns::adapter::{get_adapter_ip, get_main_adapter_gwif, set_dns_server};
+#[cfg(windows)]
+use crate::{
+    dns::adapter::get_adapter_index, types::Result, wintun::route_add_with_metric, OPTIONS,
+};
 
+#[cfg(windows)]
 mod adapter;
+mod clock;
+mod dns64;
 mod domain;
+mod edns;
+mod fakeip;
+mod fallback;
+mod gzip;
+mod health;
+mod ipc;
+mod limit;
+mod local;
+mod observer;
+mod policy;
+mod route;
+mod schedule;
 mod server;
+mod srv;
+mod stream;
+mod svcb;
+mod token;
+mod upstream;
 
-/// Token for trusted DNS server
-const DNS_TRUSTED: usize = 2;
-/// Token for poisoned DNS server
-const DNS_POISONED: usize = 3;
-/// Token for local DNS server
-const DNS_LOCAL: usize = 4;
-
+#[cfg(windows)]
 extern "system" fn console_callback(ctrl_type: DWORD) -> BOOL {
     log::warn!("console_callback called:{}", ctrl_type);
     match ctrl_type {
@@ -37,7 +68,20 @@ extern "system" fn console_callback(ctrl_type: DWORD) -> BOOL {
     FALSE
 }
 
+#[cfg(windows)]
 pub fn run() -> Result<()> {
+    run_with(None, None, None)
+}
+
+/// Run the DNS server, reporting routing decisions to `observer`.
+/// Domains received from `promotions` are added to the block list and saved to its file.
+/// Trusted and poisoned addresses received from `upstreams` replace the upstream servers.
+#[cfg(windows)]
+pub fn run_with(
+    observer: Option<Box<dyn DnsObserver>>,
+    promotions: Option<Receiver<String>>,
+    upstreams: Option<Receiver<(SocketAddr, SocketAddr)>>,
+) -> Result<()> {
     unsafe {
         if FALSE == SetConsoleCtrlHandler(Some(console_callback), TRUE) {
             log::warn!("register console ctrl handle failed");
@@ -50,20 +94,60 @@ pub fn run() -> Result<()> {
         thread::sleep(Duration::new(1, 0));
     }
     let index = get_adapter_index(OPTIONS.dns_args().tun_name.as_str()).unwrap();
+    let if_index = OPTIONS.dns_args().route_if_index.unwrap_or(index);
+    let metric = OPTIONS.dns_args().route_metric;
+    let (route_sender, route_failures) = route::spawn(
+        move |ip| route_add_with_metric(ip.into(), !0, 0, if_index, metric),
+        OPTIONS.dns_args().route_shared_warn,
+        OPTIONS.dns_args().route_dump_file.clone(),
+        Duration::from_millis(OPTIONS.dns_args().route_debounce_ms),
+    );
 
     let mut poll = Poll::new()?;
     let mut events = Events::with_capacity(1024);
-    let mut dns_server = DnsServer::new(index);
-    dns_server.setup(&poll);
+    let args = OPTIONS.dns_args();
+    let mut dns_server = DnsServerBuilder::new(args, route_sender)
+        .cache_time(args.dns_cache_time)
+        .ttl_bounds(args.dns_min_ttl, args.dns_max_ttl)
+        .upstreams(&args.trusted_dns, &args.poisoned_dns)
+        .upstream_ports(args.upstream_ports)
+        .observer(observer)
+        .promotions(promotions)
+        .route_failures(route_failures)
+        .build()?;
+    dns_server.setup(&poll)?;
+    dns_server.warm_routes();
     if !set_dns_server(dns_server.name_server()) {
         log::warn!("set dns server failed");
     }
+    if let Some(path) = OPTIONS.dns_args().dns_ipc_path.clone() {
+        let server = dns_server.listen_addr();
+        thread::spawn(move || {
+            if let Err(err) = ipc::serve(path.as_str(), server) {
+                log::error!("dns ipc on {} failed:{}", path, err);
+            }
+        });
+    }
 
     log::warn!("dns server is ready");
+    let check_duration = Duration::new(1, 0);
+    let mut last_check_time = Instant::now();
     loop {
-        poll.poll(&mut events, None)?;
+        poll.poll(&mut events, Some(check_duration))?;
         for event in &events {
             dns_server.ready(event, &poll);
         }
+        let now = Instant::now();
+        if now - last_check_time >= check_duration {
+            dns_server.handle_promotions();
+            dns_server.handle_reloads();
+            if let Some(receiver) = &upstreams {
+                for (trusted, poisoned) in receiver.try_iter() {
+                    dns_server.reconfigure_upstreams(trusted, poisoned);
+                }
+            }
+            dns_server.check_timeout(dns_server.now());
+            last_check_time = now;
+        }
     }
 }
@@ -0,0 +1,58 @@
+use std::net::IpAddr;
+
+use crate::dns::route::Upstream;
+
+/// Hook for embedders watching routing decisions of the DNS server.
+/// It's called inside the DNS loop, so it should be cheap or offload the work.
+pub trait DnsObserver: Send {
+    /// `name` is going to be resolved by `decision`
+    fn on_query(&mut self, name: &str, decision: Upstream);
+    /// route for `ip` resolved from `domain` is requested
+    fn on_route(&mut self, ip: IpAddr, domain: &str);
+}
+
+#[allow(dead_code)]
+#[allow(unused_imports)]
+mod tests {
+    use std::{
+        net::IpAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::dns::{observer::DnsObserver, route::Upstream};
+
+    #[derive(Default, Clone)]
+    struct RecordingObserver {
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl DnsObserver for RecordingObserver {
+        fn on_query(&mut self, name: &str, decision: Upstream) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("query {} {}", name, decision));
+        }
+
+        fn on_route(&mut self, ip: IpAddr, domain: &str) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("route {} {}", ip, domain));
+        }
+    }
+
+    #[test]
+    fn test_recording_observer() {
+        let recorder = RecordingObserver::default();
+        let mut observer: Option<Box<dyn DnsObserver>> = Some(Box::new(recorder.clone()));
+        if let Some(observer) = observer.as_mut() {
+            observer.on_query("example.com.", Upstream::Trusted);
+            observer.on_route("1.2.3.4".parse().unwrap(), "example.com.");
+        }
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            vec!["query example.com. trusted", "route 1.2.3.4 example.com."]
+        );
+    }
+}
@@ -0,0 +1,133 @@
+use std::{collections::HashMap, net::IpAddr, str::FromStr};
+
+use crate::{
+    dns::domain::DomainMap,
+    types::{Result, TrojanError},
+};
+
+/// Split policy applied to queries from one client
+pub enum ClientPolicy {
+    /// Resolve every domain through poisoned DNS
+    Direct,
+    /// Resolve every domain through trusted DNS
+    Tunnel,
+    /// Resolve domains in the list through trusted DNS
+    Domains(DomainMap),
+}
+
+impl ClientPolicy {
+    pub fn parse(policy: &str) -> Result<Self> {
+        match policy {
+            "direct" => Ok(ClientPolicy::Direct),
+            "tunnel" => Ok(ClientPolicy::Tunnel),
+            file => Ok(ClientPolicy::Domains(DomainMap::with_file(file)?)),
+        }
+    }
+
+    pub fn is_blocked(&self, name: &str) -> bool {
+        match self {
+            ClientPolicy::Direct => false,
+            ClientPolicy::Tunnel => true,
+            ClientPolicy::Domains(domains) => domains.contains(name),
+        }
+    }
+}
+
+/// Parse client policies in format like 192.168.1.10=direct
+pub fn parse_client_policies(policies: &[String]) -> Result<HashMap<IpAddr, ClientPolicy>> {
+    let mut result = HashMap::new();
+    for item in policies {
+        let (ip, policy) = item
+            .split_once('=')
+            .ok_or_else(|| TrojanError::InvalidConfig(format!("invalid client policy:{}", item)))?;
+        result.insert(ip.trim().parse()?, ClientPolicy::parse(policy.trim())?);
+    }
+    Ok(result)
+}
+
+/// Client network like 192.168.1.0/24, a bare address is a single host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientNet {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl ClientNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // clients of dual-stack listeners show up as IPv4-mapped addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for ClientNet {
+    type Err = TrojanError;
+
+    fn from_str(net: &str) -> Result<Self> {
+        let invalid = || TrojanError::InvalidConfig(format!("invalid client network:{}", net));
+        let (addr, prefix) = match net.trim().split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (net.trim(), None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use std::net::IpAddr;
+
+    use crate::dns::policy::{parse_client_policies, ClientNet};
+
+    #[test]
+    fn test_client_policy() {
+        let policies =
+            parse_client_policies(&["192.168.1.10=direct".into(), "192.168.1.11=tunnel".into()])
+                .unwrap();
+        let kids: IpAddr = "192.168.1.10".parse().unwrap();
+        let mine: IpAddr = "192.168.1.11".parse().unwrap();
+        assert!(!policies[&kids].is_blocked("www.google.com."));
+        assert!(policies[&mine].is_blocked("www.google.com."));
+        assert!(parse_client_policies(&["192.168.1.10".into()]).is_err());
+    }
+
+    #[test]
+    fn test_client_net() {
+        let net: ClientNet = "192.168.1.0/24".parse().unwrap();
+        assert!(net.contains("192.168.1.200".parse().unwrap()));
+        assert!(net.contains("::ffff:192.168.1.7".parse().unwrap()));
+        assert!(!net.contains("192.168.2.1".parse().unwrap()));
+        assert!(!net.contains("fd00::1".parse().unwrap()));
+        let net: ClientNet = "fd00::/8".parse().unwrap();
+        assert!(net.contains("fd12::1".parse().unwrap()));
+        let net: ClientNet = "10.0.0.1".parse().unwrap();
+        assert!(net.contains("10.0.0.1".parse().unwrap()));
+        assert!(!net.contains("10.0.0.2".parse().unwrap()));
+        let net: ClientNet = "0.0.0.0/0".parse().unwrap();
+        assert!(net.contains("8.8.8.8".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<ClientNet>().is_err());
+        assert!("lan".parse::<ClientNet>().is_err());
+    }
+}
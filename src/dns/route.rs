@@ -0,0 +1,588 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{Display, Formatter},
+    fs::{self, File},
+    io::{BufWriter, Write},
+    net::{IpAddr, Ipv4Addr},
+    sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SendError, SyncSender, TrySendError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use itertools::Itertools;
+
+use crate::{config::RoutePolicy, types::Result};
+
+/// Max pending route events before the DNS loop starts dropping them
+const ROUTE_CHANNEL_SIZE: usize = 1024;
+/// Min interval between dumps of the routed mapping
+const DUMP_INTERVAL: Duration = Duration::from_secs(5);
+/// Max time the block policy waits for room in the route channel
+const BLOCK_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Upstream DNS server which resolved an address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Upstream {
+    Trusted,
+    Poisoned,
+    /// Upstream some query types are sent to, with its index among them
+    Typed(usize),
+}
+
+impl Display for Upstream {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Upstream::Trusted => write!(f, "trusted"),
+            Upstream::Poisoned => write!(f, "poisoned"),
+            Upstream::Typed(index) => write!(f, "typed{}", index),
+        }
+    }
+}
+
+/// Request sent from the DNS loop to the route thread
+#[derive(Debug, Clone)]
+pub struct RouteEvent {
+    pub ip: IpAddr,
+    pub domain: String,
+    /// Last target of the CNAME chain `domain` resolved through, like the CDN host contacted
+    pub cname: Option<String>,
+    pub source: Upstream,
+}
+
+/// Route the route thread failed to install, reported back to the DNS loop
+#[derive(Debug, Clone)]
+pub struct RouteFailure {
+    pub event: RouteEvent,
+    /// Why the install failed, like access denied or the interface being gone
+    pub error: String,
+}
+
+/// Whether `ip` is a public address, private and reserved ranges are reached without the
+/// tunnel.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // shared address space for carrier grade NAT, see RFC 6598
+                || octets[0] == 100 && octets[1] & 0xc0 == 64
+                || octets[0] >= 240)
+        }
+        IpAddr::V6(ip) => {
+            let segment = ip.segments()[0];
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // unique local and link local unicast
+                || segment & 0xfe00 == 0xfc00
+                || segment & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+/// Sending side of the route channel, applying a policy once it's full
+pub struct RouteSender {
+    sender: SyncSender<RouteEvent>,
+    policy: RoutePolicy,
+    /// Events waiting for room in the channel, for the drop oldest policy
+    backlog: VecDeque<RouteEvent>,
+}
+
+impl RouteSender {
+    pub fn new(sender: SyncSender<RouteEvent>, policy: RoutePolicy) -> Self {
+        Self {
+            sender,
+            policy,
+            backlog: VecDeque::new(),
+        }
+    }
+
+    /// Queue `event` for the route thread, returns the older event dropped to make room.
+    /// `Full` carries `event` itself if it's dropped.
+    pub fn send(
+        &mut self,
+        event: RouteEvent,
+    ) -> std::result::Result<Option<RouteEvent>, TrySendError<RouteEvent>> {
+        self.flush()?;
+        match self.policy {
+            RoutePolicy::DropNewest => self.sender.try_send(event).map(|_| None),
+            RoutePolicy::Block => {
+                let deadline = Instant::now() + BLOCK_TIMEOUT;
+                let mut event = event;
+                loop {
+                    match self.sender.try_send(event) {
+                        Err(TrySendError::Full(full)) if Instant::now() < deadline => {
+                            event = full;
+                            thread::sleep(Duration::from_millis(1));
+                        }
+                        result => return result.map(|_| None),
+                    }
+                }
+            }
+            RoutePolicy::DropOldest => {
+                // nothing overtakes the backlog, so events keep their order
+                if !self.backlog.is_empty() {
+                    self.backlog.push_back(event);
+                } else if let Err(err) = self.sender.try_send(event) {
+                    match err {
+                        TrySendError::Full(event) => self.backlog.push_back(event),
+                        err => return Err(err),
+                    }
+                }
+                if self.backlog.len() > ROUTE_CHANNEL_SIZE {
+                    return Ok(self.backlog.pop_front());
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    /// Queue `event` waiting for room however long it takes, for startup before queries
+    /// are served.
+    pub fn send_wait(
+        &mut self,
+        event: RouteEvent,
+    ) -> std::result::Result<(), SendError<RouteEvent>> {
+        self.sender.send(event)
+    }
+
+    /// Move backlogged events into the channel as long as there is room.
+    pub fn flush(&mut self) -> std::result::Result<(), TrySendError<RouteEvent>> {
+        while let Some(event) = self.backlog.pop_front() {
+            match self.sender.try_send(event) {
+                Ok(_) => {}
+                Err(TrySendError::Full(event)) => {
+                    self.backlog.push_front(event);
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Route events held back per domain, so addresses resolved close together are routed in
+/// one batch
+struct RouteDebouncer {
+    window: Duration,
+    /// Deadline and events of each domain waiting for its window to end
+    pending: HashMap<String, (Instant, Vec<RouteEvent>)>,
+}
+
+impl RouteDebouncer {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Hold `event` until the window opened by the first event of its domain ends.
+    fn push(&mut self, event: RouteEvent, now: Instant) {
+        let deadline = now + self.window;
+        let (_, events) = self
+            .pending
+            .entry(event.domain.clone())
+            .or_insert_with(|| (deadline, vec![]));
+        if events.iter().all(|pending| pending.ip != event.ip) {
+            events.push(event);
+        }
+    }
+
+    /// Take the events of domains whose window ended by `now`.
+    fn take_due(&mut self, now: Instant) -> Vec<RouteEvent> {
+        let due: Vec<String> = self
+            .pending
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(domain, _)| domain.clone())
+            .collect();
+        due.iter()
+            .filter_map(|domain| self.pending.remove(domain))
+            .flat_map(|(_, events)| events)
+            .collect()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|(deadline, _)| *deadline).min()
+    }
+}
+
+/// Routed addresses and the domains resolved to them, along with their CNAME targets
+struct RouteRegistry {
+    routes: HashMap<IpAddr, HashMap<String, Option<String>>>,
+    /// Domain count making an address reported as shared, 0 for disable
+    shared_threshold: usize,
+}
+
+impl RouteRegistry {
+    fn new(shared_threshold: usize) -> Self {
+        Self {
+            routes: HashMap::new(),
+            shared_threshold,
+        }
+    }
+
+    /// Record `domain` resolved to `ip` through `cname`, returns true if `ip` is not routed yet.
+    fn add(&mut self, ip: IpAddr, domain: &str, cname: Option<&str>) -> bool {
+        let domains = self.routes.entry(ip).or_default();
+        let new = domains.is_empty();
+        if domains
+            .insert(domain.to_owned(), cname.map(Into::into))
+            .is_none()
+            && self.is_shared(&ip)
+        {
+            log::warn!(
+                "{} is routed for {} domains like {}, it may be a shared CDN address",
+                ip,
+                self.shared_threshold,
+                self.routes[&ip].keys().take(3).join(",")
+            );
+        }
+        new
+    }
+
+    /// Whether `ip` just got its shared threshold domain count
+    fn is_shared(&self, ip: &IpAddr) -> bool {
+        self.shared_threshold != 0
+            && self
+                .routes
+                .get(ip)
+                .is_some_and(|domains| domains.len() == self.shared_threshold)
+    }
+
+    fn remove(&mut self, ip: &IpAddr) {
+        self.routes.remove(ip);
+    }
+
+    /// Write `ip,domain` lines of every routed address, sorted for stable diffs. The CNAME
+    /// target follows as a third column if the domain resolved through one.
+    fn dump(&self, mut writer: impl Write) -> Result<()> {
+        for ip in self.routes.keys().sorted() {
+            for (domain, cname) in self.routes[ip].iter().sorted() {
+                match cname {
+                    Some(cname) => writeln!(writer, "{},{},{}", ip, domain, cname)?,
+                    None => writeln!(writer, "{},{}", ip, domain)?,
+                }
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Replace `file` with the current mapping, readers never see a partial dump.
+    fn dump_file(&self, file: &str) -> Result<()> {
+        let temp = format!("{}.tmp", file);
+        self.dump(BufWriter::new(File::create(&temp)?))?;
+        fs::rename(temp, file)?;
+        Ok(())
+    }
+}
+
+/// Read the routed mapping a previous run saved to `file`, invalid lines are skipped.
+pub fn load_dump(file: &str) -> Result<Vec<RouteEvent>> {
+    let mut events = vec![];
+    for line in fs::read_to_string(file)?.lines() {
+        let mut fields = line.splitn(3, ',');
+        let parsed = fields
+            .next()
+            .and_then(|ip| ip.parse().ok())
+            .zip(fields.next());
+        match parsed {
+            Some((ip, domain)) => events.push(RouteEvent {
+                ip,
+                domain: domain.to_string(),
+                cname: fields.next().map(Into::into),
+                source: Upstream::Trusted,
+            }),
+            None => log::warn!("invalid route line {} in {}", line, file),
+        }
+    }
+    Ok(events)
+}
+
+/// Spawn the thread adding routes through `install`, returns the sender feeding it and the
+/// receiver of failed installs.
+/// The routed mapping is saved to `dump_file` as CSV when it changes, addresses of a domain
+/// are routed together `debounce` after the first one, zero for right away.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub fn spawn<F>(
+    install: F,
+    shared_threshold: usize,
+    dump_file: Option<String>,
+    debounce: Duration,
+) -> (SyncSender<RouteEvent>, Receiver<RouteFailure>)
+where
+    F: FnMut(Ipv4Addr) -> Result<()> + Send + 'static,
+{
+    let (sender, receiver) = sync_channel(ROUTE_CHANNEL_SIZE);
+    let (failure_sender, failures) = sync_channel(ROUTE_CHANNEL_SIZE);
+    let registry = RouteRegistry::new(shared_threshold);
+    let debouncer = RouteDebouncer::new(debounce);
+    thread::spawn(move || {
+        route_loop(
+            receiver,
+            install,
+            registry,
+            debouncer,
+            dump_file,
+            failure_sender,
+        )
+    });
+    (sender, failures)
+}
+
+fn route_loop(
+    receiver: Receiver<RouteEvent>,
+    mut install: impl FnMut(Ipv4Addr) -> Result<()>,
+    mut registry: RouteRegistry,
+    mut debouncer: RouteDebouncer,
+    dump_file: Option<String>,
+    failures: SyncSender<RouteFailure>,
+) {
+    let mut dirty = false;
+    let mut last_dump = Instant::now();
+    loop {
+        let timeout = debouncer.next_deadline().map_or(DUMP_INTERVAL, |deadline| {
+            deadline.saturating_duration_since(Instant::now())
+        });
+        match receiver.recv_timeout(timeout) {
+            Ok(event) if debouncer.window.is_zero() => {
+                dirty |= handle_event(event, &mut install, &mut registry, &failures)
+            }
+            Ok(event) => debouncer.push(event, Instant::now()),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        let batch = debouncer.take_due(Instant::now());
+        if !batch.is_empty() {
+            log::info!("route batch of {} addresses", batch.len());
+        }
+        for event in batch {
+            dirty |= handle_event(event, &mut install, &mut registry, &failures);
+        }
+        if let Some(file) = &dump_file {
+            if dirty && last_dump.elapsed() >= DUMP_INTERVAL {
+                if let Err(err) = registry.dump_file(file) {
+                    log::error!("dump routes to {} failed:{:?}", file, err);
+                }
+                dirty = false;
+                last_dump = Instant::now();
+            }
+        }
+    }
+    log::warn!("route channel closed, route thread exit");
+}
+
+/// Route the address of `event`, returns true if the routed mapping changed.
+/// Failures are reported to `failures`, dropped when the DNS loop falls behind.
+fn handle_event(
+    event: RouteEvent,
+    install: &mut impl FnMut(Ipv4Addr) -> Result<()>,
+    registry: &mut RouteRegistry,
+    failures: &SyncSender<RouteFailure>,
+) -> bool {
+    let ip = match event.ip {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return false,
+    };
+    if !registry.add(event.ip, event.domain.as_str(), event.cname.as_deref()) {
+        return true;
+    }
+    match install(ip) {
+        Ok(_) => log::info!(
+            "route {} added for {}{} resolved by {}",
+            ip,
+            event.domain,
+            event
+                .cname
+                .as_ref()
+                .map_or_else(String::new, |cname| format!(" via {}", cname)),
+            event.source
+        ),
+        Err(err) => {
+            log::error!(
+                "route {} for {} resolved by {} failed:{:?}",
+                ip,
+                event.domain,
+                event.source,
+                err
+            );
+            registry.remove(&event.ip);
+            let _ = failures.try_send(RouteFailure {
+                event,
+                error: format!("{:?}", err),
+            });
+        }
+    }
+    true
+}
+
+#[allow(dead_code)]
+#[allow(unused_imports)]
+mod tests {
+    use std::{
+        env, fs,
+        net::IpAddr,
+        sync::mpsc::{sync_channel, TrySendError},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        config::RoutePolicy,
+        dns::route::{
+            is_public, load_dump, RouteDebouncer, RouteEvent, RouteRegistry, RouteSender, Upstream,
+            ROUTE_CHANNEL_SIZE,
+        },
+    };
+
+    fn event(index: usize) -> RouteEvent {
+        RouteEvent {
+            ip: "1.2.3.4".parse().unwrap(),
+            domain: format!("{}.example.com.", index),
+            cname: None,
+            source: Upstream::Trusted,
+        }
+    }
+
+    #[test]
+    fn test_route_policy() {
+        let (sender, receiver) = sync_channel(1);
+        let mut sender = RouteSender::new(sender, RoutePolicy::DropNewest);
+        assert!(matches!(sender.send(event(0)), Ok(None)));
+        match sender.send(event(1)) {
+            Err(TrySendError::Full(event)) => assert_eq!(event.domain, "1.example.com."),
+            result => panic!("unexpected result:{:?}", result),
+        }
+        receiver.try_recv().unwrap();
+
+        let (sender, receiver) = sync_channel(1);
+        let mut sender = RouteSender::new(sender, RoutePolicy::DropOldest);
+        for index in 0..=ROUTE_CHANNEL_SIZE {
+            assert!(matches!(sender.send(event(index)), Ok(None)));
+        }
+        let dropped = sender.send(event(ROUTE_CHANNEL_SIZE + 1)).unwrap().unwrap();
+        assert_eq!(dropped.domain, "1.example.com.");
+        assert_eq!(receiver.try_recv().unwrap().domain, "0.example.com.");
+        sender.flush().unwrap();
+        assert_eq!(receiver.try_recv().unwrap().domain, "2.example.com.");
+
+        let (sender, receiver) = sync_channel(1);
+        let mut sender = RouteSender::new(sender, RoutePolicy::Block);
+        sender.send(event(0)).unwrap();
+        let consumer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            receiver.recv().unwrap();
+            receiver
+        });
+        assert!(matches!(sender.send(event(1)), Ok(None)));
+        let _receiver = consumer.join().unwrap();
+        assert!(matches!(sender.send(event(2)), Err(TrySendError::Full(_))));
+    }
+
+    #[test]
+    fn test_route_debounce() {
+        let now = Instant::now();
+        let window = Duration::from_millis(100);
+        let mut debouncer = RouteDebouncer::new(window);
+        let event = |domain: &str, ip: &str| RouteEvent {
+            ip: ip.parse().unwrap(),
+            domain: domain.into(),
+            cname: None,
+            source: Upstream::Trusted,
+        };
+        debouncer.push(event("cdn.example.com.", "1.2.3.4"), now);
+        debouncer.push(
+            event("cdn.example.com.", "1.2.3.5"),
+            now + Duration::from_millis(30),
+        );
+        debouncer.push(
+            event("cdn.example.com.", "1.2.3.4"),
+            now + Duration::from_millis(60),
+        );
+        debouncer.push(
+            event("other.example.com.", "1.2.3.6"),
+            now + Duration::from_millis(60),
+        );
+        assert!(debouncer
+            .take_due(now + Duration::from_millis(90))
+            .is_empty());
+        assert_eq!(debouncer.next_deadline(), Some(now + window));
+
+        let batch = debouncer.take_due(now + window);
+        let ips: Vec<String> = batch.iter().map(|event| event.ip.to_string()).collect();
+        assert_eq!(ips, vec!["1.2.3.4", "1.2.3.5"]);
+        assert_eq!(
+            debouncer.next_deadline(),
+            Some(now + Duration::from_millis(160))
+        );
+    }
+
+    #[test]
+    fn test_is_public() {
+        for ip in ["1.2.3.4", "8.8.8.8", "2001:4860:4860::8888"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "192.168.1.5",
+            "10.0.0.1",
+            "172.16.0.1",
+            "127.0.0.1",
+            "169.254.1.1",
+            "100.64.0.1",
+            "0.0.0.0",
+            "224.0.0.1",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_shared_address() {
+        let mut registry = RouteRegistry::new(3);
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        assert!(registry.add(ip, "a.example.com.", None));
+        assert!(!registry.add(ip, "b.example.com.", None));
+        assert!(!registry.add(ip, "b.example.com.", None));
+        assert!(!registry.is_shared(&ip));
+        registry.add(ip, "c.example.com.", None);
+        assert!(registry.is_shared(&ip));
+        registry.add(ip, "d.example.com.", None);
+        assert!(!registry.is_shared(&ip));
+    }
+
+    #[test]
+    fn test_dump() {
+        let mut registry = RouteRegistry::new(0);
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        registry.add(ip, "b.example.com.", Some("b.cdn.net."));
+        registry.add(ip, "a.example.com.", None);
+        registry.add("1.1.1.1".parse().unwrap(), "one.one.", None);
+        let file = env::temp_dir().join("trojan_test_route_dump.csv");
+        let file = file.to_str().unwrap();
+        registry.dump_file(file).unwrap();
+        let content = fs::read_to_string(file).unwrap();
+        let events = load_dump(file).unwrap();
+        fs::remove_file(file).unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0].ip.to_string(), "1.1.1.1");
+        assert_eq!(events[0].domain, "one.one.");
+        assert_eq!(events[0].cname, None);
+        assert_eq!(events[2].cname.as_deref(), Some("b.cdn.net."));
+        assert_eq!(
+            content,
+            "1.1.1.1,one.one.\n1.2.3.4,a.example.com.\n1.2.3.4,b.example.com.,b.cdn.net.\n"
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{
+    dns::domain::DomainMap,
+    types::{Result, TrojanError},
+};
+
+/// Local time window like 09:00-18:00, a window ending before it starts wraps midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeWindow {
+    /// Minute of the day the window starts at, inclusive
+    start: u32,
+    /// Minute of the day the window ends at, exclusive
+    end: u32,
+}
+
+impl TimeWindow {
+    pub fn parse(window: &str) -> Result<Self> {
+        let invalid = || TrojanError::InvalidConfig(format!("invalid time window:{}", window));
+        let minute = |time: &str| {
+            let (hour, minute) = time.trim().split_once(':')?;
+            let (hour, minute): (u32, u32) = (hour.parse().ok()?, minute.parse().ok()?);
+            (hour < 24 && minute < 60).then_some(hour * 60 + minute)
+        };
+        let (start, end) = window.split_once('-').ok_or_else(invalid)?;
+        Ok(Self {
+            start: minute(start).ok_or_else(invalid)?,
+            end: minute(end).ok_or_else(invalid)?,
+        })
+    }
+
+    /// Whether the minute of the day `minute` is inside the window.
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Domains resolved through trusted DNS during their time window only
+#[derive(Default)]
+pub struct ScheduledDomains {
+    windows: Vec<(TimeWindow, DomainMap)>,
+}
+
+impl ScheduledDomains {
+    /// Parse schedules in format like work.example.com=09:00-18:00
+    pub fn parse(schedules: &[String]) -> Result<Self> {
+        let mut result = Self::default();
+        for item in schedules {
+            let (domain, window) = item.split_once('=').ok_or_else(|| {
+                TrojanError::InvalidConfig(format!("invalid domain schedule:{}", item))
+            })?;
+            let window = TimeWindow::parse(window)?;
+            match result
+                .windows
+                .iter_mut()
+                .find(|(other, _)| *other == window)
+            {
+                Some((_, domains)) => domains.add_domain(domain.trim()),
+                None => {
+                    let mut domains = DomainMap::new();
+                    domains.add_domain(domain.trim());
+                    result.windows.push((window, domains));
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    /// Whether `name` is scheduled for the minute of the day `minute`.
+    pub fn contains(&self, name: &str, minute: u32) -> bool {
+        self.windows
+            .iter()
+            .any(|(window, domains)| window.contains(minute) && domains.contains(name))
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use crate::dns::schedule::{ScheduledDomains, TimeWindow};
+
+    #[test]
+    fn test_scheduled_domains() {
+        let domains = ScheduledDomains::parse(&[
+            "work.example.com=09:00-18:00".into(),
+            "night.example.com=22:30-06:00".into(),
+        ])
+        .unwrap();
+        let nine = 9 * 60;
+        let six_pm = 18 * 60;
+        assert!(domains.contains("www.work.example.com.", nine));
+        assert!(domains.contains("work.example.com.", six_pm - 1));
+        assert!(!domains.contains("work.example.com.", six_pm));
+        assert!(!domains.contains("work.example.com.", nine - 1));
+        assert!(domains.contains("night.example.com.", 23 * 60));
+        assert!(domains.contains("night.example.com.", 60));
+        assert!(!domains.contains("night.example.com.", 12 * 60));
+        assert!(!domains.contains("other.example.com.", nine));
+
+        assert!(TimeWindow::parse("24:00-06:00").is_err());
+        assert!(TimeWindow::parse("09:00").is_err());
+        assert!(ScheduledDomains::parse(&["work.example.com".into()]).is_err());
+    }
+}
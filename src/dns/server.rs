@@ -1,230 +1,818 @@
 use std::{
-    collections::{HashMap, HashSet},
-    fs::File,
-    io::{BufRead, BufReader, ErrorKind},
-    net::{IpAddr, SocketAddr},
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    fmt::{Display, Formatter},
+    hash::{BuildHasher, Hasher},
+    io,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Receiver, Sender, SyncSender, TrySendError},
+        Arc, RwLock,
+    },
+    thread::{self, JoinHandle},
     time::{Duration, Instant},
 };
 
 use itertools::Itertools;
-use mio::{event::Event, net::UdpSocket, Interest, Poll, Token};
+use mio::{event::Event, net::UdpSocket, Interest, Poll, Registry};
+use socket2::{Domain, Protocol, Socket, Type};
 use trust_dns_proto::{
     op::{Message, MessageType, Query, ResponseCode},
-    rr::{DNSClass, Name, RData, Record, RecordType},
+    rr::{rdata::SRV, DNSClass, Name, RData, Record, RecordType},
     serialize::binary::BinDecodable,
 };
 
 use crate::{
-    dns::{domain::DomainMap, DNS_LOCAL, DNS_POISONED, DNS_TRUSTED},
+    config::{DnsArgs, MultiQueryMode, ResolutionStage},
+    dns::{
+        clock::{Clock, SystemClock},
+        dns64,
+        domain::{append_domain, DomainMap},
+        edns,
+        fakeip::FakeIpPool,
+        fallback::FallbackResolver,
+        health::{HealthStatus, UpstreamHealth},
+        limit::RateLimiter,
+        local,
+        observer::DnsObserver,
+        policy::{parse_client_policies, ClientNet, ClientPolicy},
+        route::{self, RouteEvent, RouteFailure, RouteSender, Upstream},
+        schedule::ScheduledDomains,
+        srv, svcb,
+        token::{DnsSocket, TokenAllocator},
+        upstream::{self, DnsUpstream, UpstreamUrl},
+    },
     proto::MAX_PACKET_SIZE,
-    wintun::route_add_with_if,
-    OPTIONS,
+    types::{Result, TrojanError},
 };
 
+/// Time before an unanswered upstream query may be sent again
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Consecutive socket errors after which an upstream's sockets are recreated, they go stale
+/// when the tunnel interface bounces
+const SOCKET_ERROR_LIMIT: u32 = 5;
+/// Interval cache metrics are logged at
+const METRICS_INTERVAL: Duration = Duration::from_secs(60);
+/// Min interval between dumps of the fake addresses, short as the tunnel looks new ones up
+/// as soon as their clients connect
+const FAKE_IP_DUMP_INTERVAL: Duration = Duration::from_secs(1);
+/// Query for this name from a loopback client flushes the cache
+const FLUSH_CACHE_NAME: &str = "flush.trojan.dns.";
+/// Query for this name from a loopback client reloads the block list files
+const RELOAD_NAME: &str = "reload.trojan.dns.";
+/// CHAOS TXT query for this name from a loopback client returns the health status
+const HEALTH_NAME: &str = "health.trojan.dns.";
+/// Connectivity probes of common systems, captive portals must see them before the tunnel is up
+const CAPTIVE_PORTAL_DOMAINS: [&str; 9] = [
+    "connectivitycheck.gstatic.com.",
+    "connectivitycheck.android.com.",
+    "clients3.google.com.",
+    "www.msftconnecttest.com.",
+    "www.msftncsi.com.",
+    "captive.apple.com.",
+    "www.apple.com.",
+    "detectportal.firefox.com.",
+    "nmcheck.gnome.org.",
+];
+/// TTL of expired answers served because upstream failed
+const STALE_TTL: u32 = 30;
+/// Hot entries are refreshed after this percentage of their TTL passed
+const REFRESH_PERCENT: u32 = 80;
+
 pub struct DnsServer {
-    listener: UdpSocket,
-    trusted: UdpSocket,
-    poisoned: UdpSocket,
+    args: Arc<DnsArgs>,
+    listeners: Vec<UdpSocket>,
+    trusted: Box<dyn DnsUpstream>,
+    poisoned: Box<dyn DnsUpstream>,
+    /// Upstreams query types are mapped to, indexed by `Upstream::Typed`
+    typed: Vec<Box<dyn DnsUpstream>>,
+    typed_health: Vec<UpstreamHealth>,
+    /// Index of the typed upstream each mapped query type goes to
+    query_types: HashMap<RecordType, usize>,
     buffer: Vec<u8>,
-    arp_data: Vec<u8>,
-    blocked_domains: DomainMap,
+    /// Swapped whole by the reload thread, so lookups see either the old or the new list
+    blocked_domains: Arc<RwLock<DomainMap>>,
+    /// Domains always resolved through poisoned DNS, by default taking precedence over block
+    /// lists
+    direct_domains: DomainMap,
+    /// Exact names of connectivity probes, always resolved through poisoned DNS
+    captive_portal_domains: HashSet<String>,
+    /// Domains resolved through trusted DNS during their time window
+    scheduled_domains: ScheduledDomains,
+    /// Domains answered with the block page address
+    sinkhole_domains: DomainMap,
+    /// Domains whose HTTPS/SVCB answers have ECH stripped
+    strip_ech_domains: DomainMap,
+    /// Domains always forwarded upstream, their answers are never cached
+    no_cache_domains: DomainMap,
+    client_policies: HashMap<IpAddr, ClientPolicy>,
+    /// Networks clients may query from besides loopback, empty for every client
+    allowed_clients: Vec<ClientNet>,
+    /// Local services answered to SRV queries
+    srv_map: HashMap<String, SRV>,
+    /// Names answered with the interface address or a configured one
+    local_names: HashMap<String, IpAddr>,
     store: HashMap<String, QueryResult>,
+    /// Multi-query messages split into single queries, waiting for their answers
+    batches: Vec<Batch>,
+    /// Sampled queries sent to both upstreams, keyed by question
+    comparisons: HashMap<String, Comparison>,
     ptr_name: String,
-    trusted_addr: SocketAddr,
-    poisoned_addr: SocketAddr,
-    route_added: HashSet<u32>,
-    adapter_index: u32,
+    /// CLIENT-SUBNET option data attached to trusted queries
+    client_subnet: Option<Vec<u8>>,
+    /// NAT64 prefix AAAA answers are synthesized with, None without DNS64
+    nat64_prefix: Option<Ipv6Addr>,
+    /// Fake addresses blocked domains are answered with, None unless tunneling by domain
+    fake_ips: Option<FakeIpPool>,
+    /// Fake addresses were handed out since they were last dumped
+    fake_ips_dirty: bool,
+    fake_ips_dump_time: Option<Instant>,
+    route_sender: RouteSender,
+    /// Route events dropped because the route thread fell behind
+    dropped_routes: u64,
+    /// Private addresses resolved for routed domains and not routed, often a sign of
+    /// split-horizon DNS answering with LAN addresses
+    private_routes_skipped: u64,
+    /// Routes the route thread failed to install, reported through `route_failures`
+    failed_routes: u64,
+    route_failures: Option<Receiver<RouteFailure>>,
+    /// The route thread exited, resolved addresses are not routed anymore
+    route_disconnected: bool,
+    observer: Option<Box<dyn DnsObserver>>,
+    /// Domains reported failing through poisoned DNS, to be added to the block list
+    promotions: Option<Receiver<String>>,
+    /// Block lists replaced by a reload, their entries classified by the old list are dropped
+    replaced_lists: Receiver<DomainMap>,
+    replaced_sender: Sender<DomainMap>,
+    /// Set while a reload thread runs, so reloads don't pile up and overwrite each other
+    reloading: Arc<AtomicBool>,
+    fallback: FallbackResolver,
+    tokens: TokenAllocator,
+    trusted_health: UpstreamHealth,
+    poisoned_health: UpstreamHealth,
+    /// Zero length datagrams dropped by listeners
+    empty_datagrams: u64,
+    rate_limiter: RateLimiter,
+    /// Queries dropped because their client exceeded its rate
+    throttled_queries: u64,
+    cache_metrics: CacheMetrics,
+    clock: Box<dyn Clock>,
+    /// When cache metrics were logged last time
+    metrics_time: Instant,
+    /// Registry upstreams are registered with, recreated upstreams register again
+    registry: Option<Registry>,
+    /// Upstreams and block list are ready after `setup`
+    ready: bool,
+}
+
+/// How client queries were answered, for tuning the cache time
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct CacheMetrics {
+    /// Answered from an unexpired cache entry
+    cache_hit: u64,
+    /// Answered from an expired cache entry, which is refreshed
+    cache_expired_refresh: u64,
+    /// Not answered from the cache
+    cache_miss: u64,
+}
+
+impl Display for CacheMetrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cache_hit={} cache_expired_refresh={} cache_miss={}",
+            self.cache_hit, self.cache_expired_refresh, self.cache_miss
+        )
+    }
+}
+
+/// Cached answers of a name, for embedders showing the cache state
+#[allow(dead_code)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheInfo {
+    pub source: Upstream,
+    /// Addresses of the cached A and AAAA answers
+    pub addresses: Vec<IpAddr>,
+    /// Time since the oldest of the answers was cached
+    pub age: Duration,
+    /// None of the answers expired
+    pub fresh: bool,
+}
+
+/// Client waiting for an upstream response
+struct PendingClient {
+    listener: usize,
+    address: SocketAddr,
+    id: u16,
+    /// When the query arrived, late responses are not sent to clients which gave up
+    query_time: Instant,
+    /// Largest response the client accepts over UDP, see `edns::payload_size`
+    max_size: usize,
+}
+
+impl PendingClient {
+    fn is_expired(&self, now: Instant, window: Duration) -> bool {
+        self.query_time + window < now
+    }
+
+    /// Encode the shared upstream `message` with the id this client sent, truncated to the
+    /// size the client accepts.
+    fn response(&self, message: &mut Message) -> Vec<u8> {
+        message.set_id(self.id);
+        truncated_response(message, self.max_size)
+    }
+}
+
+/// Multi-query message split into single queries, answered once every part is
+struct Batch {
+    client: PendingClient,
+    request: Message,
+    /// Cache key of each query along with its answer
+    parts: Vec<(String, Option<Message>)>,
+}
+
+/// Addresses both upstreams answered a sampled query with, logged once both arrive
+struct Comparison {
+    /// Upstream answering the client
+    source: Upstream,
+    trusted: Option<Vec<IpAddr>>,
+    poisoned: Option<Vec<IpAddr>>,
+    query_time: Instant,
 }
 
 struct QueryResult {
-    addresses: Vec<(SocketAddr, u16)>,
+    addresses: Vec<PendingClient>,
     response: Option<Message>,
     expire_time: Instant,
+    update_time: Instant,
+    query_time: Instant,
+    /// Last query forwarded upstream, kept for refreshing
+    query: Vec<u8>,
+    /// Question of the last query, responses must match it
+    question: Option<Query>,
+    source: Upstream,
+    hits: u32,
+    /// The last query is waiting for an upstream answer
+    pending: bool,
+    /// Retransmissions of the last query
+    retries: u32,
+    /// The last query got its answer, later ones are duplicates
+    answered: bool,
+    /// The AAAA answer has no address, an A query resolves the addresses to synthesize
+    synthesizing: bool,
+    /// Key of the AAAA query waiting for this A answer to be synthesized from
+    dns64: Option<String>,
+    /// The system resolver was asked since the last query went upstream
+    fallback: bool,
 }
 
-impl DnsServer {
-    pub fn new(index: u32) -> Self {
-        let default_addr = "0.0.0.0:0".to_owned();
-        let trusted_dns_addr = OPTIONS.dns_args().trusted_dns.clone() + ":53";
-        let poisoned_dns_addr = OPTIONS.dns_args().poisoned_dns.clone() + ":53";
-        let trusted_addr = trusted_dns_addr.as_str().parse().unwrap();
-        let poisoned_addr = poisoned_dns_addr.as_str().parse().unwrap();
+impl QueryResult {
+    fn new(now: Instant, source: Upstream, cache_time: Duration) -> Self {
+        Self {
+            addresses: vec![],
+            response: None,
+            expire_time: now + cache_time,
+            update_time: now,
+            query_time: now,
+            query: vec![],
+            question: None,
+            source,
+            hits: 0,
+            pending: false,
+            retries: 0,
+            answered: false,
+            synthesizing: false,
+            dns64: None,
+            fallback: false,
+        }
+    }
+
+    /// Send the cached answer to waiting clients despite it expired, returns false if
+    /// nothing is cached.
+    fn serve_stale(&mut self, listeners: &[UdpSocket]) -> bool {
+        let mut response = match &self.response {
+            Some(response) => stale_response(response),
+            None => return false,
+        };
+        for client in self.addresses.drain(..) {
+            if let Err(err) = listeners[client.listener]
+                .send_to(client.response(&mut response).as_slice(), client.address)
+            {
+                log::error!("send to {} failed:{}", client.address, err);
+            }
+        }
+        true
+    }
+}
+
+/// Whether a query is sampled at `rate`, std hashers are randomly keyed.
+fn sampled(rate: f64) -> bool {
+    let random = RandomState::new().build_hasher().finish();
+    rate >= 1.0 || (random as f64 / u64::MAX as f64) < rate
+}
+
+/// Log `data` as hex along with a parsed summary if it parses, for debugging bad messages.
+fn log_hex(reason: &str, data: &[u8]) {
+    let summary = match Message::from_vec(data) {
+        Ok(message) => format!("{:?}", message.queries()),
+        Err(err) => format!("parse failed:{}", err),
+    };
+    log::info!(
+        "{}, {} bytes:{}, {}",
+        reason,
+        data.len(),
+        hex::encode(data),
+        summary
+    );
+}
+
+/// Bind a non-blocking listener. SO_REUSEADDR lets a quick restart get the address back on
+/// unix, on windows it would let other processes take over the port, which SO_EXCLUSIVEADDRUSE
+/// forbids instead.
+fn bind_listener(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    #[cfg(windows)]
+    crate::sys::set_exclusive_addr_use(&socket)?;
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into()))
+}
+
+/// Block list from the list files plus inline domains.
+fn load_blocked_domains(args: &DnsArgs) -> Result<DomainMap> {
+    let mut domains = DomainMap::with_files(&args.blocked_domain_list, args.blocked_domain_max)?;
+    for domain in &args.blocked_domains_inline {
+        domains.add_domain(domain);
+    }
+    Ok(domains)
+}
+
+/// Last target of the CNAME chain the question of `response` resolved through, None without
+/// CNAMEs.
+fn cname_target(response: &Message) -> Option<String> {
+    let mut name = response.queries().first()?.name();
+    let mut target = None;
+    // a chain can't be longer than the answers, which ends looping ones
+    for _ in response.answers() {
+        let next = response
+            .answers()
+            .iter()
+            .find_map(|record| match record.data() {
+                Some(RData::CNAME(next)) if record.name() == name => Some(next),
+                _ => None,
+            });
+        match next {
+            Some(next) => {
+                name = next;
+                target = Some(next);
+            }
+            None => break,
+        }
+    }
+    target.map(|name| name.to_lowercase().to_utf8())
+}
+
+/// Copy of `request` asking the same name for `query_type`.
+fn with_query_type(request: &Message, query_type: RecordType) -> Message {
+    let mut query = request.queries()[0].clone();
+    query.set_query_type(query_type);
+    let mut message = request.clone();
+    message.take_queries();
+    message.add_query(query);
+    message
+}
+
+/// Copy of the multi-query `request` asking `query` only.
+fn with_query(request: &Message, query: &Query) -> Message {
+    let mut message = request.clone();
+    message.take_queries();
+    message.add_query(query.clone());
+    message
+}
+
+/// Response to the multi-query `request` merging the answers to each of its queries, the first
+/// error any part got is the response code.
+fn merge_responses(request: &Message, parts: &[&Message]) -> Message {
+    let code = parts
+        .iter()
+        .map(|part| part.response_code())
+        .find(|code| *code != ResponseCode::NoError)
+        .unwrap_or(ResponseCode::NoError);
+    let mut response = local::empty_response(request, code);
+    for part in parts {
+        response.add_answers(part.answers().to_vec());
+        response.add_name_servers(part.name_servers().to_vec());
+    }
+    response
+}
+
+/// Rewrite TTLs of records in every section of `response` with `f`.
+fn map_ttl(response: &mut Message, f: impl Fn(u32) -> u32) {
+    let map = |records: &mut Vec<Record>| {
+        for record in records.iter_mut() {
+            record.set_ttl(f(record.ttl()));
+        }
+    };
+    map(response.answers_mut());
+    map(response.name_servers_mut());
+    map(response.additionals_mut());
+}
+
+/// Copy of a cached `response` with TTLs decreased by `elapsed` seconds.
+/// Authority and additional records are kept along with their TTLs, so negative answers
+/// still carry the SOA and referrals their glue.
+fn age_response(response: &Message, elapsed: u32) -> Message {
+    let mut response = response.clone();
+    map_ttl(&mut response, |ttl| ttl.saturating_sub(elapsed));
+    response
+}
+
+/// Rotate addresses of A/AAAA answers by `shift` places, so repeated cache hits spread
+/// clients over the servers, other records like CNAMEs stay where they are.
+fn rotate_addresses(response: &mut Message, shift: usize) {
+    let is_address =
+        |record: &Record| matches!(record.record_type(), RecordType::A | RecordType::AAAA);
+    let positions: Vec<usize> = response
+        .answers()
+        .iter()
+        .enumerate()
+        .filter(|(_, record)| is_address(record))
+        .map(|(position, _)| position)
+        .collect();
+    if positions.len() < 2 {
+        return;
+    }
+    let mut records: Vec<Record> = positions
+        .iter()
+        .map(|position| response.answers()[*position].clone())
+        .collect();
+    let shift = shift % records.len();
+    records.rotate_left(shift);
+    let answers = response.answers_mut();
+    for (position, record) in positions.into_iter().zip(records) {
+        answers[position] = record;
+    }
+}
+
+/// Cache lifetime of an answer with `ttl`, short-lived records are raised to `min_ttl`,
+/// long-lived records are limited to `max_ttl` so their routes get re-validated, 0 for no
+/// limit. `max_ttl` wins when it's lower than `min_ttl`.
+fn clamp_ttl(ttl: u32, min_ttl: u32, max_ttl: u32) -> u32 {
+    let ttl = ttl.max(min_ttl);
+    if max_ttl == 0 {
+        ttl
+    } else {
+        ttl.min(max_ttl)
+    }
+}
+
+/// Copy of `response` without additional records, only SOA is kept in the authority section
+/// as negative caching needs it, see RFC 2308.
+fn minimal_response(response: &Message) -> Message {
+    let mut message = Message::new();
+    message.set_header(*response.header());
+    message.add_queries(response.queries().to_vec());
+    message.add_answers(response.answers().to_vec());
+    message.add_name_servers(
+        response
+            .name_servers()
+            .iter()
+            .filter(|record| record.record_type() == RecordType::SOA)
+            .cloned(),
+    );
+    if let Some(edns) = response.extensions() {
+        message.set_edns(edns.clone());
+    }
+    message
+}
+
+/// Encoding of `response` fitting in `max_size` bytes. Answers that don't fit are dropped along
+/// with the authority and additional records, and TC is set so the client retries over TCP,
+/// see RFC 2181 9.
+fn truncated_response(response: &Message, max_size: usize) -> Vec<u8> {
+    let data = response.to_vec().unwrap();
+    if data.len() <= max_size {
+        return data;
+    }
+    let mut truncated = response.clone();
+    truncated.set_truncated(true);
+    truncated.take_name_servers();
+    truncated.take_additionals();
+    let answers = truncated.take_answers();
+    let mut data = truncated.to_vec().unwrap();
+    for answer in answers {
+        truncated.add_answer(answer);
+        let longer = truncated.to_vec().unwrap();
+        if longer.len() > max_size {
+            break;
+        }
+        data = longer;
+    }
+    data
+}
+
+/// Copy of an expired `response` served when upstream fails, see RFC 8767.
+fn stale_response(response: &Message) -> Message {
+    let mut response = response.clone();
+    map_ttl(&mut response, |_| STALE_TTL);
+    response
+}
+
+/// Collects settings of a `DnsServer`, starting from the dns arguments.
+pub struct DnsServerBuilder {
+    args: DnsArgs,
+    route_sender: SyncSender<RouteEvent>,
+    observer: Option<Box<dyn DnsObserver>>,
+    promotions: Option<Receiver<String>>,
+    route_failures: Option<Receiver<RouteFailure>>,
+}
 
+// only run_with builds servers outside tests so far, which is windows only
+#[cfg_attr(not(windows), allow(dead_code))]
+impl DnsServerBuilder {
+    pub fn new(args: &DnsArgs, route_sender: SyncSender<RouteEvent>) -> Self {
         Self {
-            trusted_addr,
-            poisoned_addr,
-            listener: UdpSocket::bind(
-                OPTIONS
-                    .dns_args()
-                    .dns_listen_address
-                    .as_str()
-                    .parse()
-                    .unwrap(),
-            )
-            .unwrap(),
-            trusted: UdpSocket::bind(default_addr.as_str().parse().unwrap()).unwrap(),
-            poisoned: UdpSocket::bind(default_addr.as_str().parse().unwrap()).unwrap(),
+            args: args.clone(),
+            route_sender,
+            observer: None,
+            promotions: None,
+            route_failures: None,
+        }
+    }
+
+    /// Seconds answers are cached for, before TTL bounds apply.
+    pub fn cache_time(mut self, seconds: u64) -> Self {
+        self.args.dns_cache_time = seconds;
+        self
+    }
+
+    /// Min and max seconds an answer is cached, 0 for no limit, the max wins over a higher min.
+    pub fn ttl_bounds(mut self, min: u32, max: u32) -> Self {
+        self.args.dns_min_ttl = min;
+        self.args.dns_max_ttl = max;
+        self
+    }
+
+    /// Upstream servers, addresses or URLs like tls://8.8.8.8#dns.google.
+    pub fn upstreams(mut self, trusted: &str, poisoned: &str) -> Self {
+        self.args.trusted_dns = trusted.into();
+        self.args.poisoned_dns = poisoned.into();
+        self
+    }
+
+    /// Source ports each UDP upstream rotates queries through.
+    pub fn upstream_ports(mut self, ports: usize) -> Self {
+        self.args.upstream_ports = ports;
+        self
+    }
+
+    pub fn observer(mut self, observer: Option<Box<dyn DnsObserver>>) -> Self {
+        self.observer = observer;
+        self
+    }
+
+    pub fn promotions(mut self, promotions: Option<Receiver<String>>) -> Self {
+        self.promotions = promotions;
+        self
+    }
+
+    /// Failed installs reported back by the route thread, see `route::spawn`.
+    pub fn route_failures(mut self, route_failures: Receiver<RouteFailure>) -> Self {
+        self.route_failures = Some(route_failures);
+        self
+    }
+
+    /// Create the server, which owns the settings, so rebuilt servers free the old ones.
+    /// Fails with `InvalidConfig` if an upstream or listen address doesn't parse.
+    pub fn build(self) -> Result<DnsServer> {
+        let args = Arc::new(self.args);
+        let mut server = DnsServer::new(args, self.route_sender, self.observer, self.promotions)?;
+        server.route_failures = self.route_failures;
+        Ok(server)
+    }
+}
+
+impl DnsServer {
+    fn new(
+        args: Arc<DnsArgs>,
+        route_sender: SyncSender<RouteEvent>,
+        observer: Option<Box<dyn DnsObserver>>,
+        promotions: Option<Receiver<String>>,
+    ) -> Result<Self> {
+        let trusted: UpstreamUrl = args
+            .downstream_dns
+            .as_ref()
+            .unwrap_or(&args.trusted_dns)
+            .parse()?;
+        let poisoned: UpstreamUrl = args
+            .downstream_dns
+            .as_ref()
+            .unwrap_or(&args.poisoned_dns)
+            .parse()?;
+        let (typed, query_types) = upstream::parse_type_upstreams(&args.query_type_upstream)?;
+        let listeners = args
+            .dns_listen_address
+            .iter()
+            .map(|addr| {
+                let addr = addr.parse().map_err(|_| {
+                    TrojanError::InvalidConfig(format!("invalid dns listen address:{}", addr))
+                })?;
+                Ok(bind_listener(addr)?)
+            })
+            .collect::<Result<_>>()?;
+        let fake_ips = if args.tunnel_by_domain {
+            Some(FakeIpPool::parse(&args.fake_ip_range)?)
+        } else {
+            None
+        };
+        let (replaced_sender, replaced_lists) = channel();
+
+        Ok(Self {
+            args: args.clone(),
+            listeners,
+            trusted: trusted.upstream(args.upstream_ports)?,
+            poisoned: poisoned.upstream(args.upstream_ports)?,
+            typed_health: typed.iter().map(|_| UpstreamHealth::default()).collect(),
+            typed: typed
+                .iter()
+                .map(|url| url.upstream(args.upstream_ports))
+                .collect::<io::Result<_>>()?,
+            query_types,
             buffer: vec![0; MAX_PACKET_SIZE],
-            blocked_domains: DomainMap::new(),
-            arp_data: vec![],
+            blocked_domains: Arc::new(RwLock::new(DomainMap::new())),
+            direct_domains: DomainMap::new(),
+            captive_portal_domains: HashSet::new(),
+            scheduled_domains: ScheduledDomains::default(),
+            sinkhole_domains: DomainMap::new(),
+            strip_ech_domains: DomainMap::new(),
+            no_cache_domains: DomainMap::new(),
+            client_policies: HashMap::new(),
+            allowed_clients: vec![],
+            srv_map: HashMap::new(),
+            local_names: HashMap::new(),
             store: HashMap::new(),
+            batches: vec![],
+            comparisons: HashMap::new(),
             ptr_name: String::new(),
-            route_added: HashSet::new(),
-            adapter_index: index,
-        }
+            client_subnet: None,
+            nat64_prefix: None,
+            fake_ips,
+            fake_ips_dirty: false,
+            fake_ips_dump_time: None,
+            route_sender: RouteSender::new(route_sender, args.route_channel_policy),
+            dropped_routes: 0,
+            private_routes_skipped: 0,
+            failed_routes: 0,
+            route_failures: None,
+            route_disconnected: false,
+            observer,
+            promotions,
+            replaced_lists,
+            replaced_sender,
+            reloading: Arc::new(AtomicBool::new(false)),
+            fallback: FallbackResolver::new(),
+            tokens: TokenAllocator::new(),
+            trusted_health: UpstreamHealth::default(),
+            poisoned_health: UpstreamHealth::default(),
+            empty_datagrams: 0,
+            rate_limiter: RateLimiter::new(args.per_client_qps),
+            throttled_queries: 0,
+            cache_metrics: CacheMetrics::default(),
+            metrics_time: Instant::now(),
+            clock: Box::new(SystemClock),
+            registry: None,
+            ready: false,
+        })
     }
 
     pub fn name_server(&self) -> String {
-        self.listener.local_addr().unwrap().ip().to_string()
+        self.listen_addr().ip().to_string()
     }
 
-    pub fn setup(&mut self, poll: &Poll) {
-        poll.registry()
-            .register(&mut self.trusted, Token(DNS_TRUSTED), Interest::READABLE)
-            .unwrap();
-        poll.registry()
-            .register(&mut self.poisoned, Token(DNS_POISONED), Interest::READABLE)
-            .unwrap();
-        poll.registry()
-            .register(&mut self.listener, Token(DNS_LOCAL), Interest::READABLE)
-            .unwrap();
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
 
-        let mut domain_map = DomainMap::new();
-        let file = File::open(OPTIONS.dns_args().blocked_domain_list.as_str()).unwrap();
-        let reader = BufReader::new(file);
-        reader.lines().for_each(|line| {
-            if let Ok(line) = line {
-                domain_map.add_domain(line.as_str());
-            }
-        });
-        self.blocked_domains = domain_map;
+    /// Address of the first listener, IPC clients are relayed to it
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.listeners[0].local_addr().unwrap()
+    }
 
-        let mut message = Message::new();
-        message.set_message_type(MessageType::Response);
-        message.set_id(1);
-        message.set_recursion_desired(true);
-        message.set_recursion_available(true);
-        message.set_response_code(ResponseCode::NoError);
-        let mut query = Query::new();
-        let address: String = self
-            .listener
-            .local_addr()
-            .unwrap()
-            .ip()
-            .to_string()
-            .split('.')
-            .rev()
-            .join(".");
-        let name = address + ".in-addr.arpa.";
-        self.ptr_name = name.clone();
-        let name = Name::from_str(name.as_str()).unwrap();
-        query.set_name(name.clone());
-        query.set_query_type(RecordType::PTR);
-        query.set_query_class(DNSClass::IN);
-        message.add_query(query);
-        let mut record = Record::new();
-        record.set_name(name);
-        record.set_record_type(RecordType::PTR);
-        record.set_dns_class(DNSClass::IN);
-        record.set_ttl(20567);
-        record.set_data(Some(RData::PTR(Name::from_str("trojan.dns").unwrap())));
-        message.add_answer(record);
-        self.arp_data = message.to_vec().unwrap();
+    /// Register the sockets with `poll` and load the domain lists, fails with
+    /// `InvalidConfig` on a malformed list or setting.
+    pub fn setup(&mut self, poll: &Poll) -> Result<()> {
+        self.check_upstreams();
+        self.registry = poll.registry().try_clone().ok();
+        let typed = (0..self.typed.len()).map(Upstream::Typed);
+        for source in [Upstream::Trusted, Upstream::Poisoned]
+            .iter()
+            .copied()
+            .chain(typed)
+        {
+            let token = self.tokens.allocate(DnsSocket::Upstream(source));
+            self.upstream(source).register(poll.registry(), token)?;
+        }
+        for (index, listener) in self.listeners.iter_mut().enumerate() {
+            let token = self.tokens.allocate(DnsSocket::Listener(index));
+            poll.registry()
+                .register(listener, token, Interest::READABLE)?;
+        }
+
+        *self.blocked_domains.write().unwrap() = load_blocked_domains(&self.args)?;
+        self.scheduled_domains = ScheduledDomains::parse(&self.args.blocked_domain_schedule)?;
+        if self.args.dns64 {
+            self.nat64_prefix = Some(dns64::parse_prefix(&self.args.nat64_prefix)?);
+        }
+        if let Some(file) = &self.args.force_direct_list {
+            self.direct_domains = DomainMap::with_file(file)?;
+        }
+        self.captive_portal_domains = CAPTIVE_PORTAL_DOMAINS
+            .iter()
+            .map(|domain| domain.to_string())
+            .chain(
+                self.args
+                    .captive_portal_domains
+                    .iter()
+                    .map(|domain| format!("{}.", domain.trim_end_matches('.').to_lowercase())),
+            )
+            .collect();
+        if let Some(file) = &self.args.sinkhole_domain_list {
+            self.sinkhole_domains = DomainMap::with_file(file)?;
+        }
+        for domain in &self.args.strip_ech_domains {
+            self.strip_ech_domains.add_domain(domain);
+        }
+        if let Some(file) = &self.args.no_cache_list {
+            self.no_cache_domains = DomainMap::with_file(file)?;
+        }
+        self.client_policies = parse_client_policies(&self.args.client_policy)?;
+        self.allowed_clients = self
+            .args
+            .allowed_clients
+            .iter()
+            .map(|net| net.parse())
+            .collect::<Result<_>>()?;
+        self.srv_map = srv::parse_srv_map(&self.args.srv_map)?;
+        self.local_names =
+            local::parse_local_names(&self.args.local_names, self.listen_addr().ip())?;
+        self.client_subnet = self
+            .args
+            .ecs_subnet
+            .as_deref()
+            .map(edns::client_subnet)
+            .transpose()?;
+
+        let address: String = self.name_server().split('.').rev().join(".");
+        self.ptr_name = address + ".in-addr.arpa.";
+        self.ready = true;
+        Ok(())
     }
 
     pub fn ready(&mut self, event: &Event, poll: &Poll) {
-        match event.token() {
-            Token(DNS_LOCAL) => {
-                self.dispatch_local(poll);
-            }
-            Token(DNS_TRUSTED) => {
-                self.dispatch_trusted(poll);
-            }
-            Token(DNS_POISONED) => {
-                self.dispatch_poisoned(poll);
-            }
-            _ => unreachable!(),
+        match self.tokens.socket(event.token()) {
+            Some(DnsSocket::Upstream(source)) => self.dispatch_server(poll, source),
+            Some(DnsSocket::Listener(index)) => self.dispatch_local(poll, index),
+            None => unreachable!(),
         }
     }
 
-    fn dispatch_local(&mut self, poll: &Poll) {
-        let now = Instant::now();
+    fn dispatch_local(&mut self, poll: &Poll, index: usize) {
+        let now = self.clock.now();
         loop {
-            match self.listener.recv_from(self.buffer.as_mut_slice()) {
+            match self.listeners[index].recv_from(self.buffer.as_mut_slice()) {
+                Ok((0, from)) => {
+                    // not worth an error log, which empty datagram floods could abuse
+                    self.empty_datagrams += 1;
+                    log::debug!("empty datagram received from {}", from);
+                }
                 Ok((length, from)) => {
-                    let data = &self.buffer.as_slice()[..length];
-                    if let Ok(message) = Message::from_bytes(data) {
-                        if message.query_count() == 1 {
-                            let query = &message.queries()[0];
-                            let name = query.name().to_utf8();
-                            if query.query_type() == RecordType::PTR && name == self.ptr_name {
-                                log::debug!("found ptr query");
-                                if let Err(err) =
-                                    self.listener.send_to(self.arp_data.as_slice(), from)
-                                {
-                                    log::error!("send response to {} failed:{}", from, err);
-                                }
-                                continue;
-                            }
-                            let key = Self::get_message_key(&message);
-                            let (renew, respond) = if let Some(QueryResult {
-                                response: Some(response),
-                                expire_time,
-                                ..
-                            }) = self.store.get_mut(&key)
-                            {
-                                log::info!("query:{} found in cache", key);
-                                response.set_id(message.id());
-                                if let Err(err) = self
-                                    .listener
-                                    .send_to(response.to_vec().unwrap().as_slice(), from)
-                                {
-                                    log::error!("send response to {} failed:{}", from, err);
-                                }
-                                (*expire_time <= now, false)
-                            } else {
-                                (true, true)
-                            };
-
-                            if renew {
-                                if self.is_blocked(&name) {
-                                    if let Err(err) = self.trusted.send_to(data, self.trusted_addr)
-                                    {
-                                        log::error!("send to trusted dns failed:{}", err);
-                                        continue;
-                                    }
-                                    log::info!("domain:{} is blocked", name);
-                                } else {
-                                    if let Err(err) =
-                                        self.poisoned.send_to(data, self.poisoned_addr)
-                                    {
-                                        log::error!("send to poisoned dns failed:{}", err);
-                                        continue;
-                                    }
-                                    log::info!("domain:{} is not blocked", name);
-                                }
-                                if respond {
-                                    self.add_request(key, from, message.id());
-                                }
-                            }
-                        } else {
-                            log::error!(
-                                "query count:{} found in message:{:?}",
-                                message.query_count(),
-                                message
-                            );
-                        }
+                    let data = self.buffer[..length].to_vec();
+                    if let Ok(message) = Message::from_bytes(data.as_slice()) {
+                        self.handle_query(index, from, data.as_slice(), message, now);
                     } else {
                         log::error!("invalid request message received from {}", from);
+                        if self.args.dns_debug_hex {
+                            log_hex(&format!("invalid request from {}", from), &data);
+                        }
+                        if self.args.reply_formerr {
+                            if let Some(response) = local::formerr_response(&data) {
+                                self.send_response(index, &response, from);
+                            }
+                        }
                     }
                 }
                 Err(err) if err.kind() == ErrorKind::WouldBlock => break,
                 Err(err) if err.kind() == ErrorKind::ConnectionReset => continue,
                 Err(err) => {
                     log::error!("dns request recv failed:{}, kind:{:?}", err, err.kind());
+                    let token = self.tokens.token(DnsSocket::Listener(index)).unwrap();
                     poll.registry()
-                        .reregister(&mut self.listener, Token(DNS_LOCAL), Interest::READABLE)
+                        .reregister(&mut self.listeners[index], token, Interest::READABLE)
                         .unwrap();
                     break;
                 }
@@ -232,139 +820,3796 @@ impl DnsServer {
         }
     }
 
-    fn get_message_key(message: &Message) -> String {
+    fn handle_query(
+        &mut self,
+        index: usize,
+        from: SocketAddr,
+        data: &[u8],
+        message: Message,
+        now: Instant,
+    ) {
+        if !self.ready {
+            log::warn!("query from {} before dns server is ready", from);
+            let response = local::empty_response(&message, ResponseCode::ServFail);
+            self.send_response(index, &response, from);
+            return;
+        }
+        if !self.is_allowed(from.ip()) {
+            log::warn!("query from {} refused, the client is not allowed", from);
+            let response = local::empty_response(&message, ResponseCode::Refused);
+            self.send_response(index, &response, from);
+            return;
+        }
+        // dropped without an answer, so a flooding client gets nothing to amplify
+        if !self.rate_limiter.allow(from.ip(), now) {
+            self.throttled_queries += 1;
+            log::debug!("query from {} exceeds its rate, dropped", from);
+            return;
+        }
+        if message.query_count() == 0 {
+            log::error!("query without question found in message:{:?}", message);
+            return;
+        }
+        let long_name = message.queries().iter().find_map(|query| {
+            let name = query.name().to_utf8();
+            (name.trim_end_matches('.').len() > self.args.max_name_length).then_some(name)
+        });
+        if let Some(name) = long_name {
+            log::warn!(
+                "query from {} with {} bytes name rejected",
+                from,
+                name.len()
+            );
+            let response = local::empty_response(&message, ResponseCode::FormErr);
+            self.send_response(index, &response, from);
+            return;
+        }
+        if message.query_count() > 1 {
+            match self.args.multi_query_mode {
+                MultiQueryMode::Reject => log::error!(
+                    "query count:{} found in message:{:?}",
+                    message.query_count(),
+                    message
+                ),
+                MultiQueryMode::First => {
+                    let source = self.classify(&message.queries()[0], from.ip());
+                    self.pass_through(index, from, data, &message, source, now);
+                }
+                MultiQueryMode::Split => self.split_query(index, from, &message, now),
+            }
+            return;
+        }
+        if self.is_sniffer() {
+            let name = message.queries()[0].name().to_utf8();
+            // blocked domains go through the trusted socket, so their addresses get routed
+            let source = if self.is_blocked(&name, from.ip()) {
+                Upstream::Trusted
+            } else {
+                Upstream::Poisoned
+            };
+            self.pass_through(index, from, data, &message, source, now);
+            return;
+        }
         let query = &message.queries()[0];
         let name = query.name().to_utf8();
-        name + "|" + query.query_type().to_string().as_str()
+        if self.args.intercept_loopback_ptr
+            && query.query_type() == RecordType::PTR
+            && name == self.ptr_name
+        {
+            log::debug!("found ptr query");
+            let record = Record::from_rdata(
+                query.name().clone(),
+                self.args.local_answer_ttl,
+                RData::PTR(Name::from_str("trojan.dns").unwrap()),
+            );
+            let response = local::local_response(&message, vec![record]);
+            self.send_response(index, &response, from);
+            return;
+        }
+        let stage = self.resolution_stage(&name, from.ip());
+        if stage == Some(ResolutionStage::Hosts) {
+            let ip = &self.local_names[&name.to_lowercase()];
+            log::debug!("answer local name {} with {}", name, ip);
+            let response = local::local_name_response(&message, *ip, self.args.local_answer_ttl);
+            self.send_response(index, &response, from);
+            return;
+        }
+        if self.args.ptr_from_cache && query.query_type() == RecordType::PTR {
+            if let Some(record) = self.cached_ptr(query.name(), now) {
+                log::info!("ptr query {} answered from cache", name);
+                let response = local::local_response(&message, vec![record]);
+                self.send_response(index, &response, from);
+                return;
+            }
+        }
+        if name == RELOAD_NAME && from.ip().is_loopback() {
+            self.reload_blocked_domains();
+            let response = local::local_response(&message, vec![]);
+            self.send_response(index, &response, from);
+            return;
+        }
+        if name == FLUSH_CACHE_NAME && from.ip().is_loopback() {
+            self.flush_cache();
+            let response = local::local_response(&message, vec![]);
+            self.send_response(index, &response, from);
+            return;
+        }
+        if name == HEALTH_NAME && query.query_class() == DNSClass::CH && from.ip().is_loopback() {
+            let status = self.health(now);
+            let response = local::chaos_response(&message, status.to_string().as_str());
+            self.send_response(index, &response, from);
+            return;
+        }
+        if let Some(response) = local::special_use_response(&message, self.args.local_answer_ttl) {
+            log::debug!("answer special-use name {} locally", name);
+            self.send_response(index, &response, from);
+            return;
+        }
+        if local::is_chaos_identity(&message) {
+            log::debug!("answer CHAOS query {} locally", name);
+            let response = local::chaos_response(&message, self.args.dns_version_string.as_str());
+            self.send_response(index, &response, from);
+            return;
+        }
+        if self.args.suppress_aaaa && query.query_type() == RecordType::AAAA
+            || self.args.suppress_a && query.query_type() == RecordType::A
+        {
+            log::debug!(
+                "answer {} query for {} with NODATA",
+                query.query_type(),
+                name
+            );
+            let response = local::nodata_response(&message, self.args.local_answer_ttl);
+            self.send_response(index, &response, from);
+            return;
+        }
+        if stage == Some(ResolutionStage::Sinkhole) {
+            log::info!("domain:{} from {} is sinkholed", name, from);
+            let response = local::sinkhole_response(
+                &message,
+                self.args.sinkhole_ip,
+                self.args.sinkhole_ipv6,
+                self.args.local_answer_ttl,
+            );
+            self.send_response(index, &response, from);
+            return;
+        }
+        if query.query_type() == RecordType::SRV {
+            if let Some(srv) = self.srv_map.get(&name.to_lowercase()) {
+                log::debug!("answer SRV query for {} with {}", name, srv);
+                let response = srv::srv_response(&message, srv, self.args.local_answer_ttl);
+                self.send_response(index, &response, from);
+                return;
+            }
+        }
+        // after local services, which may well live under .local
+        if let Some(response) = local::link_local_response(&message, self.args.local_answer_ttl) {
+            log::debug!("answer link-local name {} locally", name);
+            self.send_response(index, &response, from);
+            return;
+        }
+        if query.query_type() == RecordType::ANY {
+            log::debug!("answer ANY query for {} with HINFO", name);
+            let response = local::any_response(&message, self.args.local_answer_ttl);
+            self.send_response(index, &response, from);
+            return;
+        }
+        let source = self.classify(query, from.ip());
+        if let Some(observer) = self.observer.as_mut() {
+            observer.on_query(&name, source);
+        }
+        if source == Upstream::Trusted
+            && self.fake_ips.is_some()
+            && self.answer_fake(index, from, &message)
+        {
+            return;
+        }
+        let key = Self::get_message_key(&message, source);
+        let bypass = edns::bypass_cache(&message) || self.no_cache_domains.contains(&name);
+        let mut reroute = None;
+        let (renew, respond) = match self.store.get_mut(&key) {
+            _ if bypass => {
+                log::info!("query:{} bypasses cache", key);
+                self.cache_metrics.cache_miss += 1;
+                (true, true)
+            }
+            Some(QueryResult {
+                response: Some(response),
+                expire_time,
+                update_time,
+                query_time,
+                hits,
+                ..
+            }) => {
+                log::info!("query:{} found in cache", key);
+                if *expire_time > now {
+                    self.cache_metrics.cache_hit += 1;
+                } else {
+                    self.cache_metrics.cache_expired_refresh += 1;
+                }
+                *hits += 1;
+                // routes may be gone since they were added, like flushed by a tunnel restart
+                if self.args.reroute_on_cache_hit {
+                    reroute = Some(response.clone());
+                }
+                let elapsed = now.saturating_duration_since(*update_time).as_secs() as u32;
+                let mut response = age_response(response, elapsed);
+                if self.args.rrset_rotate {
+                    rotate_addresses(&mut response, *hits as usize);
+                }
+                response.set_id(message.id());
+                response.set_authoritative(false);
+                response.set_recursion_available(true);
+                edns::rewrite_cookie(&mut response, &message);
+                let data = truncated_response(&response, edns::payload_size(&message));
+                if let Err(err) = self.listeners[index].send_to(data.as_slice(), from) {
+                    log::error!("send response to {} failed:{}", from, err);
+                }
+                (
+                    *expire_time <= now && *query_time + QUERY_TIMEOUT <= now,
+                    false,
+                )
+            }
+            Some(QueryResult {
+                response: None,
+                query_time,
+                ..
+            }) if *query_time + QUERY_TIMEOUT > now => {
+                log::info!("query:{} is in flight", key);
+                self.cache_metrics.cache_miss += 1;
+                (false, true)
+            }
+            _ => {
+                self.cache_metrics.cache_miss += 1;
+                (true, true)
+            }
+        };
+        if let Some(response) = reroute {
+            log::debug!("reroute cached addresses of {}", name);
+            let addresses = response
+                .answers()
+                .iter()
+                .filter_map(Record::data)
+                .flat_map(svcb::record_addresses)
+                .collect();
+            let domain = query.name().to_lowercase().to_utf8();
+            self.add_routes(addresses, &domain, cname_target(&response), source);
+        }
+
+        if renew {
+            if !self.send_upstream(key.clone(), &message, data, source, now) {
+                return;
+            }
+            log::info!("domain:{} from {} goes {}", name, from, source);
+            self.compare(&message, data, source, now);
+            if self.args.prefetch_aaaa && query.query_type() == RecordType::A {
+                self.prefetch(&message, RecordType::AAAA, source, now);
+            }
+        }
+        if respond {
+            self.add_request(
+                key,
+                PendingClient {
+                    listener: index,
+                    address: from,
+                    id: message.id(),
+                    query_time: now,
+                    max_size: edns::payload_size(&message),
+                },
+            );
+        }
+    }
+
+    /// PTR record for the reverse name `name` of an address in unexpired cached answers,
+    /// pointing to the domain which resolved to it.
+    fn cached_ptr(&self, name: &Name, now: Instant) -> Option<Record> {
+        let net = name.parse_arpa_name().ok()?;
+        if net.prefix_len() != net.max_prefix_len() {
+            return None;
+        }
+        let ip = net.addr();
+        self.store.values().find_map(|result| {
+            let response = result.response.as_ref()?;
+            if result.expire_time <= now
+                || !response
+                    .answers()
+                    .iter()
+                    .any(|record| record.data().and_then(RData::to_ip_addr) == Some(ip))
+            {
+                return None;
+            }
+            let ttl = result.expire_time.duration_since(now).as_secs() as u32;
+            let domain = response.queries().first()?.name().clone();
+            Some(Record::from_rdata(name.clone(), ttl, RData::PTR(domain)))
+        })
     }
 
-    fn dispatch_server(
-        recv_socket: &UdpSocket,
-        send_socket: &UdpSocket,
-        buffer: &mut [u8],
-        store: &mut HashMap<String, QueryResult>,
-        route_added: &mut HashSet<u32>,
-        adapter_index: u32,
-        blocked: bool,
+    /// Forward `data` of `request` to upstream and mark `key` as in flight.
+    fn send_upstream(
+        &mut self,
+        key: String,
+        request: &Message,
+        data: &[u8],
+        source: Upstream,
+        now: Instant,
     ) -> bool {
-        let now = Instant::now();
-        loop {
-            match recv_socket.recv_from(buffer) {
-                Ok((length, from)) => {
-                    let data = &buffer[..length];
-                    if let Ok(mut message) = Message::from_bytes(data) {
-                        log::debug!("response:{:?}", message);
-                        let name = Self::get_message_key(&message);
-                        if let Some(result) = store.get_mut(&name) {
-                            for (address, id) in &result.addresses {
-                                message.set_id(*id);
-                                if let Err(err) = send_socket
-                                    .send_to(message.to_vec().unwrap().as_slice(), *address)
-                                {
-                                    log::error!("send to {} failed:{}", address, err);
-                                } else {
-                                    log::debug!("send response to {}", address);
-                                }
-                            }
-                            let mut timeout = 0;
-                            for record in message.answers() {
-                                timeout = record.ttl();
-                                if let Some(addr) = record.data().and_then(|data| data.to_ip_addr())
-                                {
-                                    if OPTIONS.dns_args().add_route && blocked && addr.is_ipv4() {
-                                        if let IpAddr::V4(addr) = addr {
-                                            let addr: u32 = addr.into();
-                                            if !route_added.contains(&addr)
-                                                && route_add_with_if(addr, !0, 0, adapter_index)
-                                                    .is_ok()
-                                            {
-                                                route_added.insert(addr);
-                                            }
-                                        }
-                                    }
-                                }
-                                log::info!(
-                                    "got response {} -> {}, expire in {} seconds",
-                                    name,
-                                    record.to_string(),
-                                    timeout,
-                                );
-                            }
-                            result.expire_time = now + Duration::new(timeout as u64, 0);
-                            result.addresses.clear();
-                            result.response.replace(message);
-                        } else {
-                            log::error!("key:{} not found in store", name);
-                        }
-                    } else {
-                        log::error!("invalid response message received from {}", from);
-                    }
+        let padding = self.args.dns_padding;
+        let data = match source {
+            Upstream::Trusted
+                if !self.is_sniffer() && (self.client_subnet.is_some() || padding != 0) =>
+            {
+                // rebuilt from the parsed request, so the client's header flags and OPT record
+                // like the DO bit go upstream unchanged
+                let mut query = request.clone();
+                if let Some(subnet) = &self.client_subnet {
+                    edns::set_client_subnet(&mut query, subnet.clone());
                 }
-                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
-                Err(err) if err.kind() == ErrorKind::ConnectionReset => continue,
-                Err(err) => {
-                    log::error!(
-                        "dns response from:{:?} recv failed:{}",
-                        recv_socket.local_addr(),
-                        err
-                    );
-                    return false;
+                // padding goes last, it depends on the final size
+                if padding != 0 {
+                    edns::set_padding(&mut query, padding);
                 }
+                query.to_vec().unwrap()
             }
+            _ => data.to_vec(),
+        };
+        if let Err(err) = self.upstream(source).send_query(&data) {
+            log::error!("send to {} dns failed:{}", source, err);
+            self.upstream_health(source).on_failure();
+            self.on_socket_error(source);
+            return false;
         }
+        self.upstream_health(source).clear_socket_errors();
+        let cache_time = Duration::new(self.args.dns_cache_time, 0);
+        let result = self
+            .store
+            .entry(key)
+            .or_insert_with(|| QueryResult::new(now, source, cache_time));
+        result.query_time = now;
+        result.pending = true;
+        result.retries = 0;
+        result.answered = false;
+        result.synthesizing = false;
+        result.fallback = false;
+        result.query = data;
+        result.question = request.queries().first().cloned();
         true
     }
 
-    fn dispatch_trusted(&mut self, poll: &Poll) {
-        if !Self::dispatch_server(
-            &self.trusted,
-            &self.listener,
-            self.buffer.as_mut_slice(),
-            &mut self.store,
-            &mut self.route_added,
-            self.adapter_index,
-            true,
-        ) {
-            poll.registry()
-                .reregister(&mut self.trusted, Token(DNS_TRUSTED), Interest::READABLE)
-                .unwrap();
+    /// Speculatively resolve the same name with `query_type`, so the coming query hits the cache.
+    fn prefetch(
+        &mut self,
+        request: &Message,
+        query_type: RecordType,
+        source: Upstream,
+        now: Instant,
+    ) {
+        let message = with_query_type(request, query_type);
+        let key = Self::get_message_key(&message, source);
+        if let Some(result) = self.store.get(&key) {
+            if result.query_time + QUERY_TIMEOUT > now
+                || result.response.is_some() && result.expire_time > now
+            {
+                return;
+            }
         }
+        log::info!("prefetch query:{}", key);
+        let data = message.to_vec().unwrap();
+        self.send_upstream(key, &message, data.as_slice(), source, now);
     }
 
-    fn dispatch_poisoned(&mut self, poll: &Poll) {
-        if !Self::dispatch_server(
-            &self.poisoned,
-            &self.listener,
-            self.buffer.as_mut_slice(),
-            &mut self.store,
-            &mut self.route_added,
-            self.adapter_index,
-            false,
-        ) {
-            poll.registry()
-                .reregister(&mut self.poisoned, Token(DNS_POISONED), Interest::READABLE)
-                .unwrap();
+    fn send_response(&self, index: usize, message: &Message, to: SocketAddr) {
+        if let Err(err) = self.listeners[index].send_to(message.to_vec().unwrap().as_slice(), to) {
+            log::error!("send response to {} failed:{}", to, err);
         }
     }
 
-    fn is_blocked(&self, name: &str) -> bool {
-        self.blocked_domains.contains(name)
-    }
-    fn add_request(&mut self, name: String, address: SocketAddr, id: u16) {
-        let result = if let Some(result) = self.store.get_mut(&name) {
-            result
+    /// Upstream a query from `client` goes to.
+    fn classify(&self, query: &Query, client: IpAddr) -> Upstream {
+        if let Some(index) = self.query_types.get(&query.query_type()) {
+            Upstream::Typed(*index)
+        } else if self.is_blocked(&query.name().to_utf8(), client) {
+            Upstream::Trusted
         } else {
-            self.store.insert(
-                name.clone(),
-                QueryResult {
-                    addresses: vec![],
-                    response: None,
-                    expire_time: Instant::now()
-                        + Duration::new(OPTIONS.dns_args().dns_cache_time, 0),
-                },
-            );
-            self.store.get_mut(&name).unwrap()
+            Upstream::Poisoned
+        }
+    }
+
+    /// Cache key of a message, answers from different upstreams are cached separately.
+    /// The query type is part of the key, so DS/DNSKEY answers never collide with addresses.
+    /// Names are lowercased, as upstreams may echo the question in randomized case.
+    /// Every query of a multi-query message is part of its key.
+    fn get_message_key(message: &Message, source: Upstream) -> String {
+        format!("{}|{}", Self::question_key(message), source)
+    }
+
+    /// Queries of a message regardless of the upstream, like example.com.|A
+    fn question_key(message: &Message) -> String {
+        let queries: Vec<String> = message
+            .queries()
+            .iter()
+            .map(|query| {
+                let name = query.name().to_lowercase().to_utf8();
+                format!("{}|{}", name, query.query_type())
+            })
+            .collect();
+        queries.join(",")
+    }
+
+    /// Send a sampled fraction of queries to the other upstream too, the answers are compared
+    /// and the other one is dropped.
+    fn compare(&mut self, request: &Message, data: &[u8], source: Upstream, now: Instant) {
+        let rate = self.args.compare_sample_rate;
+        let other = match source {
+            Upstream::Trusted => Upstream::Poisoned,
+            Upstream::Poisoned => Upstream::Trusted,
+            Upstream::Typed(_) => return,
+        };
+        if rate <= 0.0 || !sampled(rate) {
+            return;
+        }
+        let key = Self::question_key(request);
+        if self.comparisons.contains_key(&key) {
+            return;
+        }
+        if let Err(err) = self.upstream(other).send_query(data) {
+            log::error!("send comparison query to {} dns failed:{}", other, err);
+            return;
+        }
+        log::debug!("query:{} compared with {} dns", key, other);
+        self.comparisons.insert(
+            key,
+            Comparison {
+                source,
+                trusted: None,
+                poisoned: None,
+                query_time: now,
+            },
+        );
+    }
+
+    /// Record the addresses of `response` to a sampled query, logging the differences once
+    /// both upstreams answered. Returns true if the response only answers the comparison.
+    fn record_comparison(&mut self, response: &Message, source: Upstream) -> bool {
+        let key = Self::question_key(response);
+        let comparison = match self.comparisons.get_mut(&key) {
+            Some(comparison) => comparison,
+            None => return false,
+        };
+        let compared = source != comparison.source;
+        let slot = match source {
+            Upstream::Trusted => &mut comparison.trusted,
+            Upstream::Poisoned => &mut comparison.poisoned,
+            Upstream::Typed(_) => return false,
+        };
+        if slot.is_none() {
+            let addresses = response
+                .answers()
+                .iter()
+                .filter_map(|record| record.data().and_then(RData::to_ip_addr))
+                .sorted()
+                .collect();
+            slot.replace(addresses);
+        }
+        if let (Some(trusted), Some(poisoned)) = (&comparison.trusted, &comparison.poisoned) {
+            let trusted_only: Vec<_> = trusted.iter().filter(|ip| !poisoned.contains(ip)).collect();
+            let poisoned_only: Vec<_> =
+                poisoned.iter().filter(|ip| !trusted.contains(ip)).collect();
+            if trusted_only.is_empty() && poisoned_only.is_empty() {
+                log::info!("compare {}: answers agree", key);
+            } else {
+                log::warn!(
+                    "compare {}: trusted only {:?}, poisoned only {:?}",
+                    key,
+                    trusted_only,
+                    poisoned_only
+                );
+            }
+            self.comparisons.remove(&key);
+        }
+        // a client may be waiting for the other upstream's answer as well
+        compared
+            && !self
+                .store
+                .get(&Self::get_message_key(response, source))
+                .is_some_and(|result| result.pending)
+    }
+
+    fn upstream_health(&mut self, source: Upstream) -> &mut UpstreamHealth {
+        match source {
+            Upstream::Trusted => &mut self.trusted_health,
+            Upstream::Poisoned => &mut self.poisoned_health,
+            Upstream::Typed(index) => &mut self.typed_health[index],
+        }
+    }
+
+    /// Whether listeners are bound, upstreams answer, routes are added and a query succeeded
+    /// recently.
+    pub fn health(&self, now: Instant) -> HealthStatus {
+        let listening = self.ready
+            && !self.listeners.is_empty()
+            && self
+                .listeners
+                .iter()
+                .all(|listener| listener.local_addr().is_ok());
+        HealthStatus::new(
+            listening,
+            !self.route_disconnected,
+            &self.trusted_health,
+            &self.poisoned_health,
+            now,
+        )
+    }
+
+    /// Warn about trusted and poisoned DNS being the same server, which defeats the split
+    /// and leaves blocked domains unprotected, panic under the strict flag.
+    fn check_upstreams(&self) {
+        let server_addr = self.trusted.server_addr();
+        if server_addr != self.poisoned.server_addr() || self.is_sniffer() {
+            return;
+        }
+        if self.args.strict_upstreams {
+            panic!("trusted and poisoned dns are both {}", server_addr);
+        }
+        log::error!(
+            "trusted and poisoned dns are both {}, blocked domains are not protected",
+            server_addr
+        );
+    }
+
+    fn upstream(&mut self, source: Upstream) -> &mut dyn DnsUpstream {
+        self.upstream_slot(source).as_mut()
+    }
+
+    fn upstream_slot(&mut self, source: Upstream) -> &mut Box<dyn DnsUpstream> {
+        match source {
+            Upstream::Trusted => &mut self.trusted,
+            Upstream::Poisoned => &mut self.poisoned,
+            Upstream::Typed(index) => &mut self.typed[index],
+        }
+    }
+
+    /// Configured URL of `source`, pointing to its current server address.
+    fn upstream_url(&mut self, source: Upstream) -> UpstreamUrl {
+        let mut url: UpstreamUrl = match source {
+            Upstream::Trusted => self
+                .args
+                .downstream_dns
+                .as_ref()
+                .unwrap_or(&self.args.trusted_dns)
+                .parse()
+                .unwrap(),
+            Upstream::Poisoned => self
+                .args
+                .downstream_dns
+                .as_ref()
+                .unwrap_or(&self.args.poisoned_dns)
+                .parse()
+                .unwrap(),
+            Upstream::Typed(index) => {
+                let (mut urls, _) =
+                    upstream::parse_type_upstreams(&self.args.query_type_upstream).unwrap();
+                urls.swap_remove(index)
+            }
         };
-        result.addresses.push((address, id));
+        url.addr = self.upstream(source).server_addr();
+        url
+    }
+
+    /// Count a send or receive error of `source`, recreating its sockets once errors repeat,
+    /// returns true if they're recreated.
+    fn on_socket_error(&mut self, source: Upstream) -> bool {
+        let errors = self.upstream_health(source).on_socket_error();
+        if errors < SOCKET_ERROR_LIMIT {
+            return false;
+        }
+        if self.registry.is_none() {
+            return false;
+        }
+        match self.recreate_upstream(source) {
+            Ok(_) => {
+                self.upstream_health(source).clear_socket_errors();
+                log::warn!("{} dns sockets recreated after {} errors", source, errors);
+                true
+            }
+            Err(err) => {
+                log::error!("recreate {} dns sockets failed:{}", source, err);
+                false
+            }
+        }
+    }
+
+    /// Replace the sockets of `source` with new ones registered for the same token.
+    fn recreate_upstream(&mut self, source: Upstream) -> io::Result<()> {
+        let url = self.upstream_url(source);
+        let token = self.tokens.token(DnsSocket::Upstream(source)).unwrap();
+        let mut upstream = url.upstream(self.args.upstream_ports)?;
+        if let Some(registry) = &self.registry {
+            upstream.register(registry, token)?;
+        }
+        *self.upstream_slot(source) = upstream;
+        Ok(())
+    }
+
+    fn dispatch_server(&mut self, poll: &Poll, source: Upstream) {
+        let now = self.clock.now();
+        loop {
+            let upstream = match source {
+                Upstream::Trusted => self.trusted.as_mut(),
+                Upstream::Poisoned => self.poisoned.as_mut(),
+                Upstream::Typed(index) => self.typed[index].as_mut(),
+            };
+            match upstream.recv_response(&mut self.buffer) {
+                Ok(length) => {
+                    if let Ok(message) = Message::from_bytes(&self.buffer[..length]) {
+                        self.handle_response(message, source, now);
+                    } else {
+                        let from = self.upstream(source).server_addr();
+                        log::error!("invalid response message received from {}", from);
+                        if self.args.dns_debug_hex {
+                            log_hex(
+                                &format!("invalid response from {}", from),
+                                &self.buffer[..length],
+                            );
+                        }
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::ConnectionReset => continue,
+                Err(err) => {
+                    let upstream = self.upstream(source);
+                    log::error!(
+                        "dns response from:{} recv failed:{}",
+                        upstream.server_addr(),
+                        err
+                    );
+                    if !self.on_socket_error(source) {
+                        let token = self.tokens.token(DnsSocket::Upstream(source)).unwrap();
+                        self.upstream(source)
+                            .reregister(poll.registry(), token)
+                            .unwrap();
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    fn handle_response(&mut self, mut message: Message, source: Upstream, now: Instant) {
+        log::debug!("response:{:?}", message);
+        if message.message_type() != MessageType::Response || message.query_count() == 0 {
+            log::error!("invalid response from {} dns:{:?}", source, message);
+            return;
+        }
+        self.upstream_health(source).on_response(now);
+        if !self.comparisons.is_empty() && self.record_comparison(&message, source) {
+            return;
+        }
+        // answers to multi-query messages are relayed whole, never cached
+        let multi = message.query_count() > 1;
+        if self.nat64_prefix.is_some()
+            && !self.is_sniffer()
+            && !multi
+            && self.start_dns64(&message, source, now)
+        {
+            return;
+        }
+        let name = Self::get_message_key(&message, source);
+        let domain = message.queries()[0].name().to_lowercase().to_utf8();
+        let cname = cname_target(&message);
+        let mut synthesis = None;
+        let mut part = None;
+        let mut routes = vec![];
+        // errors like a transient SERVFAIL aren't cached, so the next query goes upstream again,
+        // an earlier answer is kept
+        let failed = !matches!(
+            message.response_code(),
+            ResponseCode::NoError | ResponseCode::NXDomain
+        );
+        if let Some(result) = self.store.get_mut(&name) {
+            if result.question.as_ref() != message.queries().first() {
+                log::error!(
+                    "response question {} mismatches query {:?} from {} dns",
+                    message.queries()[0],
+                    result.question,
+                    source
+                );
+                if self.args.dns_debug_hex {
+                    let data = message.to_vec().unwrap_or_default();
+                    log_hex(&format!("mismatched response from {} dns", source), &data);
+                }
+                return;
+            }
+            // retransmissions or a flaky upstream may answer twice, clients are answered and
+            // routes are added once
+            if result.answered {
+                log::debug!("duplicate response {} from {} dns dropped", name, source);
+                return;
+            }
+            if matches!(
+                message.response_code(),
+                ResponseCode::ServFail | ResponseCode::Refused
+            ) && result.serve_stale(&self.listeners)
+            {
+                log::warn!(
+                    "{} from {} dns, serve stale {}",
+                    message.response_code(),
+                    source,
+                    name
+                );
+                // the query is answered, retransmissions and the timeout check leave it alone
+                result.pending = false;
+                result.answered = true;
+                return;
+            }
+            // the downstream resolver's answers pass through unchanged
+            if self.args.downstream_dns.is_none() {
+                if matches!(
+                    message.queries()[0].query_type(),
+                    RecordType::HTTPS | RecordType::SVCB
+                ) && self.strip_ech_domains.contains(&domain)
+                    && svcb::strip_ech(&mut message)
+                {
+                    log::debug!("ech config stripped from {} answers", domain);
+                }
+                // the stripped answer is what gets cached, so cache hits and stale answers lack
+                // ECH too
+                if self.args.minimal_responses {
+                    message = minimal_response(&message);
+                }
+                let (min_ttl, max_ttl) = (self.args.dns_min_ttl, self.args.dns_max_ttl);
+                map_ttl(&mut message, |ttl| clamp_ttl(ttl, min_ttl, max_ttl));
+            }
+            let window = Duration::new(self.args.pending_client_timeout, 0);
+            for client in &result.addresses {
+                if client.is_expired(now, window) {
+                    log::warn!(
+                        "drop response {} to {}, it waited too long",
+                        name,
+                        client.address
+                    );
+                    continue;
+                }
+                if let Err(err) = self.listeners[client.listener]
+                    .send_to(client.response(&mut message).as_slice(), client.address)
+                {
+                    log::error!("send to {} failed:{}", client.address, err);
+                } else {
+                    log::debug!("send response to {}", client.address);
+                }
+            }
+            let mut timeout = 0;
+            for record in message.answers() {
+                timeout = record.ttl();
+                let addresses = record.data().map(svcb::record_addresses);
+                routes.extend(addresses.into_iter().flatten());
+                log::info!(
+                    "got response {} -> {}, expire in {} seconds",
+                    name,
+                    record.to_string(),
+                    timeout,
+                );
+            }
+            if failed {
+                log::info!(
+                    "{} from {} dns for {} not cached",
+                    message.response_code(),
+                    source,
+                    name
+                );
+            } else {
+                // passed through answers keep their TTL, but the cache lifetime is clamped too
+                if !message.answers().is_empty() {
+                    timeout = clamp_ttl(timeout, self.args.dns_min_ttl, self.args.dns_max_ttl);
+                }
+                result.expire_time = now + Duration::new(timeout as u64, 0);
+                result.update_time = now;
+            }
+            result.pending = false;
+            result.answered = true;
+            result.addresses.clear();
+            synthesis = result.dns64.take().map(|key| (key, message.clone()));
+            if !self.batches.is_empty() {
+                part = Some(message.clone());
+            }
+            #[cfg(not(feature = "no_dns_cache"))]
+            if !failed {
+                result.response.replace(message);
+            }
+        } else {
+            log::error!("key:{} not found in store", name);
+        }
+        // without the cache, entries only live while their query is in flight
+        #[cfg(feature = "no_dns_cache")]
+        self.store.remove(&name);
+        #[cfg(not(feature = "no_dns_cache"))]
+        if self.is_sniffer()
+            || multi
+            || self.no_cache_domains.contains(&domain)
+            || failed
+                && self
+                    .store
+                    .get(&name)
+                    .is_some_and(|result| result.response.is_none())
+        {
+            self.store.remove(&name);
+        }
+        self.add_routes(routes, &domain, cname, source);
+        if let Some(response) = part {
+            self.fill_batches(&name, &response);
+        }
+        if let Some((key, response)) = synthesis {
+            self.finish_dns64(&key, &response, source, now);
+        }
+    }
+
+    /// Route `addresses` resolved for `domain` through `cname` by `source`.
+    fn add_routes(
+        &mut self,
+        addresses: Vec<IpAddr>,
+        domain: &str,
+        cname: Option<String>,
+        source: Upstream,
+    ) {
+        for ip in addresses {
+            if !self.args.add_route
+                || !self.args.route_trusted_answers
+                || source != Upstream::Trusted
+                || self.args.route_prefer_ipv4 && ip.is_ipv6()
+            {
+                continue;
+            }
+            if !self.args.route_private && !route::is_public(ip) {
+                self.private_routes_skipped += 1;
+                log::warn!(
+                    "{} resolved for {} is private, not routed, {} skipped so far",
+                    ip,
+                    domain,
+                    self.private_routes_skipped
+                );
+                continue;
+            }
+            let event = RouteEvent {
+                ip,
+                domain: domain.to_string(),
+                cname: cname.clone(),
+                source,
+            };
+            let dropped = match self.route_sender.send(event) {
+                Ok(dropped) => {
+                    if let Some(observer) = self.observer.as_mut() {
+                        observer.on_route(ip, domain);
+                    }
+                    dropped
+                }
+                Err(TrySendError::Full(event)) => Some(event),
+                Err(TrySendError::Disconnected(_)) => {
+                    if !self.route_disconnected {
+                        log::error!(
+                            "route thread exited, resolved addresses are not routed anymore"
+                        );
+                    }
+                    self.route_disconnected = true;
+                    None
+                }
+            };
+            if let Some(event) = dropped {
+                self.dropped_routes += 1;
+                log::error!(
+                    "route channel full, drop route {} for {}",
+                    event.ip,
+                    event.domain
+                );
+            }
+        }
+    }
+
+    /// Query A records for the AAAA `response` without addresses, returns true if the AAAA
+    /// clients wait for the synthesized answer instead.
+    fn start_dns64(&mut self, response: &Message, source: Upstream, now: Instant) -> bool {
+        if !dns64::needs_synthesis(response) {
+            return false;
+        }
+        let key = Self::get_message_key(response, source);
+        let request = match self.store.get_mut(&key) {
+            Some(result)
+                if !result.answered
+                    && !result.synthesizing
+                    && result.question.as_ref() == response.queries().first() =>
+            {
+                match Message::from_vec(&result.query) {
+                    Ok(request) => {
+                        result.synthesizing = true;
+                        result.pending = false;
+                        request
+                    }
+                    Err(_) => return false,
+                }
+            }
+            _ => return false,
+        };
+        let message = with_query_type(&request, RecordType::A);
+        let a_key = Self::get_message_key(&message, source);
+        let data = message.to_vec().unwrap();
+        if !self.send_upstream(a_key.clone(), &message, data.as_slice(), source, now) {
+            if let Some(result) = self.store.get_mut(&key) {
+                result.synthesizing = false;
+            }
+            return false;
+        }
+        log::info!("no AAAA record for {}, synthesize from A records", key);
+        if let Some(result) = self.store.get_mut(&a_key) {
+            result.dns64 = Some(key);
+        }
+        true
+    }
+
+    /// Answer the AAAA query `key` with addresses synthesized from the A `response`, they
+    /// are cached and routed like an upstream answer.
+    fn finish_dns64(&mut self, key: &str, response: &Message, source: Upstream, now: Instant) {
+        let question = match self.store.get(key) {
+            Some(result) if result.synthesizing => result.question.clone(),
+            _ => None,
+        };
+        if let (Some(question), Some(prefix)) = (question, self.nat64_prefix) {
+            let message = dns64::synthesize_response(response, &question, prefix);
+            self.handle_response(message, source, now);
+        }
+    }
+
+    /// Whether `name` goes to trusted DNS, the force direct list and the block lists are
+    /// matched in pipeline order.
+    fn is_blocked(&self, name: &str, client: IpAddr) -> bool {
+        if self.captive_portal_domains.contains(&name.to_lowercase()) {
+            return false;
+        }
+        self.args
+            .resolution_pipeline
+            .iter()
+            .find_map(|stage| match stage {
+                ResolutionStage::ForceDirect if self.direct_domains.contains(name) => Some(false),
+                ResolutionStage::Block if self.is_listed(name, client) => Some(true),
+                _ => None,
+            })
+            .unwrap_or(false)
+    }
+
+    /// Whether `name` is in the block lists or the policy of `client` blocks it.
+    fn is_listed(&self, name: &str, client: IpAddr) -> bool {
+        if let Some(policy) = self.client_policies.get(&client) {
+            policy.is_blocked(name)
+        } else {
+            self.blocked_domains.read().unwrap().contains(name)
+                || !self.scheduled_domains.is_empty()
+                    && self
+                        .scheduled_domains
+                        .contains(name, self.clock.local_minute())
+        }
+    }
+
+    /// First stage of the resolution pipeline listing `name`, None if no list has it.
+    fn resolution_stage(&self, name: &str, client: IpAddr) -> Option<ResolutionStage> {
+        self.args
+            .resolution_pipeline
+            .iter()
+            .copied()
+            .find(|stage| match stage {
+                ResolutionStage::Sinkhole => self.sinkhole_domains.contains(name),
+                ResolutionStage::Hosts => self.local_names.contains_key(&name.to_lowercase()),
+                ResolutionStage::ForceDirect => self.direct_domains.contains(name),
+                ResolutionStage::Block => self.is_listed(name, client),
+            })
+    }
+
+    /// Whether queries are passed through to the downstream resolver, only adding routes.
+    fn is_sniffer(&self) -> bool {
+        self.args.downstream_dns.is_some()
+    }
+
+    /// Pass `data` through to `source` unchanged, its answer is relayed to the client and
+    /// not cached.
+    fn pass_through(
+        &mut self,
+        index: usize,
+        from: SocketAddr,
+        data: &[u8],
+        message: &Message,
+        source: Upstream,
+        now: Instant,
+    ) {
+        let name = message.queries()[0].name().to_utf8();
+        let key = Self::get_message_key(message, source);
+        if !self.send_upstream(key.clone(), message, data, source, now) {
+            return;
+        }
+        log::info!("domain:{} from {} passed through as {}", name, from, source);
+        self.add_request(
+            key,
+            PendingClient {
+                listener: index,
+                address: from,
+                id: message.id(),
+                query_time: now,
+                max_size: edns::payload_size(message),
+            },
+        );
+    }
+
+    /// Resolve each query of the multi-query `message` on its own like a single one, cached
+    /// answers included, the client gets the merged answer once every query is answered.
+    fn split_query(&mut self, index: usize, from: SocketAddr, message: &Message, now: Instant) {
+        let mut parts = vec![];
+        for query in message.queries() {
+            let part = with_query(message, query);
+            let source = self.classify(query, from.ip());
+            let key = Self::get_message_key(&part, source);
+            let answer = match self.store.get(&key) {
+                Some(QueryResult {
+                    response: Some(response),
+                    expire_time,
+                    update_time,
+                    ..
+                }) if *expire_time > now => {
+                    let elapsed = now.saturating_duration_since(*update_time).as_secs() as u32;
+                    Some(age_response(response, elapsed))
+                }
+                Some(result) if result.pending && result.query_time + QUERY_TIMEOUT > now => None,
+                _ => {
+                    let data = part.to_vec().unwrap();
+                    if !self.send_upstream(key.clone(), &part, &data, source, now) {
+                        return;
+                    }
+                    log::info!("domain:{} from {} goes {}", query.name(), from, source);
+                    None
+                }
+            };
+            parts.push((key, answer));
+        }
+        self.batches.push(Batch {
+            client: PendingClient {
+                listener: index,
+                address: from,
+                id: message.id(),
+                query_time: now,
+                max_size: edns::payload_size(message),
+            },
+            request: message.clone(),
+            parts,
+        });
+        self.send_batches();
+    }
+
+    /// Fill the parts waiting for the answer to `key` with `response`.
+    fn fill_batches(&mut self, key: &str, response: &Message) {
+        for batch in &mut self.batches {
+            for (part, answer) in batch.parts.iter_mut() {
+                if part == key && answer.is_none() {
+                    answer.replace(response.clone());
+                }
+            }
+        }
+        self.send_batches();
+    }
+
+    /// Answer the clients whose split messages got every part answered.
+    fn send_batches(&mut self) {
+        let listeners = &self.listeners;
+        self.batches.retain(|batch| {
+            let parts: Option<Vec<&Message>> = batch
+                .parts
+                .iter()
+                .map(|(_, answer)| answer.as_ref())
+                .collect();
+            let parts = match parts {
+                Some(parts) => parts,
+                None => return true,
+            };
+            let mut response = merge_responses(&batch.request, &parts);
+            let client = &batch.client;
+            if let Err(err) = listeners[client.listener]
+                .send_to(client.response(&mut response).as_slice(), client.address)
+            {
+                log::error!("send to {} failed:{}", client.address, err);
+            }
+            false
+        });
+    }
+
+    /// Answer the blocked `request` with a fake address instead of resolving it, the tunnel
+    /// maps it back to the name. Returns false for query types resolved as usual.
+    fn answer_fake(&mut self, index: usize, from: SocketAddr, request: &Message) -> bool {
+        let query = &request.queries()[0];
+        let ttl = self.args.local_answer_ttl;
+        let response = match query.query_type() {
+            RecordType::A => {
+                let pool = self.fake_ips.as_mut().unwrap();
+                let (ip, new) = pool.allocate(&query.name().to_utf8());
+                let domain = pool.domain(ip).unwrap_or_default().to_string();
+                log::info!("domain:{} from {} gets fake ip {}", domain, from, ip);
+                if new {
+                    self.fake_ips_dirty = true;
+                    self.dump_fake_ips(self.clock.now());
+                    if self.args.add_route && self.args.route_trusted_answers {
+                        self.route_fake(ip, domain);
+                    }
+                }
+                let record = Record::from_rdata(query.name().clone(), ttl, RData::A(ip));
+                local::local_response(request, vec![record])
+            }
+            // real addresses must not leak past the fake one
+            RecordType::AAAA | RecordType::HTTPS | RecordType::SVCB => {
+                local::nodata_response(request, ttl)
+            }
+            _ => return false,
+        };
+        self.send_response(index, &response, from);
+        true
+    }
+
+    /// Dump the fake addresses to their file if new ones were handed out, at most once per
+    /// `FAKE_IP_DUMP_INTERVAL`, the rest of a burst is dumped by `check_timeout`.
+    fn dump_fake_ips(&mut self, now: Instant) {
+        if !self.fake_ips_dirty
+            || self
+                .fake_ips_dump_time
+                .is_some_and(|time| time + FAKE_IP_DUMP_INTERVAL > now)
+        {
+            return;
+        }
+        if let (Some(pool), Some(file)) = (&self.fake_ips, &self.args.fake_ip_file) {
+            if let Err(err) = pool.dump_file(file) {
+                log::error!("dump fake ips to {} failed:{:?}", file, err);
+            }
+        }
+        self.fake_ips_dirty = false;
+        self.fake_ips_dump_time = Some(now);
+    }
+
+    /// Route the fake address `ip` into the tunnel like a trusted answer.
+    fn route_fake(&mut self, ip: Ipv4Addr, domain: String) {
+        let event = RouteEvent {
+            ip: ip.into(),
+            domain,
+            cname: None,
+            source: Upstream::Trusted,
+        };
+        match self.route_sender.send(event) {
+            Ok(None) => {}
+            Ok(Some(event)) | Err(TrySendError::Full(event)) => {
+                self.dropped_routes += 1;
+                log::error!(
+                    "route channel full, drop route {} for {}",
+                    event.ip,
+                    event.domain
+                );
+            }
+            Err(TrySendError::Disconnected(_)) => self.route_disconnected = true,
+        }
+    }
+
+    fn is_allowed(&self, client: IpAddr) -> bool {
+        self.allowed_clients.is_empty()
+            || client.is_loopback()
+            || self.allowed_clients.iter().any(|net| net.contains(client))
+    }
+
+    fn add_request(&mut self, name: String, client: PendingClient) {
+        if let Some(result) = self.store.get_mut(&name) {
+            result.addresses.push(client);
+        } else {
+            log::error!("key:{} not found in store", name);
+        }
+    }
+
+    /// Load the block list files again on a separate thread, queries are answered by the old
+    /// list until the new one is fully loaded and swapped in. Only one reload runs at a time,
+    /// None if one is still in progress.
+    pub fn reload_blocked_domains(&self) -> Option<JoinHandle<()>> {
+        if self.reloading.swap(true, Ordering::AcqRel) {
+            log::warn!("blocked domain list reload already in progress");
+            return None;
+        }
+        let args = self.args.clone();
+        let blocked_domains = self.blocked_domains.clone();
+        let replaced_sender = self.replaced_sender.clone();
+        let reloading = self.reloading.clone();
+        Some(thread::spawn(move || {
+            match load_blocked_domains(&args) {
+                Ok(domains) => {
+                    let old = std::mem::replace(&mut *blocked_domains.write().unwrap(), domains);
+                    log::warn!("blocked domain list reloaded");
+                    let _ = replaced_sender.send(old);
+                }
+                Err(err) => log::error!("reload blocked domain list failed:{:?}", err),
+            }
+            reloading.store(false, Ordering::Release);
+        }))
+    }
+
+    /// Drop cached answers of domains a reload moved into or out of the block list, so their
+    /// next query goes to the upstream of the new list.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn handle_reloads(&mut self) {
+        let replaced: Vec<DomainMap> = self.replaced_lists.try_iter().collect();
+        if replaced.is_empty() {
+            return;
+        }
+        let blocked_domains = self.blocked_domains.read().unwrap();
+        let count = self.store.len();
+        self.store.retain(|_, result| {
+            let name = match &result.question {
+                Some(question) => question.name().to_lowercase().to_utf8(),
+                None => return true,
+            };
+            // entries with waiting clients are kept so they still get answered
+            !result.addresses.is_empty()
+                || !matches!(result.source, Upstream::Trusted | Upstream::Poisoned)
+                || replaced
+                    .iter()
+                    .all(|old| old.contains(&name) == blocked_domains.contains(&name))
+        });
+        if self.store.len() != count {
+            log::warn!(
+                "{} cache entries reclassified by the reloaded block list dropped",
+                count - self.store.len()
+            );
+        }
+    }
+
+    /// Add domains received from the promotion channel to the block list and its file.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn handle_promotions(&mut self) {
+        let domains: Vec<String> = match &self.promotions {
+            Some(receiver) => receiver.try_iter().collect(),
+            None => return,
+        };
+        for domain in domains {
+            let domain = domain.to_lowercase();
+            let mut blocked_domains = self.blocked_domains.write().unwrap();
+            if blocked_domains.contains(&domain) {
+                continue;
+            }
+            blocked_domains.add_domain(&domain);
+            drop(blocked_domains);
+            // poisoned answers cached for the domain and its subdomains must not be served
+            // anymore
+            let mut promoted = DomainMap::new();
+            promoted.add_domain(&domain);
+            self.store.retain(|_, result| match &result.question {
+                Some(question) if result.source == Upstream::Poisoned => {
+                    !promoted.contains(&question.name().to_lowercase().to_utf8())
+                }
+                _ => true,
+            });
+            let file = self
+                .args
+                .blocked_domain_list
+                .first()
+                .map_or("", String::as_str);
+            if file.is_empty() {
+                log::warn!(
+                    "domain:{} promoted to blocked, no list file to save it",
+                    domain
+                );
+                continue;
+            }
+            if let Err(err) = append_domain(file, &domain) {
+                log::error!("save {} to {} failed:{:?}", domain, file, err);
+            }
+            log::warn!("domain:{} promoted to blocked", domain);
+        }
+    }
+
+    /// Route the addresses a previous run saved to the route dump file again, so routing is
+    /// restored before their domains are resolved.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn warm_routes(&mut self) {
+        let file = match &self.args.route_dump_file {
+            Some(file) if self.args.add_route => file,
+            _ => return,
+        };
+        let events = match route::load_dump(file) {
+            Ok(events) => events,
+            Err(err) => {
+                log::warn!("load routes from {} failed:{:?}", file, err);
+                return;
+            }
+        };
+        let count = events.len();
+        for event in events {
+            if self.route_sender.send_wait(event).is_err() {
+                log::error!("route thread exited, routes of the last run not restored");
+                self.route_disconnected = true;
+                return;
+            }
+        }
+        log::warn!("{} routes of the last run restored from {}", count, file);
+    }
+
+    /// Switch upstream servers at runtime, queries still waiting for an answer are sent again
+    /// to the new servers, so their clients get answered.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn reconfigure_upstreams(&mut self, trusted: SocketAddr, poisoned: SocketAddr) {
+        let mut changed = vec![];
+        for (source, addr) in [(Upstream::Trusted, trusted), (Upstream::Poisoned, poisoned)] {
+            let upstream = self.upstream(source);
+            if upstream.server_addr() == addr {
+                continue;
+            }
+            log::warn!(
+                "{} dns changed from {} to {}",
+                source,
+                upstream.server_addr(),
+                addr
+            );
+            let family_changed = upstream.server_addr().is_ipv4() != addr.is_ipv4();
+            upstream.set_server_addr(addr);
+            *self.upstream_health(source) = UpstreamHealth::default();
+            changed.push(source);
+            // sockets are bound to the family of the old address
+            if family_changed {
+                if let Err(err) = self.recreate_upstream(source) {
+                    log::error!("recreate {} dns sockets failed:{}", source, err);
+                }
+            }
+        }
+        let now = self.clock.now();
+        for (key, result) in self.store.iter_mut() {
+            if !result.pending || !changed.contains(&result.source) {
+                continue;
+            }
+            let upstream = match result.source {
+                Upstream::Trusted => &mut self.trusted,
+                Upstream::Poisoned => &mut self.poisoned,
+                Upstream::Typed(index) => &mut self.typed[index],
+            };
+            if let Err(err) = upstream.send_query(result.query.as_slice()) {
+                log::error!("resend query:{} failed:{}", key, err);
+            } else {
+                result.query_time = now;
+            }
+        }
+    }
+
+    /// Cache state of `name`, from trusted DNS answers if any are cached, else poisoned ones.
+    // the binary itself only flushes the whole cache
+    #[allow(dead_code)]
+    pub fn lookup_cache(&self, name: &str) -> Option<CacheInfo> {
+        let mut name = name.to_lowercase();
+        if !name.ends_with('.') {
+            name.push('.');
+        }
+        let now = self.clock.now();
+        [Upstream::Trusted, Upstream::Poisoned]
+            .iter()
+            .find_map(|source| {
+                let results: Vec<(&QueryResult, &Message)> = [RecordType::A, RecordType::AAAA]
+                    .iter()
+                    .filter_map(|query_type| {
+                        let result = self
+                            .store
+                            .get(&format!("{}|{}|{}", name, query_type, source))?;
+                        Some((result, result.response.as_ref()?))
+                    })
+                    .collect();
+                if results.is_empty() {
+                    return None;
+                }
+                Some(CacheInfo {
+                    source: *source,
+                    addresses: results
+                        .iter()
+                        .flat_map(|(_, response)| response.answers())
+                        .filter_map(|record| record.data().and_then(RData::to_ip_addr))
+                        .collect(),
+                    age: results
+                        .iter()
+                        .map(|(result, _)| now.saturating_duration_since(result.update_time))
+                        .max()
+                        .unwrap_or_default(),
+                    fresh: results.iter().all(|(result, _)| result.expire_time > now),
+                })
+            })
+    }
+
+    /// Drop every cached answer, entries with waiting clients are kept so they still get answered.
+    pub fn flush_cache(&mut self) {
+        let count = self.store.len();
+        self.store.retain(|_, result| !result.addresses.is_empty());
+        log::warn!(
+            "dns cache flushed, {} entries removed",
+            count - self.store.len()
+        );
+    }
+
+    /// Answer clients whose query upstream didn't answer in time through the system resolver,
+    /// once both trusted and poisoned DNS fail. Blocked names are never resolved by it, its
+    /// answers are as poisoned as those of poisoned DNS.
+    fn system_fallback(&mut self, now: Instant) {
+        let failing = self.trusted_health.is_failing() && self.poisoned_health.is_failing();
+        let blocked_domains = self.blocked_domains.read().unwrap();
+        for (key, result) in self.store.iter_mut() {
+            if !failing
+                || result.fallback
+                || result.response.is_some()
+                || result.addresses.is_empty()
+                || result.query_time + QUERY_TIMEOUT > now
+                || result.source == Upstream::Trusted
+            {
+                continue;
+            }
+            let blocked = result.question.as_ref().is_none_or(|question| {
+                blocked_domains.contains(&question.name().to_lowercase().to_utf8())
+            });
+            if blocked {
+                continue;
+            }
+            result.fallback = true;
+            match Message::from_vec(result.query.as_slice()) {
+                Ok(request) => {
+                    log::warn!("query:{} timeout, fallback to system resolver", key);
+                    self.fallback.resolve(key.clone(), request);
+                }
+                Err(err) => log::error!("parse query:{} failed:{}", key, err),
+            }
+        }
+        drop(blocked_domains);
+        for (key, mut response) in self.fallback.responses() {
+            let result = match self.store.get_mut(&key) {
+                Some(result) if result.response.is_none() => result,
+                _ => continue,
+            };
+            for client in result.addresses.drain(..) {
+                if let Err(err) = self.listeners[client.listener]
+                    .send_to(client.response(&mut response).as_slice(), client.address)
+                {
+                    log::error!("send to {} failed:{}", client.address, err);
+                }
+            }
+        }
+    }
+
+    #[cfg_attr(not(windows), allow(dead_code))]
+    pub fn check_timeout(&mut self, now: Instant) {
+        if let Err(TrySendError::Disconnected(_)) = self.route_sender.flush() {
+            if !self.route_disconnected {
+                log::error!("route thread exited, resolved addresses are not routed anymore");
+            }
+            self.route_disconnected = true;
+        }
+        if let Some(failures) = &self.route_failures {
+            for failure in failures.try_iter() {
+                self.failed_routes += 1;
+                log::error!(
+                    "route {} for domain {} not installed:{}",
+                    failure.event.ip,
+                    failure.event.domain,
+                    failure.error
+                );
+            }
+        }
+        if self.metrics_time + METRICS_INTERVAL <= now {
+            log::info!("dns {}", self.cache_metrics);
+            if self.failed_routes != 0 {
+                log::warn!("{} routes failed to install", self.failed_routes);
+            }
+            if self.throttled_queries != 0 {
+                log::warn!("{} queries throttled", self.throttled_queries);
+            }
+            self.metrics_time = now;
+        }
+        self.rate_limiter.prune(now);
+        self.dump_fake_ips(now);
+        self.comparisons.retain(|key, comparison| {
+            let expired = comparison.query_time + QUERY_TIMEOUT <= now;
+            if expired {
+                log::debug!("comparison of {} timeout", key);
+            }
+            !expired
+        });
+        self.batches.retain(|batch| {
+            let expired = batch.client.is_expired(now, QUERY_TIMEOUT);
+            if expired {
+                log::warn!("split query from {} timeout", batch.client.address);
+            }
+            !expired
+        });
+        let retries = self.args.dns_retries;
+        let retry_interval = Duration::from_millis(self.args.dns_retry_interval_ms);
+        for (key, result) in self.store.iter_mut() {
+            if result.pending
+                && result.retries < retries
+                && result.query_time + retry_interval <= now
+            {
+                let upstream = match result.source {
+                    Upstream::Trusted => &mut self.trusted,
+                    Upstream::Poisoned => &mut self.poisoned,
+                    Upstream::Typed(index) => &mut self.typed[index],
+                };
+                result.retries += 1;
+                result.query_time = now;
+                match upstream.send_query(result.query.as_slice()) {
+                    Ok(_) => log::info!(
+                        "retransmit query:{} to {} dns, retry {}",
+                        key,
+                        result.source,
+                        result.retries
+                    ),
+                    Err(err) => log::error!("retransmit query:{} failed:{}", key, err),
+                }
+                continue;
+            }
+            if result.pending && result.query_time + QUERY_TIMEOUT <= now {
+                result.pending = false;
+                let health = match result.source {
+                    Upstream::Trusted => &mut self.trusted_health,
+                    Upstream::Poisoned => &mut self.poisoned_health,
+                    Upstream::Typed(index) => &mut self.typed_health[index],
+                };
+                health.on_failure();
+                log::warn!("query:{} to {} dns timeout", key, result.source);
+            }
+            if result.response.is_some()
+                && !result.addresses.is_empty()
+                && result.query_time + QUERY_TIMEOUT <= now
+                && result.serve_stale(&self.listeners)
+            {
+                log::warn!("query:{} timeout, serve stale answer", key);
+            }
+        }
+        if self.args.system_fallback {
+            self.system_fallback(now);
+        }
+        let refresh_hits = self.args.proactive_refresh;
+        if refresh_hits == 0 {
+            return;
+        }
+        for (key, result) in self.store.iter_mut() {
+            if result.response.is_none()
+                || result.hits < refresh_hits
+                || result.query_time + QUERY_TIMEOUT > now
+            {
+                continue;
+            }
+            let lifetime = result.expire_time - result.update_time;
+            if result.update_time + lifetime * REFRESH_PERCENT / 100 > now {
+                continue;
+            }
+            let upstream = match result.source {
+                Upstream::Trusted => &mut self.trusted,
+                Upstream::Poisoned => &mut self.poisoned,
+                Upstream::Typed(index) => &mut self.typed[index],
+            };
+            if let Err(err) = upstream.send_query(result.query.as_slice()) {
+                log::error!("refresh query:{} failed:{}", key, err);
+            } else {
+                log::info!("refresh hot query:{} with {} hits", key, result.hits);
+                result.query_time = now;
+                result.pending = true;
+                result.answered = false;
+                result.hits = 0;
+            }
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[allow(unused_imports)]
+mod tests {
+    use std::{
+        cell::Cell,
+        env, fs,
+        io::{self, Read, Write},
+        net::{IpAddr, SocketAddr, TcpListener, UdpSocket},
+        rc::Rc,
+        str::FromStr,
+        sync::{
+            mpsc::{channel, sync_channel, Receiver},
+            Mutex,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use clap::Parser;
+    use log::{LevelFilter, Log, Metadata, Record as LogRecord};
+    use mio::{Events, Poll, Registry, Token};
+    use trust_dns_proto::{
+        op::{Edns, Message, MessageType, Query, ResponseCode},
+        rr::{
+            rdata::{opt::EdnsCode, SOA},
+            DNSClass, Name, RData, Record, RecordType,
+        },
+    };
+
+    use crate::{
+        config::{DnsArgs, RoutePolicy},
+        dns::{
+            clock::Clock,
+            edns,
+            route::{self, RouteEvent, RouteSender, Upstream},
+            server::{
+                age_response, bind_listener, clamp_ttl, stale_response, CacheMetrics, DnsServer,
+                DnsServerBuilder, PendingClient, QueryResult, QUERY_TIMEOUT, SOCKET_ERROR_LIMIT,
+            },
+            upstream::DnsUpstream,
+        },
+        proto::MAX_PACKET_SIZE,
+        types::TrojanError,
+    };
+
+    #[test]
+    fn test_mixed_case_response() {
+        let mut query = Message::new();
+        query.add_query(Query::query(
+            Name::from_str("www.example.com.").unwrap(),
+            RecordType::A,
+        ));
+        let mut response = Message::new();
+        response.set_message_type(MessageType::Response);
+        response.add_query(Query::query(
+            Name::from_str("wWw.ExAmple.COM.").unwrap(),
+            RecordType::A,
+        ));
+        assert_eq!(
+            DnsServer::get_message_key(&query, Upstream::Trusted),
+            DnsServer::get_message_key(&response, Upstream::Trusted)
+        );
+        assert_eq!(query.queries().first(), response.queries().first());
+    }
+
+    #[test]
+    fn test_expired_client() {
+        let now = Instant::now();
+        let client = PendingClient {
+            listener: 0,
+            address: "127.0.0.1:5353".parse().unwrap(),
+            id: 1,
+            query_time: now,
+            max_size: 512,
+        };
+        let window = Duration::from_secs(10);
+        assert!(!client.is_expired(now + Duration::from_secs(3), window));
+        assert!(client.is_expired(now + Duration::from_secs(11), window));
+    }
+
+    #[test]
+    fn test_age_response() {
+        let name = Name::from_str("missing.example.com.").unwrap();
+        let zone = Name::from_str("example.com.").unwrap();
+        let soa = SOA::new(
+            Name::from_str("ns.example.com.").unwrap(),
+            Name::from_str("admin.example.com.").unwrap(),
+            1,
+            3600,
+            600,
+            86400,
+            300,
+        );
+        let mut response = Message::new();
+        response.set_message_type(MessageType::Response);
+        response.add_query(Query::query(name, RecordType::A));
+        response.add_name_server(Record::from_rdata(zone, 300, RData::SOA(soa)));
+
+        let aged = age_response(&response, 100);
+        assert_eq!(aged.name_servers().len(), 1);
+        assert_eq!(aged.name_servers()[0].ttl(), 200);
+        assert_eq!(aged.name_servers()[0].record_type(), RecordType::SOA);
+        assert_eq!(age_response(&response, 400).name_servers()[0].ttl(), 0);
+        assert_eq!(response.name_servers()[0].ttl(), 300);
+    }
+
+    #[test]
+    fn test_client_ids() {
+        let now = Instant::now();
+        let clients: Vec<PendingClient> = [(5353, 11), (5354, 22)]
+            .iter()
+            .map(|(port, id)| PendingClient {
+                listener: 0,
+                address: format!("127.0.0.1:{}", port).parse().unwrap(),
+                id: *id,
+                query_time: now,
+                max_size: 512,
+            })
+            .collect();
+        let mut message = Message::new();
+        message.set_id(1);
+        message.set_message_type(MessageType::Response);
+        message.add_query(Query::query(
+            Name::from_str("example.com.").unwrap(),
+            RecordType::A,
+        ));
+        for client in &clients {
+            let response = Message::from_vec(&client.response(&mut message)).unwrap();
+            assert_eq!(response.id(), client.id);
+        }
+    }
+
+    #[test]
+    fn test_stale_response() {
+        let name = Name::from_str("example.com.").unwrap();
+        let mut cached = Message::new();
+        cached.set_message_type(MessageType::Response);
+        cached.add_query(Query::query(name.clone(), RecordType::A));
+        cached.add_answer(Record::from_rdata(
+            name,
+            3600,
+            RData::A("1.2.3.4".parse().unwrap()),
+        ));
+
+        let expired = age_response(&cached, 7200);
+        assert_eq!(expired.answers()[0].ttl(), 0);
+        let stale = stale_response(&expired);
+        assert_eq!(stale.answers().len(), 1);
+        assert_eq!(stale.answers()[0].ttl(), 30);
+        assert_eq!(stale.answers()[0].data(), cached.answers()[0].data());
+    }
+
+    #[test]
+    fn test_clamp_ttl() {
+        let day = 86400;
+        assert_eq!(clamp_ttl(day, 0, 0), day);
+        assert_eq!(clamp_ttl(day, 0, 3600), 3600);
+        assert_eq!(clamp_ttl(300, 0, 3600), 300);
+        assert_eq!(clamp_ttl(10, 60, 0), 60);
+        assert_eq!(clamp_ttl(10, 60, 3600), 60);
+        // the max wins over a higher min
+        assert_eq!(clamp_ttl(10, 600, 300), 300);
+        assert_eq!(clamp_ttl(day, 600, 300), 300);
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_min_ttl() {
+        let (mut server, _) = test_server(&["--dns-min-ttl", "600", "--dns-max-ttl", "900"]);
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let from = "127.0.0.1:5353".parse().unwrap();
+        let now = Instant::now();
+        server.handle_query(0, from, &data, Message::from_vec(&data).unwrap(), now);
+        // the 300 seconds answer is raised to 600 seconds
+        server.handle_response(answer_first(&data, "1.2.3.4"), Upstream::Poisoned, now);
+        let result = &server.store["example.com.|A|poisoned"];
+        assert_eq!(result.expire_time, now + Duration::from_secs(600));
+    }
+
+    /// Server listening on a random loopback port, with `args` appended to the command line
+    fn test_server(args: &[&str]) -> (DnsServer, Poll) {
+        let mut command = vec![
+            "dns",
+            "--tun-name",
+            "test",
+            "--dns-listen-address",
+            "127.0.0.1:0",
+            "--trusted-dns",
+            "127.0.0.1",
+            "--poisoned-dns",
+            "127.0.0.1",
+        ];
+        command.extend_from_slice(args);
+        let args = DnsArgs::parse_from(command);
+        let (route_sender, _) = sync_channel(1);
+        let mut server = DnsServerBuilder::new(&args, route_sender).build().unwrap();
+        let poll = Poll::new().unwrap();
+        server.setup(&poll).unwrap();
+        (server, poll)
+    }
+
+    /// Server forced onto the trusted upstream for loopback clients and adding routes, with
+    /// `args` appended to the command line, and the receiver of its route events.
+    fn trusted_server(args: &[&str]) -> (DnsServer, Poll, Receiver<RouteEvent>) {
+        let mut command = vec!["--add-route", "--client-policy", "127.0.0.1=tunnel"];
+        command.extend_from_slice(args);
+        let (mut server, poll) = test_server(&command);
+        let (route_sender, receiver) = sync_channel(4);
+        server.route_sender = RouteSender::new(route_sender, RoutePolicy::DropNewest);
+        (server, poll, receiver)
+    }
+
+    /// Send `data` to the server, let it handle the datagram and return the reply if any.
+    fn exchange(server: &mut DnsServer, poll: &mut Poll, data: &[u8]) -> Option<Message> {
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        client
+            .send_to(data, server.listeners[0].local_addr().unwrap())
+            .unwrap();
+        let mut events = Events::with_capacity(16);
+        poll.poll(&mut events, Some(Duration::from_secs(1)))
+            .unwrap();
+        for event in &events {
+            server.ready(event, poll);
+        }
+        let mut buffer = [0u8; 512];
+        let (length, _) = client.recv_from(&mut buffer).ok()?;
+        Some(Message::from_vec(&buffer[..length]).unwrap())
+    }
+
+    fn query(name: &str, query_type: RecordType) -> Message {
+        let mut query = Message::new();
+        query.set_id(9);
+        query.add_query(Query::query(Name::from_str(name).unwrap(), query_type));
+        query
+    }
+
+    #[test]
+    fn test_engine_without_wintun() {
+        let (mut server, mut poll) = test_server(&[]);
+        let query = query("example.com.", RecordType::ANY);
+        let response = exchange(&mut server, &mut poll, &query.to_vec().unwrap()).unwrap();
+        assert_eq!(response.id(), 9);
+        assert_eq!(response.answers()[0].record_type(), RecordType::HINFO);
+    }
+
+    #[test]
+    fn test_rebind_listener() {
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        #[cfg(windows)]
+        assert!(bind_listener(addr).is_err());
+        drop(listener);
+        let listener = bind_listener(addr).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn test_query_before_setup() {
+        let args = DnsArgs::parse_from([
+            "dns",
+            "--tun-name",
+            "test",
+            "--dns-listen-address",
+            "127.0.0.1:0",
+        ]);
+        let mut server = DnsServerBuilder::new(&args, sync_channel(1).0)
+            .build()
+            .unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        let from = client.local_addr().unwrap();
+        server.handle_query(0, from, &data, request, Instant::now());
+        let mut buffer = [0u8; 512];
+        let (length, _) = client.recv_from(&mut buffer).unwrap();
+        let response = Message::from_vec(&buffer[..length]).unwrap();
+        assert_eq!(response.id(), 9);
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+        assert!(server.store.is_empty());
+    }
+
+    #[test]
+    fn test_empty_datagram() {
+        let (mut server, mut poll) = test_server(&[]);
+        assert!(exchange(&mut server, &mut poll, &[]).is_none());
+        assert_eq!(server.empty_datagrams, 1);
+    }
+
+    #[test]
+    fn test_reply_formerr() {
+        let (mut server, mut poll) = test_server(&["--reply-formerr"]);
+        // the header announces a question which is missing
+        let mut data = query("example.com.", RecordType::A).to_vec().unwrap();
+        data.truncate(12);
+        let response = exchange(&mut server, &mut poll, &data).unwrap();
+        assert_eq!(response.id(), 9);
+        assert_eq!(response.response_code(), ResponseCode::FormErr);
+        assert!(exchange(&mut server, &mut poll, &[0xde, 0xad, 0xbe]).is_none());
+    }
+
+    #[test]
+    fn test_force_direct() {
+        let blocked = env::temp_dir().join("trojan_test_blocked.txt");
+        let direct = env::temp_dir().join("trojan_test_direct.txt");
+        fs::write(&blocked, "mirror.example.com\nblocked.org\n").unwrap();
+        fs::write(&direct, "mirror.example.com\n").unwrap();
+        let (server, _) = test_server(&[
+            "--blocked-domain-list",
+            blocked.to_str().unwrap(),
+            "--force-direct-list",
+            direct.to_str().unwrap(),
+        ]);
+        fs::remove_file(blocked).unwrap();
+        fs::remove_file(direct).unwrap();
+        let client = "127.0.0.1".parse().unwrap();
+        assert!(!server.is_blocked("mirror.example.com.", client));
+        assert!(server.is_blocked("blocked.org.", client));
+    }
+
+    #[test]
+    fn test_suppress_aaaa() {
+        let (mut server, mut poll) = test_server(&["--suppress-aaaa"]);
+        let query = query("example.com.", RecordType::AAAA);
+        let response = exchange(&mut server, &mut poll, &query.to_vec().unwrap()).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+        assert_eq!(response.name_servers()[0].record_type(), RecordType::SOA);
+        assert!(server.store.is_empty());
+    }
+
+    #[test]
+    fn test_long_name() {
+        let (mut server, mut poll) = test_server(&["--max-name-length", "32"]);
+        let name = format!("{}.example.com.", "a".repeat(40));
+        let query = query(name.as_str(), RecordType::A);
+        let response = exchange(&mut server, &mut poll, &query.to_vec().unwrap()).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::FormErr);
+        assert!(server.store.is_empty());
+
+        // rejected before multi-query handling and passing through
+        let args = ["--max-name-length", "32", "--multi-query-mode", "first"];
+        let (mut server, mut poll) = test_server(&args);
+        let data = multi_query(&["example.com.", name.as_str()]);
+        let response = exchange(&mut server, &mut poll, &data).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::FormErr);
+        assert!(server.store.is_empty());
+        let args = ["--max-name-length", "32", "--downstream-dns", "127.0.0.1"];
+        let (mut server, mut poll) = test_server(&args);
+        let response = exchange(&mut server, &mut poll, &query.to_vec().unwrap()).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::FormErr);
+        assert!(server.store.is_empty());
+    }
+
+    #[test]
+    fn test_upstream_down() {
+        let (mut server, _) = test_server(&[]);
+        let now = Instant::now();
+        assert!(server.health(now).is_healthy());
+        let from = "127.0.0.1:5353".parse().unwrap();
+        for name in ["a.example.com.", "b.example.com.", "c.example.com."] {
+            let data = query(name, RecordType::A).to_vec().unwrap();
+            let message = Message::from_vec(&data).unwrap();
+            server.handle_query(0, from, data.as_slice(), message, now);
+        }
+        // nothing answers, every query times out
+        server.check_timeout(now + QUERY_TIMEOUT);
+        let status = server.health(now + QUERY_TIMEOUT);
+        assert!(status.listening);
+        assert!(!status.poisoned_up);
+        assert!(!status.recent_success);
+        assert!(!status.is_healthy());
+    }
+
+    #[test]
+    fn test_srv_map() {
+        let (mut server, mut poll) =
+            test_server(&["--srv-map", "_ldap._tcp.corp.local=dc.corp.local:389"]);
+        let query = query("_LDAP._tcp.corp.local.", RecordType::SRV);
+        let response = exchange(&mut server, &mut poll, &query.to_vec().unwrap()).unwrap();
+        match response.answers()[0].data() {
+            Some(RData::SRV(srv)) => {
+                assert_eq!(srv.target().to_utf8(), "dc.corp.local.");
+                assert_eq!(srv.port(), 389);
+            }
+            data => panic!("unexpected answer:{:?}", data),
+        }
+        assert!(server.store.is_empty());
+    }
+
+    /// Answer a trusted query for `name` with `addresses`, as if upstream responded.
+    fn answer_trusted(server: &mut DnsServer, name: &str, addresses: &[&str]) {
+        let now = Instant::now();
+        let data = query(name, RecordType::A).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        server.handle_query(0, "127.0.0.1:5353".parse().unwrap(), &data, request, now);
+        let mut response = Message::from_vec(&data).unwrap();
+        response.set_message_type(MessageType::Response);
+        for address in addresses {
+            response.add_answer(Record::from_rdata(
+                Name::from_str(name).unwrap(),
+                300,
+                RData::A(address.parse().unwrap()),
+            ));
+        }
+        server.handle_response(response, Upstream::Trusted, now);
+    }
+
+    #[test]
+    fn test_route_channel() {
+        let (mut server, _, _routes) = trusted_server(&[]);
+        let (route_sender, receiver) = sync_channel(1);
+        server.route_sender = RouteSender::new(route_sender, RoutePolicy::DropNewest);
+        answer_trusted(&mut server, "example.com.", &["1.2.3.4", "1.2.3.5"]);
+        assert_eq!(server.dropped_routes, 1);
+        assert!(!server.route_disconnected);
+        assert_eq!(receiver.try_recv().unwrap().ip.to_string(), "1.2.3.4");
+
+        drop(receiver);
+        answer_trusted(&mut server, "example.org.", &["1.2.3.6"]);
+        assert_eq!(server.dropped_routes, 1);
+        assert!(server.route_disconnected);
+        assert!(!server.health(Instant::now()).routing);
+    }
+
+    #[test]
+    fn test_route_event() {
+        let (mut server, _, receiver) = trusted_server(&[]);
+        answer_trusted(&mut server, "Example.com.", &["1.2.3.4"]);
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.ip.to_string(), "1.2.3.4");
+        assert_eq!(event.domain, "example.com.");
+        assert_eq!(event.source, Upstream::Trusted);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_reroute_on_cache_hit() {
+        let (mut server, _, receiver) = trusted_server(&["--reroute-on-cache-hit"]);
+        answer_trusted(&mut server, "example.com.", &["1.2.3.4"]);
+        assert_eq!(receiver.try_recv().unwrap().ip.to_string(), "1.2.3.4");
+
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        let from = "127.0.0.1:5353".parse().unwrap();
+        server.handle_query(0, from, &data, request, Instant::now());
+        assert_eq!(server.cache_metrics.cache_hit, 1);
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.ip.to_string(), "1.2.3.4");
+        assert_eq!(event.domain, "example.com.");
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_route_cname() {
+        let (mut server, _, receiver) = trusted_server(&[]);
+        let now = Instant::now();
+        let data = query("www.example.com.", RecordType::A).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        server.handle_query(0, "127.0.0.1:5353".parse().unwrap(), &data, request, now);
+        let mut response = Message::from_vec(&data).unwrap();
+        response.set_message_type(MessageType::Response);
+        let name = |name: &str| Name::from_str(name).unwrap();
+        response.add_answer(Record::from_rdata(
+            name("WWW.example.com."),
+            300,
+            RData::CNAME(name("edge.cdn.net.")),
+        ));
+        response.add_answer(Record::from_rdata(
+            name("edge.cdn.net."),
+            300,
+            RData::CNAME(name("a1.edge.cdn.net.")),
+        ));
+        response.add_answer(Record::from_rdata(
+            name("a1.edge.cdn.net."),
+            300,
+            RData::A("1.2.3.4".parse().unwrap()),
+        ));
+        server.handle_response(response, Upstream::Trusted, now);
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.ip.to_string(), "1.2.3.4");
+        assert_eq!(event.domain, "www.example.com.");
+        assert_eq!(event.cname.as_deref(), Some("a1.edge.cdn.net."));
+
+        answer_trusted(&mut server, "example.org.", &["1.2.3.5"]);
+        assert_eq!(receiver.try_recv().unwrap().cname, None);
+    }
+
+    #[test]
+    fn test_route_failure() {
+        let (mut server, _, _routes) = trusted_server(&[]);
+        let (route_sender, route_failures) = route::spawn(
+            |_| Err(TrojanError::Winapi("route add access denied".into())),
+            0,
+            None,
+            Duration::ZERO,
+        );
+        server.route_sender = RouteSender::new(route_sender, RoutePolicy::DropNewest);
+        server.route_failures = Some(route_failures);
+        answer_trusted(&mut server, "example.com.", &["1.2.3.4"]);
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while server.failed_routes == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+            server.check_timeout(Instant::now());
+        }
+        assert_eq!(server.failed_routes, 1);
+    }
+
+    #[test]
+    fn test_tunnel_by_domain() {
+        let fake_ips = env::temp_dir().join("trojan_test_tunnel_by_domain.csv");
+        let fake_ips = fake_ips.to_str().unwrap();
+        let args = [
+            "--tunnel-by-domain",
+            "--fake-ip-file",
+            fake_ips,
+            "--add-route",
+            "--blocked-domains-inline",
+            "blocked.org",
+        ];
+        let (mut server, mut poll) = test_server(&args);
+        let (route_sender, receiver) = sync_channel(4);
+        server.route_sender = RouteSender::new(route_sender, RoutePolicy::DropNewest);
+        let clock = FakeClock::new();
+        server.clock = Box::new(clock.clone());
+        let data = query("www.blocked.org.", RecordType::A).to_vec().unwrap();
+        let response = exchange(&mut server, &mut poll, &data).unwrap();
+        let ip = match response.answers()[0].data() {
+            Some(RData::A(ip)) => *ip,
+            data => panic!("unexpected answer:{:?}", data),
+        };
+        assert_eq!(ip.octets()[..2], [198, 18]);
+        assert!(server.store.is_empty());
+        assert_eq!(receiver.try_recv().unwrap().ip, IpAddr::from(ip));
+        // the same name keeps its address
+        let response = exchange(&mut server, &mut poll, &data).unwrap();
+        assert_eq!(response.answers()[0].data(), Some(&RData::A(ip)));
+        let data = query("www.blocked.org.", RecordType::AAAA)
+            .to_vec()
+            .unwrap();
+        let response = exchange(&mut server, &mut poll, &data).unwrap();
+        assert!(response.answers().is_empty());
+
+        let content = fs::read_to_string(fake_ips).unwrap();
+        assert!(content.contains(&format!("{},www.blocked.org", ip)));
+        // names following right after are dumped once the interval passed
+        let data = query("mail.blocked.org.", RecordType::A).to_vec().unwrap();
+        exchange(&mut server, &mut poll, &data).unwrap();
+        let dumped = || {
+            fs::read_to_string(fake_ips)
+                .unwrap()
+                .contains("mail.blocked.org")
+        };
+        assert!(!dumped());
+        server.check_timeout(clock.now());
+        assert!(!dumped());
+        clock.advance(Duration::from_secs(1));
+        server.check_timeout(clock.now());
+        assert!(dumped());
+        fs::remove_file(fake_ips).unwrap();
+    }
+
+    #[test]
+    fn test_servfail_not_cached() {
+        let (mut server, _) = test_server(&[]);
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let from = "127.0.0.1:5353".parse().unwrap();
+        let now = Instant::now();
+        let request = Message::from_vec(&data).unwrap();
+        server.handle_query(0, from, &data, request, now);
+        let mut response = Message::from_vec(&data).unwrap();
+        response.set_message_type(MessageType::Response);
+        response.set_response_code(ResponseCode::ServFail);
+        server.handle_response(response, Upstream::Poisoned, now);
+        assert!(!server.store.contains_key("example.com.|A|poisoned"));
+
+        // the next query goes upstream instead of getting the cached failure
+        let request = Message::from_vec(&data).unwrap();
+        server.handle_query(0, from, &data, request, now + Duration::from_secs(1));
+        assert_eq!(server.cache_metrics.cache_miss, 2);
+        assert!(server.store["example.com.|A|poisoned"].pending);
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_servfail_serves_stale() {
+        let (mut server, _) = test_server(&["--dns-retries", "1"]);
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let from = "127.0.0.1:5353".parse().unwrap();
+        let now = Instant::now();
+        let key = "example.com.|A|poisoned";
+        server.handle_query(0, from, &data, Message::from_vec(&data).unwrap(), now);
+        server.handle_response(answer_first(&data, "1.2.3.4"), Upstream::Poisoned, now);
+
+        // the expired answer is refreshed, upstream fails
+        let now = now + Duration::from_secs(400);
+        server.handle_query(0, from, &data, Message::from_vec(&data).unwrap(), now);
+        assert!(server.store[key].pending);
+        let mut response = Message::from_vec(&data).unwrap();
+        response.set_message_type(MessageType::Response);
+        response.set_response_code(ResponseCode::ServFail);
+        server.handle_response(response, Upstream::Poisoned, now);
+        let result = &server.store[key];
+        assert!(!result.pending && result.answered);
+        server.check_timeout(now + QUERY_TIMEOUT);
+        assert_eq!(server.store[key].retries, 0);
+        assert!(!server.poisoned_health.is_failing());
+
+        // the next refresh gets a good answer, which replaces the stale one
+        let now = now + QUERY_TIMEOUT;
+        server.handle_query(0, from, &data, Message::from_vec(&data).unwrap(), now);
+        server.handle_response(answer_first(&data, "1.2.3.5"), Upstream::Poisoned, now);
+        let result = &server.store[key];
+        assert!(result.expire_time > now);
+        assert_eq!(
+            result.response.as_ref().unwrap().answers(),
+            answer_first(&data, "1.2.3.5").answers()
+        );
+    }
+
+    #[test]
+    fn test_route_trusted_answers_off() {
+        let (mut server, _, receiver) = trusted_server(&["--route-trusted-answers", "false"]);
+        answer_trusted(&mut server, "example.com.", &["1.2.3.4"]);
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(server.dropped_routes, 0);
+    }
+
+    #[test]
+    fn test_padding() {
+        let (mut server, _, _routes) = trusted_server(&["--dns-padding", "128"]);
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        let from = "127.0.0.1:5353".parse().unwrap();
+        server.handle_query(0, from, &data, request, Instant::now());
+        let result = &server.store["example.com.|A|trusted"];
+        assert_eq!(result.query.len(), 128);
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_minimal_response() {
+        let (mut server, _) = test_server(&["--minimal-responses"]);
+        let name = Name::from_str("example.com.").unwrap();
+        let now = Instant::now();
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        server.handle_query(0, "127.0.0.1:5353".parse().unwrap(), &data, request, now);
+        let mut response = Message::from_vec(&data).unwrap();
+        response.set_message_type(MessageType::Response);
+        let address = RData::A("1.2.3.4".parse().unwrap());
+        response.add_answer(Record::from_rdata(name.clone(), 300, address.clone()));
+        for _ in 0..20 {
+            response.add_name_server(Record::from_rdata(
+                name.clone(),
+                300,
+                RData::NS(name.clone()),
+            ));
+            response.add_additional(Record::from_rdata(name.clone(), 300, address.clone()));
+        }
+        let soa = SOA::new(name.clone(), name.clone(), 1, 3600, 600, 86400, 300);
+        response.add_name_server(Record::from_rdata(name, 300, RData::SOA(soa)));
+        server.handle_response(response, Upstream::Poisoned, now);
+
+        let cached = server.store["example.com.|A|poisoned"]
+            .response
+            .as_ref()
+            .unwrap();
+        assert_eq!(cached.answers().len(), 1);
+        assert!(cached.additionals().is_empty());
+        assert_eq!(cached.name_servers().len(), 1);
+        assert_eq!(cached.name_servers()[0].record_type(), RecordType::SOA);
+        let sent = Message::from_vec(&cached.to_vec().unwrap()).unwrap();
+        assert_eq!(sent.header().additional_count(), 0);
+    }
+
+    #[test]
+    fn test_inline_blocked_domains() {
+        let blocked = env::temp_dir().join("trojan_test_inline_blocked.txt");
+        fs::write(&blocked, "blocked.org\n").unwrap();
+        let (server, _) = test_server(&[
+            "--blocked-domain-list",
+            blocked.to_str().unwrap(),
+            "--blocked-domains-inline",
+            "inline.example.com",
+        ]);
+        fs::remove_file(blocked).unwrap();
+        let client = "127.0.0.1".parse().unwrap();
+        assert!(server.is_blocked("blocked.org.", client));
+        assert!(server.is_blocked("www.inline.example.com.", client));
+        assert!(!server.is_blocked("example.net.", client));
+
+        let (server, _) = test_server(&["--blocked-domain-list", ""]);
+        assert!(!server.is_blocked("blocked.org.", client));
+    }
+
+    #[test]
+    fn test_reload_blocked_domains() {
+        let count = 20000;
+        let list = |prefix: &str| {
+            (0..count)
+                .map(|i| format!("{}{}.example.com\n", prefix, i))
+                .collect::<String>()
+        };
+        let blocked = env::temp_dir().join("trojan_test_reload_blocked.txt");
+        fs::write(&blocked, list("old")).unwrap();
+        let (server, _) = test_server(&["--blocked-domain-list", blocked.to_str().unwrap()]);
+        fs::write(&blocked, list("new")).unwrap();
+        let client = "127.0.0.1".parse().unwrap();
+        let last = count - 1;
+        let reload = server.reload_blocked_domains().unwrap();
+        // a second reload is refused while the first one runs
+        assert!(server.reload_blocked_domains().is_none());
+        let mut reloaded = false;
+        loop {
+            let finished = reload.is_finished();
+            {
+                let domains = server.blocked_domains.read().unwrap();
+                let old = domains.contains("old0.example.com.");
+                assert_eq!(old, domains.contains(&format!("old{}.example.com.", last)));
+                assert_eq!(!old, domains.contains("new0.example.com."));
+                assert_eq!(!old, domains.contains(&format!("new{}.example.com.", last)));
+            }
+            // the old list never comes back once the new one is seen
+            if server.is_blocked("new0.example.com.", client) {
+                reloaded = true;
+            } else {
+                assert!(!reloaded);
+            }
+            if finished {
+                break;
+            }
+        }
+        // the list can be reloaded again once the previous reload is done
+        server.reload_blocked_domains().unwrap().join().unwrap();
+        fs::remove_file(blocked).unwrap();
+        assert!(reloaded);
+        assert!(!server.is_blocked("old0.example.com.", client));
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_flush_cache_query() {
+        let (mut server, mut poll, _routes) = trusted_server(&[]);
+        answer_trusted(&mut server, "example.com.", &["1.2.3.4"]);
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let response = exchange(&mut server, &mut poll, &data).unwrap();
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(server.cache_metrics.cache_hit, 1);
+
+        let flush = query("flush.trojan.dns.", RecordType::A).to_vec().unwrap();
+        let response = exchange(&mut server, &mut poll, &flush).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(server.store.is_empty());
+        let misses = server.cache_metrics.cache_miss;
+        let request = Message::from_vec(&data).unwrap();
+        let from = "127.0.0.1:5353".parse().unwrap();
+        server.handle_query(0, from, &data, request, Instant::now());
+        assert_eq!(server.cache_metrics.cache_hit, 1);
+        assert_eq!(server.cache_metrics.cache_miss, misses + 1);
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_promotion_evicts_subdomains() {
+        let (mut server, _) = test_server(&["--blocked-domain-list", ""]);
+        let (promotions, receiver) = channel();
+        server.promotions = Some(receiver);
+        let from = "127.0.0.1:5353".parse().unwrap();
+        let now = Instant::now();
+        for name in ["example.com.", "www.example.com.", "example.org."] {
+            let data = query(name, RecordType::A).to_vec().unwrap();
+            server.handle_query(0, from, &data, Message::from_vec(&data).unwrap(), now);
+            server.handle_response(answer_first(&data, "10.0.0.1"), Upstream::Poisoned, now);
+        }
+        promotions.send("Example.com".into()).unwrap();
+        server.handle_promotions();
+        assert!(server.is_blocked("www.example.com.", from.ip()));
+        assert!(!server.store.contains_key("example.com.|A|poisoned"));
+        assert!(!server.store.contains_key("www.example.com.|A|poisoned"));
+        assert!(server.store.contains_key("example.org.|A|poisoned"));
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_reload_invalidates_cache() {
+        let blocked = env::temp_dir().join("trojan_test_reload_invalidates.txt");
+        fs::write(&blocked, "other.org\n").unwrap();
+        let (mut server, _) = test_server(&["--blocked-domain-list", blocked.to_str().unwrap()]);
+        let data = query("example.org.", RecordType::A).to_vec().unwrap();
+        let from = "127.0.0.1:5353".parse().unwrap();
+        let now = Instant::now();
+        let request = Message::from_vec(&data).unwrap();
+        server.handle_query(0, from, &data, request, now);
+        let mut response = Message::from_vec(&data).unwrap();
+        response.set_message_type(MessageType::Response);
+        response.add_answer(Record::from_rdata(
+            Name::from_str("example.org.").unwrap(),
+            300,
+            RData::A("93.46.8.90".parse().unwrap()),
+        ));
+        server.handle_response(response, Upstream::Poisoned, now);
+        assert!(server.store["example.org.|A|poisoned"].response.is_some());
+
+        fs::write(&blocked, "other.org\nexample.org\n").unwrap();
+        server.reload_blocked_domains().unwrap().join().unwrap();
+        fs::remove_file(blocked).unwrap();
+        server.handle_reloads();
+        assert!(!server.store.contains_key("example.org.|A|poisoned"));
+
+        let request = Message::from_vec(&data).unwrap();
+        server.handle_query(0, from, &data, request, now);
+        assert!(server.store["example.org.|A|trusted"].pending);
+    }
+
+    #[test]
+    fn test_reconfigure_upstreams() {
+        let (mut server, _) = test_server(&[]);
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        let from = "127.0.0.1:5353".parse().unwrap();
+        server.handle_query(0, from, &data, request, Instant::now());
+
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let addr = upstream.local_addr().unwrap();
+        server.reconfigure_upstreams(addr, addr);
+        // the in-flight query is sent again to the new upstream
+        let mut buffer = [0u8; 512];
+        let (length, _) = upstream.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], data.as_slice());
+
+        let data = query("example.org.", RecordType::A).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        server.handle_query(0, from, &data, request, Instant::now());
+        let (length, _) = upstream.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], data.as_slice());
+    }
+
+    #[test]
+    fn test_ipv6_upstream() {
+        let (mut server, mut poll) = test_server(&[]);
+        let upstream = UdpSocket::bind("[::1]:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let addr = upstream.local_addr().unwrap();
+        server.reconfigure_upstreams(addr, addr);
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        server.handle_query(
+            0,
+            client.local_addr().unwrap(),
+            &data,
+            request,
+            Instant::now(),
+        );
+        let mut buffer = [0u8; 512];
+        let (length, from) = upstream.recv_from(&mut buffer).unwrap();
+        assert!(from.is_ipv6());
+        let response = answer_first(&buffer[..length], "1.2.3.4");
+        upstream.send_to(&response.to_vec().unwrap(), from).unwrap();
+
+        let mut events = Events::with_capacity(16);
+        poll.poll(&mut events, Some(Duration::from_secs(1)))
+            .unwrap();
+        for event in &events {
+            server.ready(event, &poll);
+        }
+        let (length, _) = client.recv_from(&mut buffer).unwrap();
+        let relayed = Message::from_vec(&buffer[..length]).unwrap();
+        assert_eq!(relayed.answers(), response.answers());
+    }
+
+    #[test]
+    fn test_retransmission() {
+        let args = ["--dns-retries", "2", "--dns-retry-interval-ms", "500"];
+        let (mut server, _) = test_server(&args);
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let addr = upstream.local_addr().unwrap();
+        server.reconfigure_upstreams(addr, addr);
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        let now = Instant::now();
+        server.handle_query(0, "127.0.0.1:5353".parse().unwrap(), &data, request, now);
+
+        // whether the query reached the upstream by `elapsed` milliseconds
+        let sent = |server: &mut DnsServer, elapsed: u64| {
+            server.check_timeout(now + Duration::from_millis(elapsed));
+            upstream.recv_from(&mut [0u8; 512]).is_ok()
+        };
+        assert!(sent(&mut server, 0));
+        assert!(!sent(&mut server, 400));
+        assert!(sent(&mut server, 500));
+        assert!(sent(&mut server, 1000));
+        assert!(!sent(&mut server, 1500));
+        assert!(!sent(&mut server, 5999));
+        assert!(server.store["example.com.|A|poisoned"].pending);
+        assert!(!sent(&mut server, 6000));
+        let result = &server.store["example.com.|A|poisoned"];
+        assert!(!result.pending);
+        assert_eq!(result.retries, 2);
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_proactive_refresh() {
+        let (mut server, _, poisoned) = stub_upstream_server(&["--proactive-refresh", "1"]);
+        let client = "127.0.0.1:5353".parse().unwrap();
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let now = Instant::now();
+        let mut buffer = [0u8; 512];
+        server.handle_query(0, client, &data, Message::from_vec(&data).unwrap(), now);
+        assert!(poisoned.recv_from(&mut buffer).is_ok());
+        server.handle_response(answer_first(&data, "1.2.3.4"), Upstream::Poisoned, now);
+        // one cache hit makes the entry hot
+        server.handle_query(0, client, &data, Message::from_vec(&data).unwrap(), now);
+        assert!(poisoned.recv_from(&mut buffer).is_err());
+
+        server.check_timeout(now + Duration::from_secs(200));
+        assert!(poisoned.recv_from(&mut buffer).is_err());
+        // 80% of the 300 seconds TTL passed
+        server.check_timeout(now + Duration::from_secs(250));
+        let (length, _) = poisoned.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], data.as_slice());
+        server.check_timeout(now + Duration::from_secs(251));
+        assert!(poisoned.recv_from(&mut buffer).is_err());
+        assert!(server.store["example.com.|A|poisoned"].pending);
+    }
+
+    /// Clock tests move forward by hand, clones share the time
+    #[derive(Clone)]
+    struct FakeClock(Rc<Cell<Instant>>);
+
+    impl FakeClock {
+        fn new() -> Self {
+            Self(Rc::new(Cell::new(Instant::now())))
+        }
+
+        fn advance(&self, duration: Duration) {
+            self.0.set(self.0.get() + duration);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+
+        fn local_minute(&self) -> u32 {
+            0
+        }
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_fake_clock() {
+        let (mut server, mut poll, _routes) = trusted_server(&[]);
+        let clock = FakeClock::new();
+        server.clock = Box::new(clock.clone());
+        answer_trusted(&mut server, "example.com.", &["1.2.3.4"]);
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        exchange(&mut server, &mut poll, &data).unwrap();
+        assert_eq!(server.cache_metrics.cache_hit, 1);
+        assert!(!server.store["example.com.|A|trusted"].pending);
+
+        // the 300 seconds answer expires without waiting for it
+        clock.advance(Duration::from_secs(301));
+        exchange(&mut server, &mut poll, &data).unwrap();
+        assert_eq!(server.cache_metrics.cache_expired_refresh, 1);
+        assert!(server.store["example.com.|A|trusted"].pending);
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_lookup_cache() {
+        let (mut server, _, _routes) = trusted_server(&[]);
+        let clock = FakeClock::new();
+        server.clock = Box::new(clock.clone());
+        assert!(server.lookup_cache("example.com").is_none());
+        answer_trusted(&mut server, "example.com.", &["1.2.3.4", "1.2.3.5"]);
+        clock.advance(Duration::from_secs(10));
+        let info = server.lookup_cache("Example.com").unwrap();
+        assert_eq!(info.source, Upstream::Trusted);
+        let addresses: Vec<IpAddr> = vec!["1.2.3.4".parse().unwrap(), "1.2.3.5".parse().unwrap()];
+        assert_eq!(info.addresses, addresses);
+        // cached a moment after the fake clock started
+        assert!(info.age > Duration::from_secs(9));
+        assert!(info.fresh);
+
+        clock.advance(Duration::from_secs(300));
+        assert!(!server.lookup_cache("example.com.").unwrap().fresh);
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_builder() {
+        let args = DnsArgs::parse_from([
+            "dns",
+            "--tun-name",
+            "test",
+            "--dns-listen-address",
+            "127.0.0.1:0",
+            "--client-policy",
+            "127.0.0.1=tunnel",
+        ]);
+        let clock = FakeClock::new();
+        let (route_sender, _) = sync_channel(1);
+        let mut server = DnsServerBuilder::new(&args, route_sender)
+            .upstreams("127.0.0.1", "127.0.0.2")
+            .cache_time(5)
+            .ttl_bounds(0, 30)
+            .build()
+            .unwrap();
+        server.clock = Box::new(clock.clone());
+        let mut poll = Poll::new().unwrap();
+        server.setup(&poll).unwrap();
+        assert_eq!(
+            server.trusted.server_addr(),
+            "127.0.0.1:53".parse().unwrap()
+        );
+        assert_eq!(server.args.dns_cache_time, 5);
+
+        answer_trusted(&mut server, "example.com.", &["1.2.3.4"]);
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let response = exchange(&mut server, &mut poll, &data).unwrap();
+        assert_eq!(response.answers()[0].ttl(), 30);
+        // the 300 seconds answer is capped to 30 seconds
+        clock.advance(Duration::from_secs(31));
+        exchange(&mut server, &mut poll, &data).unwrap();
+        assert_eq!(server.cache_metrics.cache_expired_refresh, 1);
+
+        // bad settings are reported instead of panicking
+        let invalid = DnsServerBuilder::new(&args, sync_channel(1).0)
+            .upstreams("quic://1.1.1.1", "127.0.0.1")
+            .build();
+        assert!(matches!(invalid, Err(TrojanError::InvalidConfig(_))));
+        let args = DnsArgs::parse_from([
+            "dns",
+            "--tun-name",
+            "test",
+            "--dns-listen-address",
+            "127.0.0.1:0",
+            "--allowed-clients",
+            "lan",
+        ]);
+        let mut server = DnsServerBuilder::new(&args, sync_channel(1).0)
+            .build()
+            .unwrap();
+        let result = server.setup(&Poll::new().unwrap());
+        assert!(matches!(result, Err(TrojanError::InvalidConfig(_))));
+        assert!(!server.ready);
+    }
+
+    /// Logger keeping messages in memory, so tests can inspect them
+    struct CaptureLogger(Mutex<Vec<String>>);
+
+    impl Log for CaptureLogger {
+        fn enabled(&self, _: &Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &LogRecord) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// The logger capturing messages of every test, it can only be installed once
+    fn capture_logger() -> &'static CaptureLogger {
+        lazy_static::lazy_static! {
+            static ref LOGGER: &'static CaptureLogger = {
+                let logger = Box::leak(Box::new(CaptureLogger(Mutex::new(vec![]))));
+                log::set_logger(logger).unwrap();
+                log::set_max_level(LevelFilter::Info);
+                logger
+            };
+        }
+        &LOGGER
+    }
+
+    #[test]
+    fn test_system_fallback() {
+        let logger = capture_logger();
+        let (mut server, _) = test_server(&[
+            "--system-fallback",
+            "--blocked-domains-inline",
+            "blocked.org",
+        ]);
+        let from = "127.0.0.1:5353".parse().unwrap();
+        let now = Instant::now();
+        let send = |server: &mut DnsServer, name: &str, now: Instant| {
+            let data = query(name, RecordType::A).to_vec().unwrap();
+            server.handle_query(0, from, &data, Message::from_vec(&data).unwrap(), now);
+        };
+        let fallbacks = || {
+            let lines = logger.0.lock().unwrap();
+            lines
+                .iter()
+                .filter(|line| line.contains("fallback to system resolver"))
+                .filter(|line| line.contains("fallback.example.com.") || line.contains("blocked"))
+                .count()
+        };
+        // only poisoned dns fails
+        send(&mut server, "fallback.example.com.", now);
+        server.check_timeout(now + QUERY_TIMEOUT);
+        assert_eq!(fallbacks(), 0);
+
+        let now = now + QUERY_TIMEOUT;
+        send(&mut server, "www.blocked.org.", now);
+        server.check_timeout(now + QUERY_TIMEOUT);
+        assert_eq!(fallbacks(), 1);
+        assert!(server.store["fallback.example.com.|A|poisoned"].fallback);
+        assert!(!server.store["www.blocked.org.|A|trusted"].fallback);
+        server.check_timeout(now + QUERY_TIMEOUT * 2);
+        assert_eq!(fallbacks(), 1);
+    }
+
+    #[test]
+    fn test_debug_hex() {
+        let logger = capture_logger();
+        let (mut server, mut poll) = test_server(&["--dns-debug-hex"]);
+        assert!(exchange(&mut server, &mut poll, &[0xde, 0xad, 0xbe]).is_none());
+        let lines = logger.0.lock().unwrap();
+        assert!(lines
+            .iter()
+            .any(|line| line.contains("3 bytes:deadbe, parse failed")));
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_cached_ech_stripped() {
+        use trust_dns_proto::rr::rdata::{
+            svcb::{EchConfig, SvcParamKey, SvcParamValue},
+            SVCB,
+        };
+
+        let (mut server, mut poll, _routes) =
+            trusted_server(&["--strip-ech-domains", "example.com"]);
+        let name = "example.com.";
+        let data = query(name, RecordType::HTTPS).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        let now = Instant::now();
+        server.handle_query(0, "127.0.0.1:5353".parse().unwrap(), &data, request, now);
+        let mut response = Message::from_vec(&data).unwrap();
+        response.set_message_type(MessageType::Response);
+        let svcb = SVCB::new(
+            1,
+            Name::root(),
+            vec![(
+                SvcParamKey::EchConfig,
+                SvcParamValue::EchConfig(EchConfig(vec![1, 2, 3])),
+            )],
+        );
+        response.add_answer(Record::from_rdata(
+            Name::from_str(name).unwrap(),
+            300,
+            RData::HTTPS(svcb),
+        ));
+        server.handle_response(response, Upstream::Trusted, now);
+
+        let response = exchange(&mut server, &mut poll, &data).unwrap();
+        assert_eq!(server.store["example.com.|HTTPS|trusted"].hits, 1);
+        match response.answers()[0].data() {
+            Some(RData::HTTPS(svcb)) => assert!(svcb.svc_params().is_empty()),
+            data => panic!("unexpected answer:{:?}", data),
+        }
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_cache_metrics() {
+        let (mut server, _, _routes) = trusted_server(&[]);
+        answer_trusted(&mut server, "example.com.", &["1.2.3.4"]);
+        assert_eq!(server.cache_metrics.cache_miss, 1);
+
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let from = "127.0.0.1:5353".parse().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        server.handle_query(0, from, &data, request, Instant::now());
+        assert_eq!(server.cache_metrics.cache_hit, 1);
+
+        let later = Instant::now() + Duration::from_secs(301);
+        let request = Message::from_vec(&data).unwrap();
+        server.handle_query(0, from, &data, request, later);
+        assert_eq!(
+            server.cache_metrics,
+            CacheMetrics {
+                cache_hit: 1,
+                cache_expired_refresh: 1,
+                cache_miss: 1,
+            }
+        );
+        assert!(server.store["example.com.|A|trusted"].pending);
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_ptr_from_cache() {
+        let (mut server, mut poll, _routes) = trusted_server(&["--ptr-from-cache"]);
+        answer_trusted(&mut server, "example.com.", &["1.2.3.4"]);
+        let query = query("4.3.2.1.in-addr.arpa.", RecordType::PTR);
+        let response = exchange(&mut server, &mut poll, &query.to_vec().unwrap()).unwrap();
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::PTR(Name::from_str("example.com.").unwrap()))
+        );
+        assert!(!server
+            .store
+            .contains_key("4.3.2.1.in-addr.arpa.|PTR|trusted"));
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_route_prefer_ipv4() {
+        let (mut server, mut poll, receiver) = trusted_server(&["--route-prefer-ipv4"]);
+        let name = "example.com.";
+        let data = query(name, RecordType::AAAA).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        let now = Instant::now();
+        server.handle_query(0, "127.0.0.1:5353".parse().unwrap(), &data, request, now);
+        let mut response = Message::from_vec(&data).unwrap();
+        response.set_message_type(MessageType::Response);
+        response.add_answer(Record::from_rdata(
+            Name::from_str(name).unwrap(),
+            300,
+            RData::AAAA("2001:db8::1".parse().unwrap()),
+        ));
+        server.handle_response(response, Upstream::Trusted, now);
+        assert!(receiver.try_recv().is_err());
+
+        let response = exchange(&mut server, &mut poll, &data).unwrap();
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::AAAA("2001:db8::1".parse().unwrap()))
+        );
+        answer_trusted(&mut server, "example.org.", &["1.2.3.4"]);
+        assert_eq!(receiver.try_recv().unwrap().ip.to_string(), "1.2.3.4");
+    }
+
+    #[test]
+    fn test_dns64() {
+        let (mut server, _, receiver) = trusted_server(&["--dns64"]);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let name = "example.com.";
+        let data = query(name, RecordType::AAAA).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        let now = Instant::now();
+        server.handle_query(0, client.local_addr().unwrap(), &data, request, now);
+        // the AAAA query has no answer, so the A records are queried
+        let mut response = Message::from_vec(&data).unwrap();
+        response.set_message_type(MessageType::Response);
+        server.handle_response(response, Upstream::Trusted, now);
+        let mut buffer = [0u8; 512];
+        assert!(client.recv_from(&mut buffer).is_err());
+
+        let data = query(name, RecordType::A).to_vec().unwrap();
+        let mut response = Message::from_vec(&data).unwrap();
+        response.set_message_type(MessageType::Response);
+        response.add_answer(Record::from_rdata(
+            Name::from_str(name).unwrap(),
+            300,
+            RData::A("1.2.3.4".parse().unwrap()),
+        ));
+        server.handle_response(response, Upstream::Trusted, now);
+        let (length, _) = client.recv_from(&mut buffer).unwrap();
+        let response = Message::from_vec(&buffer[..length]).unwrap();
+        assert_eq!(response.queries()[0].query_type(), RecordType::AAAA);
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::AAAA("64:ff9b::102:304".parse().unwrap()))
+        );
+        assert_eq!(receiver.try_recv().unwrap().ip.to_string(), "1.2.3.4");
+        assert_eq!(
+            receiver.try_recv().unwrap().ip.to_string(),
+            "64:ff9b::102:304"
+        );
+    }
+
+    #[test]
+    fn test_no_cache_list() {
+        let list = env::temp_dir().join("trojan_test_no_cache.txt");
+        fs::write(&list, "dyn.example.com\n").unwrap();
+        let (mut server, _, _routes) = trusted_server(&["--no-cache-list", list.to_str().unwrap()]);
+        fs::remove_file(list).unwrap();
+        let name = "home.dyn.example.com.";
+        answer_trusted(&mut server, name, &["1.2.3.4"]);
+        let data = query(name, RecordType::A).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        let key = DnsServer::get_message_key(&request, Upstream::Trusted);
+        assert!(!server.store.contains_key(&key));
+
+        // a fresh entry cached before the name was listed is not served
+        let now = Instant::now();
+        let mut result = QueryResult::new(now, Upstream::Trusted, QUERY_TIMEOUT);
+        result.response = Some(request.clone());
+        server.store.insert(key.clone(), result);
+        let from = "127.0.0.1:5353".parse().unwrap();
+        server.handle_query(0, from, &data, request, now);
+        let result = &server.store[&key];
+        assert!(result.pending);
+        assert_eq!(result.addresses.len(), 1);
+        assert_eq!(server.cache_metrics.cache_hit, 0);
+    }
+
+    #[test]
+    fn test_poisoned_answer_not_routed() {
+        let (mut server, _) = test_server(&["--add-route"]);
+        let (route_sender, receiver) = sync_channel(4);
+        server.route_sender = RouteSender::new(route_sender, RoutePolicy::DropNewest);
+        let name = "example.com.";
+        let data = query(name, RecordType::A).to_vec().unwrap();
+        let answer = |ip: &str| {
+            let mut response = Message::from_vec(&data).unwrap();
+            response.set_message_type(MessageType::Response);
+            response.add_answer(Record::from_rdata(
+                Name::from_str(name).unwrap(),
+                300,
+                RData::A(ip.parse().unwrap()),
+            ));
+            response
+        };
+        let from = "127.0.0.1:5353".parse().unwrap();
+        let now = Instant::now();
+        let request = Message::from_vec(&data).unwrap();
+        server.handle_query(0, from, &data, request, now);
+        // the domain gets blocked while its poisoned query is in flight
+        server
+            .blocked_domains
+            .write()
+            .unwrap()
+            .add_domain("example.com");
+        let request = Message::from_vec(&data).unwrap();
+        server.handle_query(0, from, &data, request, now);
+        server.handle_response(answer("1.2.3.4"), Upstream::Trusted, now);
+        server.handle_response(answer("93.46.8.90"), Upstream::Poisoned, now);
+        assert_eq!(receiver.try_recv().unwrap().ip.to_string(), "1.2.3.4");
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_query_type_upstream() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let mapping = format!("TXT={}", upstream.local_addr().unwrap());
+        let (mut server, _) = test_server(&["--query-type-upstream", mapping.as_str()]);
+        let from = "127.0.0.1:5353".parse().unwrap();
+        let mut buffer = [0u8; 512];
+        for query_type in [RecordType::A, RecordType::TXT] {
+            let data = query("example.com.", query_type).to_vec().unwrap();
+            let request = Message::from_vec(&data).unwrap();
+            server.handle_query(0, from, &data, request, Instant::now());
+        }
+        let (length, _) = upstream.recv_from(&mut buffer).unwrap();
+        let request = Message::from_vec(&buffer[..length]).unwrap();
+        assert_eq!(request.queries()[0].query_type(), RecordType::TXT);
+        assert!(upstream.recv_from(&mut buffer).is_err());
+        let key = DnsServer::get_message_key(&request, Upstream::Typed(0));
+        assert!(server.store.contains_key(&key));
+    }
+
+    /// Upstream whose sockets went stale with the tunnel interface, every send fails
+    struct StaleUpstream {
+        server_addr: SocketAddr,
+    }
+
+    impl DnsUpstream for StaleUpstream {
+        fn send_query(&mut self, _: &[u8]) -> io::Result<()> {
+            Err(io::Error::other("network is unreachable"))
+        }
+
+        fn recv_response(&mut self, _: &mut Vec<u8>) -> io::Result<usize> {
+            Err(io::Error::other("network is unreachable"))
+        }
+
+        fn register(&mut self, _: &Registry, _: Token) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn reregister(&mut self, _: &Registry, _: Token) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn server_addr(&self) -> SocketAddr {
+            self.server_addr
+        }
+
+        fn set_server_addr(&mut self, server_addr: SocketAddr) {
+            self.server_addr = server_addr;
+        }
+    }
+
+    #[test]
+    fn test_recreate_stale_upstream() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        upstream
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let (mut server, _, _routes) = trusted_server(&[]);
+        server.trusted = Box::new(StaleUpstream {
+            server_addr: upstream.local_addr().unwrap(),
+        });
+        let from = "127.0.0.1:5353".parse().unwrap();
+        for i in 0..=SOCKET_ERROR_LIMIT {
+            let data = query(&format!("host{}.example.com.", i), RecordType::A)
+                .to_vec()
+                .unwrap();
+            let request = Message::from_vec(&data).unwrap();
+            server.handle_query(0, from, &data, request, Instant::now());
+        }
+        // the last query goes through the recreated sockets
+        let mut buffer = [0u8; 512];
+        let (length, _) = upstream.recv_from(&mut buffer).unwrap();
+        let request = Message::from_vec(&buffer[..length]).unwrap();
+        let name = format!("host{}.example.com.", SOCKET_ERROR_LIMIT);
+        assert_eq!(request.queries()[0].name().to_utf8(), name);
+        assert!(upstream.recv_from(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn test_per_client_qps() {
+        let (mut server, _) = test_server(&["--per-client-qps", "2"]);
+        let now = Instant::now();
+        // whether the query is forwarded
+        let send = |server: &mut DnsServer, from: &str, name: &str| {
+            let data = query(name, RecordType::A).to_vec().unwrap();
+            let request = Message::from_vec(&data).unwrap();
+            server.handle_query(0, from.parse().unwrap(), &data, request, now);
+            server.store.keys().any(|key| key.starts_with(name))
+        };
+        assert!(send(&mut server, "127.0.0.1:5353", "a.example.com."));
+        assert!(send(&mut server, "127.0.0.1:5354", "b.example.com."));
+        assert!(!send(&mut server, "127.0.0.1:5355", "c.example.com."));
+        assert_eq!(server.throttled_queries, 1);
+        assert!(send(&mut server, "127.0.0.2:5353", "d.example.com."));
+    }
+
+    #[test]
+    fn test_allowed_clients() {
+        let logger = capture_logger();
+        let (mut server, _) = test_server(&["--allowed-clients", "192.168.1.0/24"]);
+        assert!(server.is_allowed("192.168.1.20".parse().unwrap()));
+        assert!(server.is_allowed("::1".parse().unwrap()));
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        let from = "10.0.0.5:5353".parse().unwrap();
+        server.handle_query(0, from, &data, request, Instant::now());
+        assert!(server.store.is_empty());
+        assert!(logger
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|line| line == "query from 10.0.0.5:5353 refused, the client is not allowed"));
+        // loopback is always allowed
+        answer_trusted(&mut server, "example.org.", &["1.2.3.4"]);
+        assert_eq!(server.store.len(), 1);
+    }
+
+    #[test]
+    fn test_sniffer() {
+        let downstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        downstream
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let downstream_addr = downstream.local_addr().unwrap().to_string();
+        let args = [
+            "--downstream-dns",
+            downstream_addr.as_str(),
+            "--add-route",
+            "--dns-max-ttl",
+            "60",
+            "--blocked-domains-inline",
+            "blocked.org",
+        ];
+        let (mut server, _) = test_server(&args);
+        let (route_sender, receiver) = sync_channel(4);
+        server.route_sender = RouteSender::new(route_sender, RoutePolicy::DropNewest);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let mut buffer = [0u8; 512];
+        for (name, ip, source) in [
+            ("www.blocked.org.", "1.2.3.4", Upstream::Trusted),
+            ("example.com.", "5.6.7.8", Upstream::Poisoned),
+        ] {
+            let data = query(name, RecordType::A).to_vec().unwrap();
+            let request = Message::from_vec(&data).unwrap();
+            let now = Instant::now();
+            server.handle_query(0, client.local_addr().unwrap(), &data, request, now);
+            let (length, _) = downstream.recv_from(&mut buffer).unwrap();
+            assert_eq!(&buffer[..length], data.as_slice());
+
+            let mut response = Message::from_vec(&data).unwrap();
+            response.set_message_type(MessageType::Response);
+            response.add_answer(Record::from_rdata(
+                Name::from_str(name).unwrap(),
+                3600,
+                RData::A(ip.parse().unwrap()),
+            ));
+            server.handle_response(response.clone(), source, now);
+            let (length, _) = client.recv_from(&mut buffer).unwrap();
+            let relayed = Message::from_vec(&buffer[..length]).unwrap();
+            assert_eq!(relayed.answers(), response.answers());
+        }
+        assert!(server.store.is_empty());
+        assert_eq!(receiver.try_recv().unwrap().ip.to_string(), "1.2.3.4");
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_local_names() {
+        let args = [
+            "--local-names",
+            "Gateway.Trojan",
+            "--local-names",
+            "dns.trojan=fd00::53",
+        ];
+        let (mut server, mut poll) = test_server(&args);
+        let data = query("gateway.trojan.", RecordType::A).to_vec().unwrap();
+        let response = exchange(&mut server, &mut poll, &data).unwrap();
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::A("127.0.0.1".parse().unwrap()))
+        );
+        let data = query("gateway.trojan.", RecordType::AAAA).to_vec().unwrap();
+        let response = exchange(&mut server, &mut poll, &data).unwrap();
+        assert!(response.answers().is_empty());
+        let data = query("dns.trojan.", RecordType::AAAA).to_vec().unwrap();
+        let response = exchange(&mut server, &mut poll, &data).unwrap();
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::AAAA("fd00::53".parse().unwrap()))
+        );
+        assert!(server.store.is_empty());
+    }
+
+    #[test]
+    fn test_local_answer_flags() {
+        let (mut server, mut poll) = test_server(&["--local-names", "gateway.trojan"]);
+        let data = query("gateway.trojan.", RecordType::A).to_vec().unwrap();
+        let response = exchange(&mut server, &mut poll, &data).unwrap();
+        assert!(response.authoritative());
+        assert!(!response.recursion_available());
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_cached_answer_flags() {
+        let (mut server, _) = test_server(&[]);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let from = client.local_addr().unwrap();
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let now = Instant::now();
+        server.handle_query(0, from, &data, Message::from_vec(&data).unwrap(), now);
+        // an authoritative upstream answer is no longer one once cached
+        let mut response = answer_first(&data, "1.2.3.4");
+        response.set_authoritative(true);
+        response.set_recursion_available(false);
+        server.handle_response(response, Upstream::Poisoned, now);
+        let mut buffer = [0u8; 512];
+        assert!(client.recv_from(&mut buffer).is_ok());
+
+        server.handle_query(0, from, &data, Message::from_vec(&data).unwrap(), now);
+        let (length, _) = client.recv_from(&mut buffer).unwrap();
+        let response = Message::from_vec(&buffer[..length]).unwrap();
+        assert_eq!(server.cache_metrics.cache_hit, 1);
+        assert!(!response.authoritative());
+        assert!(response.recursion_available());
+    }
+
+    #[test]
+    fn test_resolution_pipeline() {
+        let args = [
+            "--local-names",
+            "gateway.blocked.org=10.8.0.1",
+            "--blocked-domains-inline",
+            "blocked.org",
+        ];
+        let (mut server, mut poll) = test_server(&args);
+        let data = query("gateway.blocked.org.", RecordType::A)
+            .to_vec()
+            .unwrap();
+        let response = exchange(&mut server, &mut poll, &data).unwrap();
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::A("10.8.0.1".parse().unwrap()))
+        );
+        assert!(server.store.is_empty());
+
+        let mut args = args.to_vec();
+        args.extend_from_slice(&["--resolution-pipeline", "block,hosts"]);
+        let (mut server, _) = test_server(&args);
+        let request = Message::from_vec(&data).unwrap();
+        let from = "127.0.0.1:5353".parse().unwrap();
+        server.handle_query(0, from, &data, request, Instant::now());
+        assert!(server.store["gateway.blocked.org.|A|trusted"].pending);
+    }
+
+    #[test]
+    fn test_local_answer_ttl() {
+        let sinkhole = env::temp_dir().join("trojan_test_local_answer_ttl.txt");
+        fs::write(&sinkhole, "ads.example.com\n").unwrap();
+        let args = [
+            "--local-answer-ttl",
+            "42",
+            "--local-names",
+            "gateway.trojan",
+            "--srv-map",
+            "_http._tcp.corp.local=web.corp.local:8080",
+            "--sinkhole-domain-list",
+            sinkhole.to_str().unwrap(),
+            "--suppress-aaaa",
+        ];
+        let (mut server, mut poll) = test_server(&args);
+        fs::remove_file(sinkhole).unwrap();
+        let ptr_name = server.ptr_name.clone();
+        for (name, query_type) in [
+            ("gateway.trojan.", RecordType::A),
+            ("_http._tcp.corp.local.", RecordType::SRV),
+            ("ads.example.com.", RecordType::A),
+            ("localhost.", RecordType::A),
+            (ptr_name.as_str(), RecordType::PTR),
+            ("printer.local.", RecordType::A),
+            ("example.net.", RecordType::AAAA),
+            ("example.net.", RecordType::ANY),
+        ] {
+            let data = query(name, query_type).to_vec().unwrap();
+            let response = exchange(&mut server, &mut poll, &data).unwrap();
+            let records: Vec<&Record> = response
+                .answers()
+                .iter()
+                .chain(response.name_servers())
+                .collect();
+            assert!(!records.is_empty(), "{} {}", name, query_type);
+            for record in records {
+                assert_eq!(record.ttl(), 42, "{} {}", name, query_type);
+            }
+        }
+        assert!(server.store.is_empty());
+    }
+
+    #[test]
+    fn test_duplicate_response() {
+        let (mut server, _, receiver) = trusted_server(&[]);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        let now = Instant::now();
+        server.handle_query(0, client.local_addr().unwrap(), &data, request, now);
+        let response = answer_first(&data, "1.2.3.4");
+        server.handle_response(response.clone(), Upstream::Trusted, now);
+        server.handle_response(response, Upstream::Trusted, now);
+
+        let mut buffer = [0u8; 512];
+        assert!(client.recv_from(&mut buffer).is_ok());
+        assert!(client.recv_from(&mut buffer).is_err());
+        assert_eq!(receiver.try_recv().unwrap().ip.to_string(), "1.2.3.4");
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_mismatched_response() {
+        let (mut server, _) = test_server(&[]);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        let now = Instant::now();
+        server.handle_query(0, client.local_addr().unwrap(), &data, request, now);
+        // same cache key, but another class than the one asked
+        let mut response = answer_first(&data, "1.2.3.4");
+        let mut question = response.queries()[0].clone();
+        question.set_query_class(DNSClass::CH);
+        response.take_queries();
+        response.add_query(question);
+        server.handle_response(response, Upstream::Poisoned, now);
+
+        let mut buffer = [0u8; 512];
+        assert!(client.recv_from(&mut buffer).is_err());
+        let result = &server.store["example.com.|A|poisoned"];
+        assert!(result.pending);
+        assert_eq!(result.addresses.len(), 1);
+        server.handle_response(answer_first(&data, "1.2.3.4"), Upstream::Poisoned, now);
+        assert!(client.recv_from(&mut buffer).is_ok());
+    }
+
+    #[test]
+    fn test_private_address_not_routed() {
+        let (mut server, _, receiver) = trusted_server(&[]);
+        answer_trusted(&mut server, "nas.example.com.", &["192.168.1.5", "1.2.3.4"]);
+        assert_eq!(receiver.try_recv().unwrap().ip.to_string(), "1.2.3.4");
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(server.private_routes_skipped, 1);
+        answer_trusted(&mut server, "printer.example.com.", &["10.0.0.7"]);
+        assert_eq!(server.private_routes_skipped, 2);
+
+        let (mut server, _, receiver) = trusted_server(&["--route-private"]);
+        answer_trusted(&mut server, "nas.example.com.", &["192.168.1.5"]);
+        assert_eq!(receiver.try_recv().unwrap().ip.to_string(), "192.168.1.5");
+        assert_eq!(server.private_routes_skipped, 0);
+    }
+
+    #[test]
+    fn test_warm_routes() {
+        let file = env::temp_dir().join("trojan_test_warm_routes.csv");
+        let file = file.to_str().unwrap();
+        fs::write(file, "1.2.3.4,example.com.\n1.2.3.5,example.org.\n").unwrap();
+        let args = ["--add-route", "--route-dump-file", file];
+        let (mut server, _) = test_server(&args);
+        let (route_sender, receiver) = sync_channel(4);
+        server.route_sender = RouteSender::new(route_sender, RoutePolicy::DropNewest);
+        server.warm_routes();
+        fs::remove_file(file).unwrap();
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.ip.to_string(), "1.2.3.4");
+        assert_eq!(event.domain, "example.com.");
+        assert_eq!(receiver.try_recv().unwrap().ip.to_string(), "1.2.3.5");
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_same_upstreams() {
+        let logger = capture_logger();
+        test_server(&[]);
+        assert!(logger
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|line| line == "trusted and poisoned dns are both 127.0.0.1:53, blocked domains are not protected"));
+        let result = std::panic::catch_unwind(|| test_server(&["--strict-upstreams"]));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_localhost() {
+        let (mut server, mut poll) = test_server(&[]);
+        let query = query("localhost.", RecordType::A);
+        let response = exchange(&mut server, &mut poll, &query.to_vec().unwrap()).unwrap();
+        assert_eq!(
+            response.answers()[0].data(),
+            Some(&RData::A("127.0.0.1".parse().unwrap()))
+        );
+        assert!(server.store.is_empty());
+    }
+
+    #[test]
+    fn test_link_local_name() {
+        let (mut server, mut poll, _routes) = trusted_server(&[]);
+        let query = query("printer.local.", RecordType::A);
+        let response = exchange(&mut server, &mut poll, &query.to_vec().unwrap()).unwrap();
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert!(server.store.is_empty());
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_rrset_rotate() {
+        let (mut server, mut poll) = test_server(&["--rrset-rotate"]);
+        let query = query("example.com.", RecordType::A);
+        let data = query.to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        let now = Instant::now();
+        server.handle_query(0, "127.0.0.1:5353".parse().unwrap(), &data, request, now);
+        let mut response = answer_first(&data, "1.1.1.1");
+        let first = response.answers()[0].clone();
+        for address in ["2.2.2.2", "3.3.3.3"] {
+            let mut record = first.clone();
+            record.set_data(Some(RData::A(address.parse().unwrap())));
+            response.add_answer(record);
+        }
+        server.handle_response(response, Upstream::Poisoned, now);
+
+        let first_address = |response: Message| response.answers()[0].data().unwrap().to_string();
+        let mut firsts = vec![];
+        for _ in 0..3 {
+            let response = exchange(&mut server, &mut poll, &data).unwrap();
+            assert_eq!(response.answers().len(), 3);
+            firsts.push(first_address(response));
+        }
+        assert_eq!(firsts, vec!["2.2.2.2", "3.3.3.3", "1.1.1.1"]);
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_dnssec_types_keyed_apart() {
+        let (mut server, _) = test_server(&[]);
+        let now = Instant::now();
+        let from = "127.0.0.1:5353".parse().unwrap();
+        for query_type in [RecordType::A, RecordType::DNSKEY, RecordType::DS] {
+            let data = query("example.com.", query_type).to_vec().unwrap();
+            let request = Message::from_vec(&data).unwrap();
+            server.handle_query(0, from, &data, request, now);
+        }
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        server.handle_response(answer_first(&data, "1.2.3.4"), Upstream::Poisoned, now);
+
+        assert_eq!(server.store.len(), 3);
+        for key in ["example.com.|DNSKEY|poisoned", "example.com.|DS|poisoned"] {
+            assert!(server.store[key].response.is_none());
+            assert_eq!(server.store[key].addresses.len(), 1);
+        }
+        assert!(server.store["example.com.|A|poisoned"].response.is_some());
+    }
+
+    #[cfg(feature = "no_dns_cache")]
+    #[test]
+    fn test_no_dns_cache() {
+        let (mut server, _, receiver) = trusted_server(&[]);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        for _ in 0..2 {
+            let now = Instant::now();
+            let request = Message::from_vec(&data).unwrap();
+            server.handle_query(0, client.local_addr().unwrap(), &data, request, now);
+            server.handle_response(answer_first(&data, "1.2.3.4"), Upstream::Trusted, now);
+
+            let mut buffer = [0u8; 512];
+            let (length, _) = client.recv_from(&mut buffer).unwrap();
+            let response = Message::from_vec(&buffer[..length]).unwrap();
+            assert_eq!(response.answers().len(), 1);
+            assert_eq!(receiver.try_recv().unwrap().ip.to_string(), "1.2.3.4");
+            assert!(server.store.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_captive_portal() {
+        let (server, _, _routes) =
+            trusted_server(&["--captive-portal-domains", "Portal.Example.net"]);
+        let client = "127.0.0.1".parse().unwrap();
+        assert!(!server.is_blocked("www.msftconnecttest.com.", client));
+        assert!(!server.is_blocked("CONNECTIVITYCHECK.gstatic.com.", client));
+        assert!(!server.is_blocked("portal.example.net.", client));
+        assert!(server.is_blocked("gstatic.com.", client));
+    }
+
+    #[test]
+    fn test_rebuilt_query_keeps_edns_flags() {
+        let (mut server, _, _routes) =
+            trusted_server(&["--dns-padding", "128", "--ecs-subnet", "1.2.3.0/24"]);
+        let mut query = query("example.com.", RecordType::A);
+        query.set_checking_disabled(true);
+        let mut edns = Edns::new();
+        edns.set_dnssec_ok(true);
+        edns.set_max_payload(1232);
+        query.set_edns(edns);
+        let data = query.to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        let from = "127.0.0.1:5353".parse().unwrap();
+        server.handle_query(0, from, &data, request, Instant::now());
+
+        let sent = Message::from_vec(&server.store["example.com.|A|trusted"].query).unwrap();
+        assert!(sent.checking_disabled());
+        let edns = sent.extensions().as_ref().unwrap();
+        assert!(edns.dnssec_ok());
+        assert_eq!(edns.max_payload(), 1232);
+    }
+
+    #[test]
+    fn test_large_tcp_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let trusted = format!("tcp://{}", listener.local_addr().unwrap());
+        let upstream = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut length = [0u8; 2];
+            stream.read_exact(&mut length).unwrap();
+            let mut data = vec![0; u16::from_be_bytes(length) as usize];
+            stream.read_exact(&mut data).unwrap();
+            let mut response = Message::from_vec(&data).unwrap();
+            response.set_message_type(MessageType::Response);
+            let name = response.queries()[0].name().clone();
+            for i in 0..200u8 {
+                response.add_answer(Record::from_rdata(
+                    name.clone(),
+                    300,
+                    RData::A([10, 0, 0, i].into()),
+                ));
+            }
+            let data = response.to_vec().unwrap();
+            assert!(data.len() > MAX_PACKET_SIZE);
+            stream
+                .write_all(&(data.len() as u16).to_be_bytes())
+                .unwrap();
+            stream.write_all(&data).unwrap();
+        });
+        let args = DnsArgs::parse_from([
+            "dns",
+            "--tun-name",
+            "test",
+            "--dns-listen-address",
+            "127.0.0.1:0",
+            "--blocked-domains-inline",
+            "blocked.org",
+        ]);
+        let mut server = DnsServerBuilder::new(&args, sync_channel(1).0)
+            .upstreams(&trusted, "127.0.0.1")
+            .build()
+            .unwrap();
+        let mut poll = Poll::new().unwrap();
+        server.setup(&poll).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        let data = query("www.blocked.org.", RecordType::A).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        let now = Instant::now();
+        server.handle_query(0, client.local_addr().unwrap(), &data, request, now);
+
+        let mut events = Events::with_capacity(16);
+        let mut buffer = [0u8; 4096];
+        let mut response = None;
+        for _ in 0..40 {
+            poll.poll(&mut events, Some(Duration::from_millis(50)))
+                .unwrap();
+            for event in &events {
+                server.ready(event, &poll);
+            }
+            if let Ok((length, _)) = client.recv_from(&mut buffer) {
+                response = Some(Message::from_vec(&buffer[..length]).unwrap());
+                break;
+            }
+        }
+        upstream.join().unwrap();
+        // the client sent no EDNS, so it gets the answers fitting in 512 bytes and TC
+        let response = response.unwrap();
+        assert!(response.truncated());
+        assert!(!response.answers().is_empty() && response.answers().len() < 200);
+        assert!(response.to_vec().unwrap().len() <= 512);
+        // the stream read the whole answer, which is what gets cached
+        #[cfg(not(feature = "no_dns_cache"))]
+        {
+            let cached = server.store["www.blocked.org.|A|trusted"].response.as_ref();
+            assert_eq!(cached.unwrap().answers().len(), 200);
+        }
+    }
+
+    /// Server whose trusted and poisoned upstreams are the returned local sockets, with `args`
+    /// appended to the command line.
+    fn stub_upstream_server(args: &[&str]) -> (DnsServer, UdpSocket, UdpSocket) {
+        let trusted = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let poisoned = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for socket in [&trusted, &poisoned] {
+            socket
+                .set_read_timeout(Some(Duration::from_millis(200)))
+                .unwrap();
+        }
+        let mut command = vec![
+            "dns",
+            "--tun-name",
+            "test",
+            "--dns-listen-address",
+            "127.0.0.1:0",
+            "--blocked-domains-inline",
+            "blocked.org",
+        ];
+        command.extend_from_slice(args);
+        let args = DnsArgs::parse_from(command);
+        let mut server = DnsServerBuilder::new(&args, sync_channel(1).0)
+            .upstreams(
+                &trusted.local_addr().unwrap().to_string(),
+                &poisoned.local_addr().unwrap().to_string(),
+            )
+            .build()
+            .unwrap();
+        server.setup(&Poll::new().unwrap()).unwrap();
+        (server, trusted, poisoned)
+    }
+
+    /// Message asking `names` for A records at once.
+    fn multi_query(names: &[&str]) -> Vec<u8> {
+        let mut message = query(names[0], RecordType::A);
+        for name in &names[1..] {
+            message.add_query(Query::query(Name::from_str(name).unwrap(), RecordType::A));
+        }
+        message.to_vec().unwrap()
+    }
+
+    /// Answer `data` with an A record of `ip` for its first query.
+    fn answer_first(data: &[u8], ip: &str) -> Message {
+        let mut response = Message::from_vec(data).unwrap();
+        response.set_message_type(MessageType::Response);
+        response.add_answer(Record::from_rdata(
+            response.queries()[0].name().clone(),
+            300,
+            RData::A(ip.parse().unwrap()),
+        ));
+        response
+    }
+
+    #[test]
+    fn test_multi_query_first() {
+        let (mut server, trusted, poisoned) =
+            stub_upstream_server(&["--multi-query-mode", "first"]);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let data = multi_query(&["www.blocked.org.", "example.net."]);
+        let request = Message::from_vec(&data).unwrap();
+        let now = Instant::now();
+        server.handle_query(0, client.local_addr().unwrap(), &data, request, now);
+
+        let mut buffer = [0u8; 512];
+        let (length, _) = trusted.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], data.as_slice());
+        assert!(poisoned.recv_from(&mut buffer).is_err());
+
+        let response = answer_first(&data, "1.2.3.4");
+        server.handle_response(response.clone(), Upstream::Trusted, now);
+        let (length, _) = client.recv_from(&mut buffer).unwrap();
+        let relayed = Message::from_vec(&buffer[..length]).unwrap();
+        assert_eq!(relayed.queries(), response.queries());
+        assert_eq!(relayed.answers(), response.answers());
+        assert!(server.store.is_empty());
+    }
+
+    #[test]
+    fn test_multi_query_split() {
+        let (mut server, trusted, poisoned) =
+            stub_upstream_server(&["--multi-query-mode", "split"]);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let data = multi_query(&["www.blocked.org.", "example.net."]);
+        let request = Message::from_vec(&data).unwrap();
+        let now = Instant::now();
+        server.handle_query(0, client.local_addr().unwrap(), &data, request, now);
+
+        let mut buffer = [0u8; 512];
+        let mut answers = vec![];
+        for (upstream, source, ip) in [
+            (&trusted, Upstream::Trusted, "1.2.3.4"),
+            (&poisoned, Upstream::Poisoned, "5.6.7.8"),
+        ] {
+            let (length, _) = upstream.recv_from(&mut buffer).unwrap();
+            let part = Message::from_vec(&buffer[..length]).unwrap();
+            assert_eq!(part.query_count(), 1);
+            let response = answer_first(&buffer[..length], ip);
+            answers.extend_from_slice(response.answers());
+            server.handle_response(response, source, now);
+        }
+        let (length, _) = client.recv_from(&mut buffer).unwrap();
+        let merged = Message::from_vec(&buffer[..length]).unwrap();
+        assert_eq!(merged.id(), 9);
+        assert_eq!(merged.query_count(), 2);
+        assert_eq!(merged.answers(), answers.as_slice());
+        assert!(server.batches.is_empty());
+    }
+
+    #[test]
+    fn test_compare_upstreams() {
+        let logger = capture_logger();
+        let (mut server, trusted, poisoned) =
+            stub_upstream_server(&["--compare-sample-rate", "1.0"]);
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let data = query("www.blocked.org.", RecordType::A).to_vec().unwrap();
+        let request = Message::from_vec(&data).unwrap();
+        let now = Instant::now();
+        server.handle_query(0, client.local_addr().unwrap(), &data, request, now);
+
+        let mut buffer = [0u8; 512];
+        for upstream in [&trusted, &poisoned] {
+            let (length, _) = upstream.recv_from(&mut buffer).unwrap();
+            assert_eq!(&buffer[..length], data.as_slice());
+        }
+        server.handle_response(answer_first(&data, "10.0.0.1"), Upstream::Poisoned, now);
+        assert!(client.recv_from(&mut buffer).is_err());
+        server.handle_response(answer_first(&data, "1.2.3.4"), Upstream::Trusted, now);
+        let (length, _) = client.recv_from(&mut buffer).unwrap();
+        let response = Message::from_vec(&buffer[..length]).unwrap();
+        assert_eq!(response.answers(), answer_first(&data, "1.2.3.4").answers());
+        assert!(!server.store.contains_key("www.blocked.org.|A|poisoned"));
+        assert!(server.comparisons.is_empty());
+        let lines = logger.0.lock().unwrap();
+        assert!(lines.iter().any(|line| line.contains(
+            "compare www.blocked.org.|A: trusted only [1.2.3.4], poisoned only [10.0.0.1]"
+        )));
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_prefetch_aaaa() {
+        let (mut server, _, poisoned) = stub_upstream_server(&["--prefetch-aaaa"]);
+        let client = "127.0.0.1:5353".parse().unwrap();
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let now = Instant::now();
+        server.handle_query(0, client, &data, Message::from_vec(&data).unwrap(), now);
+
+        let mut buffer = [0u8; 512];
+        for _ in 0..2 {
+            let (length, _) = poisoned.recv_from(&mut buffer).unwrap();
+            let mut response = Message::from_vec(&buffer[..length]).unwrap();
+            response.set_message_type(MessageType::Response);
+            let data = match response.queries()[0].query_type() {
+                RecordType::AAAA => RData::AAAA("2001:db8::1".parse().unwrap()),
+                _ => RData::A("1.2.3.4".parse().unwrap()),
+            };
+            let name = response.queries()[0].name().clone();
+            response.add_answer(Record::from_rdata(name, 300, data));
+            server.handle_response(response, Upstream::Poisoned, now);
+        }
+        assert!(poisoned.recv_from(&mut buffer).is_err());
+
+        let data = query("example.com.", RecordType::AAAA).to_vec().unwrap();
+        server.handle_query(0, client, &data, Message::from_vec(&data).unwrap(), now);
+        assert!(poisoned.recv_from(&mut buffer).is_err());
+        assert_eq!(server.cache_metrics.cache_hit, 1);
+    }
+
+    #[cfg(not(feature = "no_dns_cache"))]
+    #[test]
+    fn test_bypass_cache() {
+        let (mut server, _, poisoned) = stub_upstream_server(&[]);
+        let client = "127.0.0.1:5353".parse().unwrap();
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let now = Instant::now();
+        let mut buffer = [0u8; 512];
+        server.handle_query(0, client, &data, Message::from_vec(&data).unwrap(), now);
+        assert!(poisoned.recv_from(&mut buffer).is_ok());
+        server.handle_response(answer_first(&data, "1.2.3.4"), Upstream::Poisoned, now);
+
+        let mut request = query("example.com.", RecordType::A);
+        let mut extensions = Edns::new();
+        edns::set_option(&mut extensions, EdnsCode::from(edns::CACHE_BYPASS), vec![]);
+        request.set_edns(extensions);
+        let data = request.to_vec().unwrap();
+        server.handle_query(0, client, &data, Message::from_vec(&data).unwrap(), now);
+        let (length, _) = poisoned.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], data.as_slice());
+        assert_eq!(server.cache_metrics.cache_hit, 0);
+        assert!(server.store["example.com.|A|poisoned"].pending);
+    }
+
+    #[test]
+    fn test_forward_loopback_ptr() {
+        let data = query("1.0.0.127.in-addr.arpa.", RecordType::PTR)
+            .to_vec()
+            .unwrap();
+        let client = "127.0.0.1:5353".parse().unwrap();
+        let mut buffer = [0u8; 512];
+        for (intercept, forwarded) in [("true", false), ("false", true)] {
+            let (mut server, _, poisoned) =
+                stub_upstream_server(&["--intercept-loopback-ptr", intercept]);
+            let request = Message::from_vec(&data).unwrap();
+            server.handle_query(0, client, &data, request, Instant::now());
+            assert_eq!(poisoned.recv_from(&mut buffer).is_ok(), forwarded);
+        }
+    }
+
+    #[test]
+    fn test_coalesce_listeners() {
+        let (mut server, _, poisoned) =
+            stub_upstream_server(&["--dns-listen-address", "127.0.0.1:0"]);
+        assert_eq!(server.listeners.len(), 2);
+        let data = query("example.com.", RecordType::A).to_vec().unwrap();
+        let now = Instant::now();
+        let clients: Vec<UdpSocket> = (0..2)
+            .map(|index| {
+                let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+                client
+                    .set_read_timeout(Some(Duration::from_millis(200)))
+                    .unwrap();
+                let request = Message::from_vec(&data).unwrap();
+                server.handle_query(index, client.local_addr().unwrap(), &data, request, now);
+                client
+            })
+            .collect();
+
+        let mut buffer = [0u8; 512];
+        assert!(poisoned.recv_from(&mut buffer).is_ok());
+        assert!(poisoned.recv_from(&mut buffer).is_err());
+        server.handle_response(answer_first(&data, "1.2.3.4"), Upstream::Poisoned, now);
+        for (index, client) in clients.iter().enumerate() {
+            let (length, from) = client.recv_from(&mut buffer).unwrap();
+            assert_eq!(from, server.listeners[index].local_addr().unwrap());
+            let response = Message::from_vec(&buffer[..length]).unwrap();
+            assert_eq!(response.answers(), answer_first(&data, "1.2.3.4").answers());
+        }
     }
 }
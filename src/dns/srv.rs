@@ -0,0 +1,73 @@
+use std::{collections::HashMap, str::FromStr};
+
+use trust_dns_proto::{
+    op::Message,
+    rr::{rdata::SRV, Name, RData, Record},
+};
+
+use crate::{
+    dns::local,
+    types::{Result, TrojanError},
+};
+
+/// Parse local services in format like _http._tcp.example.local=host.example.local:8080,
+/// names are lowercased with a trailing dot like query names.
+pub fn parse_srv_map(services: &[String]) -> Result<HashMap<String, SRV>> {
+    let mut result = HashMap::new();
+    for item in services {
+        let invalid = || TrojanError::InvalidConfig(format!("invalid srv map:{}", item));
+        let (service, target) = item.split_once('=').ok_or_else(invalid)?;
+        let (target, port) = target.rsplit_once(':').ok_or_else(invalid)?;
+        let port: u16 = port.trim().parse().map_err(|_| invalid())?;
+        let mut service = service.trim().to_lowercase();
+        if !service.ends_with('.') {
+            service.push('.');
+        }
+        let target = Name::from_str(target.trim())?.append_domain(&Name::root())?;
+        result.insert(service, SRV::new(0, 0, port, target));
+    }
+    Ok(result)
+}
+
+/// Answer `request` with the local service `srv`, the answer carries `ttl`.
+pub fn srv_response(request: &Message, srv: &SRV, ttl: u32) -> Message {
+    let record = Record::from_rdata(
+        request.queries()[0].name().clone(),
+        ttl,
+        RData::SRV(srv.clone()),
+    );
+    local::local_response(request, vec![record])
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use std::str::FromStr;
+
+    use trust_dns_proto::{
+        op::{Message, Query},
+        rr::{Name, RData, RecordType},
+    };
+
+    use crate::dns::srv::{parse_srv_map, srv_response};
+
+    #[test]
+    fn test_srv_response() {
+        let services =
+            parse_srv_map(&["_HTTP._tcp.corp.local=web.corp.local:8080".into()]).unwrap();
+        let srv = &services["_http._tcp.corp.local."];
+        let mut request = Message::new();
+        request.add_query(Query::query(
+            Name::from_str("_http._tcp.corp.local.").unwrap(),
+            RecordType::SRV,
+        ));
+        let response = srv_response(&request, srv, 300);
+        match response.answers()[0].data() {
+            Some(RData::SRV(srv)) => {
+                assert_eq!(srv.target(), &Name::from_str("web.corp.local.").unwrap());
+                assert_eq!(srv.port(), 8080);
+            }
+            data => panic!("unexpected answer:{:?}", data),
+        }
+        assert!(parse_srv_map(&["_http._tcp.corp.local=web.corp.local".into()]).is_err());
+    }
+}
@@ -0,0 +1,417 @@
+use std::{
+    convert::TryFrom,
+    io::{self, ErrorKind, Read, Write},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use mio::{net::TcpStream, Interest, Registry, Token};
+use rustls::{ClientConfig, ClientConnection, OwnedTrustAnchor, RootCertStore, ServerName};
+
+use crate::dns::upstream::{DnsUpstream, Transport, UpstreamUrl};
+
+/// DNS over a TCP connection, wrapped in TLS for DNS over TLS and HTTPS, see RFC 7766,
+/// RFC 7858 and RFC 8484. The connection is opened by the first query and again after
+/// the server closes it.
+pub struct StreamUpstream {
+    server_addr: SocketAddr,
+    /// Config and certificate name of TLS connections, none for plain TCP
+    tls: Option<(Arc<ClientConfig>, ServerName)>,
+    /// Host and path queries are posted to, none for length prefixed messages
+    http: Option<(String, String)>,
+    /// Registry and token of the mio loop, connections opened later are registered with them
+    registry: Option<(Registry, Token)>,
+    connection: Option<Connection>,
+}
+
+struct Connection {
+    stream: TcpStream,
+    tls: Option<ClientConnection>,
+    /// Encoded queries waiting for the socket to be writable, plain TCP only
+    output: Vec<u8>,
+    /// Received data not forming a whole response yet
+    input: Vec<u8>,
+}
+
+impl StreamUpstream {
+    pub fn new(url: &UpstreamUrl) -> io::Result<Self> {
+        let tls = match url.transport {
+            Transport::Tls | Transport::Https => {
+                let name = ServerName::try_from(url.server_name.as_str())
+                    .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err))?;
+                if let ServerName::IpAddress(_) = name {
+                    // webpki only verifies certificates against names
+                    let message = format!(
+                        "{:?} upstream {} needs a certificate name like {}",
+                        url.transport, url.addr, "tls://1.1.1.1#cloudflare-dns.com"
+                    );
+                    return Err(io::Error::new(ErrorKind::InvalidInput, message));
+                }
+                Some((tls_config(), name))
+            }
+            _ => None,
+        };
+        let http = match url.transport {
+            Transport::Https => Some((url.server_name.clone(), url.path.clone())),
+            _ => None,
+        };
+        Ok(Self {
+            server_addr: url.addr,
+            tls,
+            http,
+            registry: None,
+            connection: None,
+        })
+    }
+
+    fn connect(&mut self) -> io::Result<&mut Connection> {
+        if self.connection.is_none() {
+            let mut stream = TcpStream::connect(self.server_addr)?;
+            let _ = stream.set_nodelay(true);
+            if let Some((registry, token)) = &self.registry {
+                registry.register(&mut stream, *token, Interest::READABLE | Interest::WRITABLE)?;
+            }
+            let tls = match &self.tls {
+                Some((config, name)) => Some(
+                    ClientConnection::new(config.clone(), name.clone())
+                        .map_err(io::Error::other)?,
+                ),
+                None => None,
+            };
+            log::info!("connected to upstream {}", self.server_addr);
+            self.connection = Some(Connection {
+                stream,
+                tls,
+                output: vec![],
+                input: vec![],
+            });
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+
+    fn close(&mut self) {
+        if let Some(mut connection) = self.connection.take() {
+            if let Some((registry, _)) = &self.registry {
+                let _ = registry.deregister(&mut connection.stream);
+            }
+            log::info!("connection to upstream {} closed", self.server_addr);
+        }
+    }
+
+    fn recv(&mut self, buffer: &mut Vec<u8>) -> io::Result<usize> {
+        let http = self.http.is_some();
+        let connection = match self.connection.as_mut() {
+            Some(connection) => connection,
+            None => return Err(ErrorKind::WouldBlock.into()),
+        };
+        connection.flush()?;
+        let open = connection.fill()?;
+        // the handshake may have something to send after reading
+        connection.flush()?;
+        let response = if http {
+            http_response(&mut connection.input)?
+        } else {
+            framed_response(&mut connection.input)
+        };
+        match response {
+            Some(response) => {
+                // a length prefix allows responses far beyond a UDP packet
+                if response.len() > buffer.len() {
+                    buffer.resize(response.len(), 0);
+                }
+                buffer[..response.len()].copy_from_slice(&response);
+                Ok(response.len())
+            }
+            None if !open => {
+                self.close();
+                Err(ErrorKind::WouldBlock.into())
+            }
+            None => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl Connection {
+    /// Queue `data` for the server, TLS encrypts it once the handshake is done.
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self.tls.as_mut() {
+            Some(tls) => tls.writer().write_all(data)?,
+            None => self.output.extend_from_slice(data),
+        }
+        self.flush()
+    }
+
+    /// Write queued data as long as the socket accepts it.
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(tls) = self.tls.as_mut() {
+            while tls.wants_write() {
+                match tls.write_tls(&mut self.stream) {
+                    Ok(_) => {}
+                    Err(err) if is_pending(&err) => break,
+                    Err(err) => return Err(err),
+                }
+            }
+            return Ok(());
+        }
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(length) => {
+                    self.output.drain(..length);
+                }
+                Err(err) if is_pending(&err) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read everything the socket has into `input`, returns false if the server closed.
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut buffer = [0u8; 4096];
+        let tls = match self.tls.as_mut() {
+            Some(tls) => tls,
+            None => loop {
+                match self.stream.read(&mut buffer) {
+                    Ok(0) => return Ok(false),
+                    Ok(length) => self.input.extend_from_slice(&buffer[..length]),
+                    Err(err) if is_pending(&err) => return Ok(true),
+                    Err(err) => return Err(err),
+                }
+            },
+        };
+        let mut open = true;
+        loop {
+            match tls.read_tls(&mut self.stream) {
+                Ok(0) => {
+                    open = false;
+                    break;
+                }
+                Ok(_) => {
+                    tls.process_new_packets()
+                        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+                }
+                Err(err) if is_pending(&err) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        loop {
+            match tls.reader().read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(length) => self.input.extend_from_slice(&buffer[..length]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(open),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+impl DnsUpstream for StreamUpstream {
+    fn send_query(&mut self, query: &[u8]) -> io::Result<()> {
+        let data = match &self.http {
+            Some((host, path)) => {
+                let mut data = format!(
+                    "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/dns-message\r\n\
+                     Accept: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+                    path,
+                    host,
+                    query.len()
+                )
+                .into_bytes();
+                data.extend_from_slice(query);
+                data
+            }
+            None => {
+                let mut data = (query.len() as u16).to_be_bytes().to_vec();
+                data.extend_from_slice(query);
+                data
+            }
+        };
+        let result = self
+            .connect()
+            .and_then(|connection| connection.write(&data));
+        if result.is_err() {
+            self.close();
+        }
+        result
+    }
+
+    fn recv_response(&mut self, buffer: &mut Vec<u8>) -> io::Result<usize> {
+        let result = self.recv(buffer);
+        if let Err(err) = &result {
+            if err.kind() != ErrorKind::WouldBlock {
+                self.close();
+            }
+        }
+        result
+    }
+
+    fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        if let Some(connection) = self.connection.as_mut() {
+            registry.register(
+                &mut connection.stream,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            )?;
+        }
+        self.registry = Some((registry.try_clone()?, token));
+        Ok(())
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        if let Some(connection) = self.connection.as_mut() {
+            registry.reregister(
+                &mut connection.stream,
+                token,
+                Interest::READABLE | Interest::WRITABLE,
+            )?;
+        }
+        self.registry = Some((registry.try_clone()?, token));
+        Ok(())
+    }
+
+    fn server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    /// The connection to the old server is closed, the next query connects to the new one.
+    fn set_server_addr(&mut self, server_addr: SocketAddr) {
+        self.close();
+        self.server_addr = server_addr;
+    }
+}
+
+/// Whether a socket operation has to wait for the next readiness event, a connecting
+/// socket may report not connected instead of would block.
+fn is_pending(err: &io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::NotConnected)
+}
+
+fn tls_config() -> Arc<ClientConfig> {
+    let mut root_store = RootCertStore::empty();
+    root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store)
+        .with_no_client_auth();
+    Arc::new(config)
+}
+
+/// Take the next whole length prefixed response out of `input`.
+fn framed_response(input: &mut Vec<u8>) -> Option<Vec<u8>> {
+    if input.len() < 2 {
+        return None;
+    }
+    let length = u16::from_be_bytes([input[0], input[1]]) as usize;
+    if input.len() < length + 2 {
+        return None;
+    }
+    let response = input[2..length + 2].to_vec();
+    input.drain(..length + 2);
+    Some(response)
+}
+
+/// Take the body of the next whole HTTP response out of `input`, responses other than 200
+/// are errors closing the connection.
+fn http_response(input: &mut Vec<u8>) -> io::Result<Option<Vec<u8>>> {
+    let end = match input.windows(4).position(|window| window == b"\r\n\r\n") {
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let invalid = |message: &str| io::Error::new(ErrorKind::InvalidData, message.to_string());
+    let head = std::str::from_utf8(&input[..end]).map_err(|_| invalid("invalid http head"))?;
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .ok_or_else(|| invalid("invalid http status line"))?
+        .to_string();
+    let length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .ok_or_else(|| invalid("http response without content length"))?;
+    let start = end + 4;
+    if input.len() < start + length {
+        return Ok(None);
+    }
+    let body = input[start..start + length].to_vec();
+    input.drain(..start + length);
+    if status != "200" {
+        return Err(io::Error::other(format!("http status {}", status)));
+    }
+    Ok(Some(body))
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use std::{
+        io::{ErrorKind, Read, Write},
+        net::TcpListener,
+        thread,
+        time::Duration,
+    };
+
+    use crate::dns::{
+        stream::{http_response, StreamUpstream},
+        upstream::{DnsUpstream, UpstreamUrl},
+    };
+
+    #[test]
+    fn test_tcp_upstream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url: UpstreamUrl = format!("tcp://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut query = [0u8; 6];
+            stream.read_exact(&mut query).unwrap();
+            stream.write_all(&[0, 2, 4, 3, 0, 2, 2, 1]).unwrap();
+            query
+        });
+        let mut upstream = StreamUpstream::new(&url).unwrap();
+        upstream.send_query(&[1, 2, 3, 4]).unwrap();
+
+        let mut buffer = vec![0u8; 1024];
+        let mut responses = vec![];
+        while responses.len() < 2 {
+            match upstream.recv_response(&mut buffer) {
+                Ok(length) => responses.push(buffer[..length].to_vec()),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                Err(err) => panic!("recv failed:{}", err),
+            }
+        }
+        assert_eq!(responses, vec![vec![4, 3], vec![2, 1]]);
+        assert_eq!(server.join().unwrap(), [0, 4, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_http_response() {
+        let mut input = b"HTTP/1.1 200 OK\r\ncontent-type: application/dns-message\r\n\
+                          Content-Length: 2\r\n\r\n\x04\x03HTTP/1.1 200"
+            .to_vec();
+        assert_eq!(http_response(&mut input).unwrap(), Some(vec![4, 3]));
+        assert_eq!(http_response(&mut input).unwrap(), None);
+        assert_eq!(input, b"HTTP/1.1 200");
+
+        let mut input = b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n".to_vec();
+        assert!(http_response(&mut input).is_err());
+    }
+
+    #[test]
+    fn test_tls_name() {
+        let url: UpstreamUrl = "tls://1.1.1.1".parse().unwrap();
+        assert!(StreamUpstream::new(&url).is_err());
+        let url: UpstreamUrl = "tls://1.1.1.1#cloudflare-dns.com".parse().unwrap();
+        assert!(StreamUpstream::new(&url).is_ok());
+    }
+}
@@ -0,0 +1,134 @@
+use std::net::IpAddr;
+
+use trust_dns_proto::{
+    op::Message,
+    rr::{
+        rdata::{
+            svcb::{Mandatory, SvcParamKey, SvcParamValue},
+            SVCB,
+        },
+        RData,
+    },
+};
+
+/// Remove ECH config from HTTPS/SVCB answers of `response`, returns true if any is removed.
+/// Address hints are kept, so clients still connect to the resolved addresses.
+pub fn strip_ech(response: &mut Message) -> bool {
+    let mut stripped = false;
+    for record in response.answers_mut() {
+        let svcb = match record.data_mut() {
+            Some(RData::HTTPS(svcb)) | Some(RData::SVCB(svcb)) => svcb,
+            _ => continue,
+        };
+        if !svcb
+            .svc_params()
+            .iter()
+            .any(|(key, _)| *key == SvcParamKey::EchConfig)
+        {
+            continue;
+        }
+        let params = svcb
+            .svc_params()
+            .iter()
+            .filter_map(|(key, value)| match value {
+                SvcParamValue::EchConfig(_) => None,
+                SvcParamValue::Mandatory(Mandatory(keys)) => {
+                    let keys: Vec<SvcParamKey> = keys
+                        .iter()
+                        .filter(|key| **key != SvcParamKey::EchConfig)
+                        .cloned()
+                        .collect();
+                    // an empty mandatory list is malformed, drop the whole param
+                    (!keys.is_empty()).then_some((*key, SvcParamValue::Mandatory(Mandatory(keys))))
+                }
+                _ => Some((*key, value.clone())),
+            })
+            .collect();
+        *svcb = SVCB::new(svcb.svc_priority(), svcb.target_name().clone(), params);
+        stripped = true;
+    }
+    stripped
+}
+
+/// Addresses carried by an answer, including HTTPS/SVCB address hints.
+pub fn record_addresses(data: &RData) -> Vec<IpAddr> {
+    let svcb = match data {
+        RData::HTTPS(svcb) | RData::SVCB(svcb) => svcb,
+        _ => return data.to_ip_addr().into_iter().collect(),
+    };
+    svcb.svc_params()
+        .iter()
+        .flat_map(|(_, value)| match value {
+            SvcParamValue::Ipv4Hint(hint) => hint.0.iter().map(|ip| (*ip).into()).collect(),
+            SvcParamValue::Ipv6Hint(hint) => hint.0.iter().map(|ip| (*ip).into()).collect(),
+            _ => vec![],
+        })
+        .collect()
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        str::FromStr,
+    };
+
+    use trust_dns_proto::{
+        op::Message,
+        rr::{
+            rdata::{
+                svcb::{Alpn, EchConfig, IpHint, Mandatory, SvcParamKey, SvcParamValue},
+                SVCB,
+            },
+            Name, RData, Record,
+        },
+    };
+
+    use crate::dns::svcb::{record_addresses, strip_ech};
+
+    #[test]
+    fn test_strip_ech() {
+        let hint = Ipv4Addr::new(1, 2, 3, 4);
+        let svcb = SVCB::new(
+            1,
+            Name::root(),
+            vec![
+                (
+                    SvcParamKey::Mandatory,
+                    SvcParamValue::Mandatory(Mandatory(vec![SvcParamKey::EchConfig])),
+                ),
+                (
+                    SvcParamKey::Alpn,
+                    SvcParamValue::Alpn(Alpn(vec!["h2".into()])),
+                ),
+                (
+                    SvcParamKey::EchConfig,
+                    SvcParamValue::EchConfig(EchConfig(vec![1, 2, 3])),
+                ),
+                (
+                    SvcParamKey::Ipv4Hint,
+                    SvcParamValue::Ipv4Hint(IpHint(vec![hint])),
+                ),
+            ],
+        );
+        let mut response = Message::new();
+        response.add_answer(Record::from_rdata(
+            Name::from_str("example.com.").unwrap(),
+            300,
+            RData::HTTPS(svcb),
+        ));
+
+        assert!(strip_ech(&mut response));
+        assert!(!strip_ech(&mut response));
+        let data = response.answers()[0].data().unwrap();
+        match data {
+            RData::HTTPS(svcb) => {
+                let keys: Vec<SvcParamKey> =
+                    svcb.svc_params().iter().map(|(key, _)| *key).collect();
+                assert_eq!(keys, vec![SvcParamKey::Alpn, SvcParamKey::Ipv4Hint]);
+            }
+            data => panic!("unexpected answer:{:?}", data),
+        }
+        assert_eq!(record_addresses(data), vec![IpAddr::from(hint)]);
+    }
+}
@@ -0,0 +1,78 @@
+use mio::Token;
+
+use crate::dns::route::Upstream;
+
+/// First token handed out, smaller ones stay reserved
+const MIN_TOKEN: usize = 2;
+
+/// Socket registered to the DNS poll
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsSocket {
+    Upstream(Upstream),
+    /// Local listener with its index
+    Listener(usize),
+}
+
+/// Hands out a unique poll token for each socket and maps tokens back to sockets.
+pub struct TokenAllocator {
+    sockets: Vec<DnsSocket>,
+}
+
+impl TokenAllocator {
+    pub fn new() -> Self {
+        Self { sockets: vec![] }
+    }
+
+    pub fn allocate(&mut self, socket: DnsSocket) -> Token {
+        if let Some(token) = self.token(socket) {
+            return token;
+        }
+        self.sockets.push(socket);
+        Token(MIN_TOKEN + self.sockets.len() - 1)
+    }
+
+    pub fn token(&self, socket: DnsSocket) -> Option<Token> {
+        self.sockets
+            .iter()
+            .position(|item| *item == socket)
+            .map(|index| Token(MIN_TOKEN + index))
+    }
+
+    pub fn socket(&self, token: Token) -> Option<DnsSocket> {
+        self.sockets.get(token.0.checked_sub(MIN_TOKEN)?).copied()
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use std::collections::HashSet;
+
+    use mio::Token;
+
+    use crate::dns::{
+        route::Upstream,
+        token::{DnsSocket, TokenAllocator},
+    };
+
+    #[test]
+    fn test_allocate() {
+        let mut allocator = TokenAllocator::new();
+        let sockets = [
+            DnsSocket::Upstream(Upstream::Trusted),
+            DnsSocket::Upstream(Upstream::Poisoned),
+            DnsSocket::Listener(0),
+            DnsSocket::Listener(1),
+        ];
+        let tokens: Vec<Token> = sockets
+            .iter()
+            .map(|socket| allocator.allocate(*socket))
+            .collect();
+        assert_eq!(tokens.iter().collect::<HashSet<_>>().len(), sockets.len());
+        for (socket, token) in sockets.iter().zip(&tokens) {
+            assert_eq!(allocator.socket(*token), Some(*socket));
+            assert_eq!(allocator.allocate(*socket), *token);
+        }
+        assert_eq!(allocator.socket(Token(0)), None);
+        assert_eq!(allocator.socket(Token(100)), None);
+    }
+}
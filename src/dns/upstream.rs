@@ -0,0 +1,439 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    hash::{BuildHasher, Hasher},
+    io,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+use mio::{net::UdpSocket, Interest, Registry, Token};
+use trust_dns_proto::rr::RecordType;
+
+use crate::{
+    dns::stream::StreamUpstream,
+    types::{Result, TrojanError},
+};
+
+/// Transport used to talk to an upstream DNS server, driven by the mio loop.
+pub trait DnsUpstream {
+    /// Send one raw DNS query to the upstream server.
+    fn send_query(&mut self, query: &[u8]) -> io::Result<()>;
+
+    /// Receive one raw DNS response into `buffer`, returns `WouldBlock` when drained.
+    /// Stream transports grow `buffer` to fit responses longer than it.
+    fn recv_response(&mut self, buffer: &mut Vec<u8>) -> io::Result<usize>;
+
+    fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()>;
+
+    fn reregister(&mut self, registry: &Registry, token: Token) -> io::Result<()>;
+
+    /// Address of the upstream server, used for logging.
+    fn server_addr(&self) -> SocketAddr;
+
+    /// Send later queries to `server_addr`, sockets and their registration are kept.
+    fn set_server_addr(&mut self, server_addr: SocketAddr);
+}
+
+/// Transport carrying queries to an upstream DNS server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+}
+
+impl Transport {
+    fn default_port(self) -> u16 {
+        match self {
+            Transport::Udp | Transport::Tcp => 53,
+            Transport::Tls => 853,
+            Transport::Https => 443,
+        }
+    }
+}
+
+/// Upstream DNS server in format like tls://1.1.1.1#cloudflare-dns.com or
+/// https://1.1.1.1/dns-query#cloudflare-dns.com, a bare address is plain UDP.
+/// The name after # is the one TLS certificates are verified against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamUrl {
+    pub transport: Transport,
+    pub addr: SocketAddr,
+    /// Certificate name for TLS, the host header for HTTPS, the address if not given
+    pub server_name: String,
+    /// Path DNS over HTTPS queries are posted to
+    pub path: String,
+}
+
+impl UpstreamUrl {
+    /// Create the upstream for this server, `ports` only applies to UDP.
+    pub fn upstream(&self, ports: usize) -> io::Result<Box<dyn DnsUpstream>> {
+        Ok(match self.transport {
+            Transport::Udp => Box::new(UdpUpstream::with_ports(self.addr, ports)?),
+            _ => Box::new(StreamUpstream::new(self)?),
+        })
+    }
+}
+
+impl FromStr for UpstreamUrl {
+    type Err = TrojanError;
+
+    fn from_str(url: &str) -> Result<Self> {
+        let invalid = || TrojanError::InvalidConfig(format!("invalid upstream dns:{}", url));
+        let (transport, rest) = match url.split_once("://") {
+            Some((scheme, rest)) => {
+                let transport = match scheme.to_lowercase().as_str() {
+                    "udp" => Transport::Udp,
+                    "tcp" => Transport::Tcp,
+                    "tls" => Transport::Tls,
+                    "https" => Transport::Https,
+                    _ => return Err(invalid()),
+                };
+                (transport, rest)
+            }
+            None => (Transport::Udp, url),
+        };
+        let (rest, server_name) = match rest.split_once('#') {
+            Some((rest, name)) => (rest, Some(name)),
+            None => (rest, None),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+        let addr = match authority.parse() {
+            Ok(addr) => addr,
+            Err(_) => {
+                let ip: IpAddr = authority
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse()
+                    .map_err(|_| invalid())?;
+                SocketAddr::new(ip, transport.default_port())
+            }
+        };
+        let path = match path {
+            "" if transport == Transport::Https => "/dns-query",
+            path => path,
+        };
+        Ok(Self {
+            transport,
+            addr,
+            server_name: server_name.map_or_else(|| addr.ip().to_string(), Into::into),
+            path: path.into(),
+        })
+    }
+}
+
+/// Parse upstreams for query types in format like TXT=9.9.9.9, returns the distinct upstreams
+/// and the index of the one each query type goes to.
+pub fn parse_type_upstreams(
+    items: &[String],
+) -> Result<(Vec<UpstreamUrl>, HashMap<RecordType, usize>)> {
+    let mut urls: Vec<UpstreamUrl> = vec![];
+    let mut types = HashMap::new();
+    for item in items {
+        let invalid =
+            || TrojanError::InvalidConfig(format!("invalid query type upstream:{}", item));
+        let (query_type, url) = item.split_once('=').ok_or_else(invalid)?;
+        let query_type =
+            RecordType::from_str(&query_type.trim().to_uppercase()).map_err(|_| invalid())?;
+        let url: UpstreamUrl = url.trim().parse()?;
+        let index = match urls.iter().position(|other| *other == url) {
+            Some(index) => index,
+            None => {
+                urls.push(url);
+                urls.len() - 1
+            }
+        };
+        types.insert(query_type, index);
+    }
+    Ok((urls, types))
+}
+
+/// Unanswered UDP queries remembered per upstream, the oldest are forgotten past it
+const MAX_PENDING: usize = 1024;
+
+/// Random transaction id, std hashers are randomly keyed.
+fn random_id() -> u16 {
+    RandomState::new().build_hasher().finish() as u16
+}
+
+/// Wildcard address of the family of `server_addr`, a socket can't reach the other family.
+fn bind_addr(server_addr: SocketAddr) -> SocketAddr {
+    match server_addr {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    }
+}
+
+/// Plain DNS over UDP. Responses are only taken on the socket their query left from and
+/// with its transaction id, so with queries rotating through a pool of source ports with
+/// random ids, off-path spoofers have to guess the port along with the id.
+pub struct UdpUpstream {
+    sockets: Vec<UdpSocket>,
+    /// Socket sending the next query
+    next: usize,
+    /// Socket index and transaction id of unanswered queries, oldest first
+    pending: VecDeque<(usize, u16)>,
+    server_addr: SocketAddr,
+}
+
+impl UdpUpstream {
+    pub fn with_ports(server_addr: SocketAddr, ports: usize) -> io::Result<Self> {
+        let sockets = (0..ports.max(1))
+            .map(|_| UdpSocket::bind(bind_addr(server_addr)))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(Self {
+            sockets,
+            next: 0,
+            pending: VecDeque::new(),
+            server_addr,
+        })
+    }
+
+    /// Remember the query `id` sent from socket `index`.
+    fn add_pending(&mut self, index: usize, id: u16) {
+        if self.pending.len() == MAX_PENDING {
+            self.pending.pop_front();
+        }
+        self.pending.push_back((index, id));
+    }
+
+    /// Whether the `response` received on socket `index` answers a pending query, which is
+    /// forgotten then.
+    fn take_pending(&mut self, index: usize, response: &[u8]) -> bool {
+        if response.len() < 2 {
+            return false;
+        }
+        let id = u16::from_be_bytes([response[0], response[1]]);
+        match self.pending.iter().position(|query| *query == (index, id)) {
+            Some(position) => {
+                self.pending.remove(position);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether queries are spread over several source ports, ids are randomized as well then
+    pub fn is_rotating(&self) -> bool {
+        self.sockets.len() > 1
+    }
+}
+
+impl DnsUpstream for UdpUpstream {
+    fn send_query(&mut self, query: &[u8]) -> io::Result<()> {
+        let index = self.next;
+        self.next = (self.next + 1) % self.sockets.len();
+        if query.len() < 2 {
+            return self.sockets[index]
+                .send_to(query, self.server_addr)
+                .map(|_| ());
+        }
+        let mut query = query.to_vec();
+        if self.is_rotating() {
+            // clients get their own id back, the server matches responses by question
+            query[..2].copy_from_slice(&random_id().to_be_bytes());
+        }
+        self.sockets[index].send_to(&query, self.server_addr)?;
+        self.add_pending(index, u16::from_be_bytes([query[0], query[1]]));
+        Ok(())
+    }
+
+    fn recv_response(&mut self, buffer: &mut Vec<u8>) -> io::Result<usize> {
+        for index in 0..self.sockets.len() {
+            loop {
+                match self.sockets[index].recv_from(buffer) {
+                    Ok((_, from)) if from != self.server_addr => log::warn!(
+                        "drop response from {}, expecting {}",
+                        from,
+                        self.server_addr
+                    ),
+                    Ok((length, _)) if self.take_pending(index, &buffer[..length]) => {
+                        return Ok(length)
+                    }
+                    Ok(_) => log::warn!(
+                        "drop response from {} answering no query sent from its port",
+                        self.server_addr
+                    ),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+        Err(ErrorKind::WouldBlock.into())
+    }
+
+    fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        for socket in &mut self.sockets {
+            registry.register(socket, token, Interest::READABLE)?;
+        }
+        Ok(())
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        for socket in &mut self.sockets {
+            registry.reregister(socket, token, Interest::READABLE)?;
+        }
+        Ok(())
+    }
+
+    fn server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    fn set_server_addr(&mut self, server_addr: SocketAddr) {
+        self.server_addr = server_addr;
+        self.pending.clear();
+    }
+}
+
+#[allow(unused_imports)]
+mod tests {
+    use std::{collections::HashSet, io::ErrorKind, net::UdpSocket, thread, time::Duration};
+
+    use trust_dns_proto::rr::RecordType;
+
+    use crate::dns::upstream::{
+        parse_type_upstreams, DnsUpstream, Transport, UdpUpstream, UpstreamUrl,
+    };
+
+    #[test]
+    fn test_upstream_url() {
+        let url: UpstreamUrl = "8.8.8.8".parse().unwrap();
+        assert_eq!(url.transport, Transport::Udp);
+        assert_eq!(url.addr, "8.8.8.8:53".parse().unwrap());
+        let url: UpstreamUrl = "udp://[2001:4860:4860::8888]:5353".parse().unwrap();
+        assert_eq!(url.transport, Transport::Udp);
+        assert_eq!(url.addr, "[2001:4860:4860::8888]:5353".parse().unwrap());
+        let url: UpstreamUrl = "tcp://8.8.8.8".parse().unwrap();
+        assert_eq!(url.transport, Transport::Tcp);
+        assert_eq!(url.addr.port(), 53);
+        let url: UpstreamUrl = "tls://1.1.1.1#cloudflare-dns.com".parse().unwrap();
+        assert_eq!(url.transport, Transport::Tls);
+        assert_eq!(url.addr, "1.1.1.1:853".parse().unwrap());
+        assert_eq!(url.server_name, "cloudflare-dns.com");
+        let url: UpstreamUrl = "https://1.1.1.1/dns-query#cloudflare-dns.com"
+            .parse()
+            .unwrap();
+        assert_eq!(url.transport, Transport::Https);
+        assert_eq!(url.addr, "1.1.1.1:443".parse().unwrap());
+        assert_eq!(url.path, "/dns-query");
+        let url: UpstreamUrl = "HTTPS://9.9.9.9:8443#dns.quad9.net".parse().unwrap();
+        assert_eq!(url.addr.port(), 8443);
+        assert_eq!(url.path, "/dns-query");
+        assert!("quic://1.1.1.1".parse::<UpstreamUrl>().is_err());
+        assert!("tls://dns.google".parse::<UpstreamUrl>().is_err());
+    }
+
+    #[test]
+    fn test_type_upstreams() {
+        let (urls, types) = parse_type_upstreams(&[
+            "PTR=192.168.1.1".into(),
+            "txt=tls://9.9.9.9#dns.quad9.net".into(),
+            "MX=tls://9.9.9.9#dns.quad9.net".into(),
+        ])
+        .unwrap();
+        assert_eq!(urls.len(), 2);
+        assert_eq!(
+            urls[types[&RecordType::PTR]].addr,
+            "192.168.1.1:53".parse().unwrap()
+        );
+        assert_eq!(types[&RecordType::TXT], types[&RecordType::MX]);
+        assert!(!types.contains_key(&RecordType::A));
+        assert!(parse_type_upstreams(&["TXT".into()]).is_err());
+        assert!(parse_type_upstreams(&["BOGUS=9.9.9.9".into()]).is_err());
+    }
+
+    #[test]
+    fn test_udp_upstream() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut upstream = UdpUpstream::with_ports(server.local_addr().unwrap(), 1).unwrap();
+        upstream.send_query(&[1, 2, 3, 4]).unwrap();
+
+        let mut buffer = vec![0u8; 1024];
+        let (length, from) = server.recv_from(buffer.as_mut_slice()).unwrap();
+        assert_eq!(&buffer[..length], &[1, 2, 3, 4]);
+        server.send_to(&[1, 2, 2, 1], from).unwrap();
+
+        let length = loop {
+            match upstream.recv_response(&mut buffer) {
+                Ok(length) => break length,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                Err(err) => panic!("recv failed:{}", err),
+            }
+        };
+        assert_eq!(&buffer[..length], &[1, 2, 2, 1]);
+    }
+
+    #[test]
+    fn test_ipv6_udp_upstream() {
+        let server = UdpSocket::bind("[::1]:0").unwrap();
+        let mut upstream = UdpUpstream::with_ports(server.local_addr().unwrap(), 2).unwrap();
+        for _ in 0..2 {
+            upstream.send_query(&[1, 2, 3, 4]).unwrap();
+            let mut buffer = [0u8; 16];
+            let (length, from) = server.recv_from(&mut buffer).unwrap();
+            assert_eq!(&buffer[2..length], &[3, 4]);
+            assert!(from.is_ipv6());
+        }
+    }
+
+    #[test]
+    fn test_port_rotation() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut upstream = UdpUpstream::with_ports(server.local_addr().unwrap(), 4).unwrap();
+        let mut buffer = vec![0u8; 1024];
+        let mut ports = HashSet::new();
+        let mut ids = HashSet::new();
+        for _ in 0..4 {
+            upstream.send_query(&[1, 2, 3, 4]).unwrap();
+            let (length, from) = server.recv_from(buffer.as_mut_slice()).unwrap();
+            assert_eq!(&buffer[2..length], &[3, 4]);
+            ports.insert(from.port());
+            ids.insert([buffer[0], buffer[1]]);
+        }
+        assert_eq!(ports.len(), 4);
+        assert!(ids.len() > 1);
+    }
+
+    #[test]
+    fn test_spoofed_response() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut upstream = UdpUpstream::with_ports(server.local_addr().unwrap(), 2).unwrap();
+        let mut buffer = vec![0u8; 1024];
+        let mut queries = vec![];
+        for _ in 0..2 {
+            upstream.send_query(&[1, 2, 3, 4]).unwrap();
+            let (length, from) = server.recv_from(buffer.as_mut_slice()).unwrap();
+            queries.push((from, [buffer[0], buffer[1]]));
+            assert_eq!(length, 4);
+        }
+        let (port, id) = queries[0];
+        let (other_port, other_id) = queries[1];
+        let respond = |id: [u8; 2], data: u8, to| {
+            server.send_to(&[id[0], id[1], data], to).unwrap();
+        };
+        // the right id on the wrong port, then ids of no query sent from the port
+        if id != other_id {
+            respond(id, 0, other_port);
+            respond(other_id, 0, port);
+        }
+        respond((u16::from_be_bytes(id) ^ 0x8000).to_be_bytes(), 0, port);
+        respond(id, 1, port);
+        thread::sleep(Duration::from_millis(50));
+
+        let length = upstream.recv_response(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], &[id[0], id[1], 1]);
+        // answered already
+        respond(id, 2, port);
+        thread::sleep(Duration::from_millis(50));
+        let err = upstream.recv_response(&mut buffer).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    }
+}
@@ -8,9 +8,9 @@ use backtrace::Backtrace;
 use crate::config::{Mode, OPTIONS};
 
 mod config;
+mod dns;
 cfg_if::cfg_if! {
     if #[cfg(windows)] {
-        mod dns;
         mod wintun;
     }
 }
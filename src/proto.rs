@@ -117,6 +117,19 @@ impl<'a> TrojanRequest<'a> {
         buffer.put_u8(b'\r');
         buffer.put_u8(b'\n');
     }
+
+    /// Request connecting to `domain`, resolved by the trojan server.
+    #[cfg(windows)]
+    pub fn generate_domain(buffer: &mut BytesMut, cmd: u8, domain: &str, port: u16) {
+        buffer.extend_from_slice(OPTIONS.get_pass().as_bytes());
+        buffer.put_u8(b'\r');
+        buffer.put_u8(b'\n');
+        buffer.put_u8(cmd);
+        log::info!("generate domain:{}:{}", domain, port);
+        Sock5Address::generate_domain(buffer, domain, port);
+        buffer.put_u8(b'\r');
+        buffer.put_u8(b'\n');
+    }
 }
 
 fn parse_address(atyp: u8, buffer: &[u8]) -> Option<(usize, Sock5Address)> {
@@ -391,4 +404,12 @@ impl Sock5Address {
         }
         buffer.put_u16(endpoint.port);
     }
+
+    #[cfg(windows)]
+    pub fn generate_domain(buffer: &mut BytesMut, domain: &str, port: u16) {
+        buffer.put_u8(DOMAIN);
+        buffer.put_u8(domain.len() as u8);
+        buffer.extend_from_slice(domain.as_bytes());
+        buffer.put_u16(port);
+    }
 }
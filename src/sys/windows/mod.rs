@@ -1,10 +1,36 @@
-use std::{any::Any, io::Result, net::SocketAddr};
+use std::{
+    any::Any,
+    io::{Error, Result},
+    net::SocketAddr,
+    os::windows::io::AsRawSocket,
+};
+
+use winapi::um::winsock2::{self, SOCKET, SOL_SOCKET, SO_EXCLUSIVEADDRUSE};
 
 #[allow(dead_code)]
 pub fn set_mark<T: Any>(_socket: &T, _mark: u8) -> Result<()> {
     Ok(())
 }
 
+/// Set SO_EXCLUSIVEADDRUSE, so no other process can bind the address of `socket` too.
+pub fn set_exclusive_addr_use<T: AsRawSocket>(socket: &T) -> Result<()> {
+    let enable: i32 = 1;
+    unsafe {
+        let ret = winsock2::setsockopt(
+            socket.as_raw_socket() as SOCKET,
+            SOL_SOCKET,
+            SO_EXCLUSIVEADDRUSE,
+            &enable as *const _ as *const _,
+            std::mem::size_of_val(&enable) as i32,
+        );
+        if ret != 0 {
+            Err(Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
 pub fn set_socket_opts<T: Any>(_v4: bool, _is_udp: bool, _socket: &T) -> Result<()> {
     unimplemented!("proxy mode not supported in windows");
 }
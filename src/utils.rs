@@ -1,4 +1,5 @@
 use std::{
+    env,
     io::{ErrorKind, Read, Write},
     net::{IpAddr, SocketAddr},
     str::FromStr,
@@ -125,10 +126,63 @@ pub fn resolve(name: &str, dns_server_addr: &str) -> Result<Vec<IpAddr>> {
     }
 }
 
+/// Expand leading `~` and `$VAR`, `${VAR}`, `%VAR%` environment variables in `path`,
+/// unknown variables are kept as they are.
+pub fn expand_path(path: &str) -> String {
+    let mut result = String::new();
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") || rest.starts_with("~\\") {
+        if let Some(home) = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")) {
+            result.push_str(&home.to_string_lossy());
+            rest = &rest[1..];
+        }
+    }
+    while let Some(pos) = rest.find(['$', '%']) {
+        result.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+        let (name, length) = if let Some(stripped) = tail.strip_prefix("${") {
+            match stripped.find('}') {
+                Some(end) => (&stripped[..end], end + 3),
+                None => ("", 1),
+            }
+        } else if let Some(stripped) = tail.strip_prefix('$') {
+            let end = stripped
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(stripped.len());
+            (&stripped[..end], end + 1)
+        } else {
+            match tail[1..].find('%') {
+                Some(end) => (&tail[1..end + 1], end + 2),
+                None => ("", 1),
+            }
+        };
+        let value = if name.is_empty() {
+            None
+        } else {
+            env::var(name).ok()
+        };
+        result.push_str(value.as_deref().unwrap_or(&tail[..length]));
+        rest = &tail[length..];
+    }
+    result.push_str(rest);
+    result
+}
+
 mod test {
     #[test]
     fn test_resolve() {
         let result = crate::utils::resolve("www.baidu.com", "192.168.3.1:53");
         println!("{:?}", result);
     }
+
+    #[test]
+    fn test_expand_path() {
+        use crate::utils::expand_path;
+        std::env::set_var("TROJAN_IPSET_DIR", "ipset");
+        let expected = "ipset/domain.txt";
+        assert_eq!(expand_path("$TROJAN_IPSET_DIR/domain.txt"), expected);
+        assert_eq!(expand_path("%TROJAN_IPSET_DIR%/domain.txt"), expected);
+        assert_eq!(expand_path("$TROJAN_NOT_SET/a%b"), "$TROJAN_NOT_SET/a%b");
+        assert!(std::fs::File::open(expand_path("${TROJAN_IPSET_DIR}/domain.txt")).is_ok());
+    }
 }
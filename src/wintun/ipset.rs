@@ -8,7 +8,7 @@ use std::{
 
 use smoltcp::wire::{IpAddress, IpEndpoint};
 
-use crate::{types::Result, wintun::route::route_add_with_if};
+use crate::{types::Result, utils::expand_path, wintun::route::route_add_with_if};
 
 //TODO ipv6
 pub fn is_private(endpoint: IpEndpoint) -> bool {
@@ -85,7 +85,7 @@ impl Ord for Cidr {
 impl IPSet {
     pub fn with_file(file: &str, inverse: bool) -> crate::types::Result<Self> {
         let mut ipset = Self::new();
-        let file = File::open(file)?;
+        let file = File::open(expand_path(file))?;
         let reader = BufReader::new(file);
         reader.lines().for_each(|line| {
             if let Ok(line) = line {
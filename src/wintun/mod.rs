@@ -16,7 +16,7 @@ use smoltcp::{
 };
 use wintun::{Adapter, Session};
 
-pub use route::route_add_with_if;
+pub use route::{route_add_with_if, route_add_with_metric};
 
 use crate::{
     dns::{get_adapter_ip, get_main_adapter_gwif},
@@ -139,7 +139,7 @@ pub fn run() -> Result<()> {
     let mut pool = prepare_idle_pool(&poll, &resolver)?;
 
     let mut udp_server = UdpServer::new();
-    let mut tcp_server = TcpServer::new();
+    let mut tcp_server = TcpServer::new()?;
 
     let mut interface = prepare_device(session);
 
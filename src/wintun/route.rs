@@ -14,15 +14,15 @@ use winapi::{
 
 use crate::types::{Result, TrojanError};
 
+/// Metric of routes added without an explicit one
+pub const DEFAULT_METRIC: u32 = 99;
+
 pub fn route_add_with_if(dst: u32, mask: u32, gw: u32, if_index: u32) -> Result<()> {
-    log::trace!(
-        "route add {} mask {} {} metric 1 if {}",
-        Ipv4Addr::from(dst),
-        Ipv4Addr::from(mask),
-        Ipv4Addr::from(gw),
-        if_index
-    );
-    let mut forward = MIB_IPFORWARDROW {
+    route_add_with_metric(dst, mask, gw, if_index, DEFAULT_METRIC)
+}
+
+fn forward_row(dst: u32, mask: u32, gw: u32, if_index: u32, metric: u32) -> MIB_IPFORWARDROW {
+    MIB_IPFORWARDROW {
         dwForwardDest: dst.to_be(),
         dwForwardMask: mask.to_be(),
         dwForwardPolicy: 0,
@@ -32,12 +32,30 @@ pub fn route_add_with_if(dst: u32, mask: u32, gw: u32, if_index: u32) -> Result<
         ForwardProto: MIB_IPPROTO_NETMGMT,
         dwForwardAge: 0,
         dwForwardNextHopAS: 0,
-        dwForwardMetric1: 99,
+        dwForwardMetric1: metric,
         dwForwardMetric2: !0,
         dwForwardMetric3: !0,
         dwForwardMetric4: !0,
         dwForwardMetric5: !0,
-    };
+    }
+}
+
+pub fn route_add_with_metric(
+    dst: u32,
+    mask: u32,
+    gw: u32,
+    if_index: u32,
+    metric: u32,
+) -> Result<()> {
+    log::trace!(
+        "route add {} mask {} {} metric {} if {}",
+        Ipv4Addr::from(dst),
+        Ipv4Addr::from(mask),
+        Ipv4Addr::from(gw),
+        metric,
+        if_index
+    );
+    let mut forward = forward_row(dst, mask, gw, if_index, metric);
 
     let ret = unsafe { iphlpapi::CreateIpForwardEntry(&mut forward) };
     match ret {
@@ -48,3 +66,16 @@ pub fn route_add_with_if(dst: u32, mask: u32, gw: u32, if_index: u32) -> Result<
         _ => Err(TrojanError::Winapi("route add error unknown".into())),
     }
 }
+
+#[allow(unused_imports)]
+mod tests {
+    use crate::wintun::route::forward_row;
+
+    #[test]
+    fn test_forward_row() {
+        let row = forward_row(0x01020304, !0, 0, 12, 5);
+        assert_eq!(row.dwForwardDest, 0x01020304u32.to_be());
+        assert_eq!(row.dwForwardIfIndex, 12);
+        assert_eq!(row.dwForwardMetric1, 5);
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
     collections::{HashMap, HashSet},
     io::{Error, ErrorKind, Write},
+    net::Ipv4Addr,
     sync::Arc,
     task::Waker,
     time::{Duration, Instant},
@@ -8,16 +9,18 @@ use std::{
 
 use bytes::BytesMut;
 use mio::{event::Event, Poll, Token};
-use smoltcp::{iface::SocketHandle, socket::TcpSocket};
+use smoltcp::{iface::SocketHandle, socket::TcpSocket, wire::IpAddress};
 
 use crate::{
+    dns::FakeIpMap,
     idle_pool::IdlePool,
     proto::{TrojanRequest, CONNECT},
     resolver::DnsResolver,
     tls_conn::TlsConn,
-    types::{CopyResult, TrojanError},
+    types::{CopyResult, Result, TrojanError},
     utils::copy_stream,
     wintun::{waker::Wakers, SocketSet, CHANNEL_CNT, CHANNEL_TCP, MAX_INDEX, MIN_INDEX},
+    OPTIONS,
 };
 
 pub struct TcpStreamRef<'a, 'b> {
@@ -197,6 +200,7 @@ impl Connection {
         poll: &Poll,
         event: &Event,
         wakers: &mut Wakers,
+        fake_ips: &mut FakeIpMap,
     ) {
         self.last_active = Instant::now();
         if event.is_writable() {
@@ -208,7 +212,20 @@ impl Connection {
                 } else {
                     let mut request = BytesMut::new();
                     let endpoint = sockets.get_socket::<TcpSocket>(self.local).local_endpoint();
-                    TrojanRequest::generate_endpoint(&mut request, CONNECT, &endpoint);
+                    // fake addresses of the dns server connect by name
+                    let domain = match endpoint.addr {
+                        IpAddress::Ipv4(ip) => fake_ips.domain(Ipv4Addr::from(ip.0)),
+                        _ => None,
+                    };
+                    match domain {
+                        Some(domain) => TrojanRequest::generate_domain(
+                            &mut request,
+                            CONNECT,
+                            domain,
+                            endpoint.port,
+                        ),
+                        None => TrojanRequest::generate_endpoint(&mut request, CONNECT, &endpoint),
+                    }
                     log::info!("send trojan request {} bytes", request.len());
                     if self.remote.write(request.as_ref()).is_ok() {
                         self.established = true;
@@ -307,15 +324,18 @@ pub struct TcpServer {
     token2conns: HashMap<Token, Arc<Connection>>,
     handle2conns: HashMap<SocketHandle, Arc<Connection>>,
     removed: HashSet<SocketHandle>,
+    fake_ips: FakeIpMap,
 }
 
 impl TcpServer {
-    pub fn new() -> Self {
-        Self {
+    pub fn new() -> Result<Self> {
+        let args = OPTIONS.wintun_args();
+        Ok(Self {
             token2conns: Default::default(),
             handle2conns: Default::default(),
             removed: HashSet::new(),
-        }
+            fake_ips: FakeIpMap::new(args.fake_ip_file.clone(), &args.fake_ip_range)?,
+        })
     }
 
     pub(crate) fn do_local(
@@ -367,7 +387,15 @@ impl TcpServer {
         wakers: &mut Wakers,
     ) {
         if let Some(conn) = self.token2conns.get_mut(&event.token()) {
-            unsafe { Arc::get_mut_unchecked(conn).do_remote(sockets, poll, event, wakers) };
+            unsafe {
+                Arc::get_mut_unchecked(conn).do_remote(
+                    sockets,
+                    poll,
+                    event,
+                    wakers,
+                    &mut self.fake_ips,
+                )
+            };
             if conn.is_closed(sockets) {
                 self.removed.insert(conn.local);
             }